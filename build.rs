@@ -0,0 +1,28 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// The embedded migrations can't be listed, so the versions are collected
+// here to find out which ones are pending. Versions are named the way diesel
+// does: the directory name up to the first `_`, without dashes.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("the migrations directory is missing")
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().join("up.sql").exists())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            Some(name.split('_').next()?.replace('-', ""))
+        })
+        .collect();
+    versions.sort();
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("migration_versions.rs");
+    fs::write(
+        out,
+        format!("const MIGRATION_VERSIONS: &[&str] = &{:?};\n", versions),
+    )
+    .unwrap();
+}
@@ -2,9 +2,11 @@
 
 set -e
 
-until gosu rust diesel migration run --locked-schema; do
-  echo "Migration failed, retrying in 5 seconds..."
-  sleep 5
-done
+# development only, production keys are provisioned out of band
+if [ "$GENERATE_SIGNING_KEY" = "true" ] && [ ! -f "$JWT_SIGNING_KEY_FILE" ]; then
+  mkdir -p "$(dirname "$JWT_SIGNING_KEY_FILE")"
+  openssl genpkey -algorithm ed25519 -out "$JWT_SIGNING_KEY_FILE"
+  chown rust:rust "$JWT_SIGNING_KEY_FILE"
+fi
 
 exec "$@"
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX account_lockouts_user_id_fk;
+DROP TABLE account_lockouts;
+DROP TABLE login_throttles;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE login_throttles (
+    throttle_key VARCHAR(255) PRIMARY KEY,
+    failed_count INTEGER NOT NULL DEFAULT 0,
+    last_failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until TIMESTAMP
+);
+
+CREATE TABLE account_lockouts (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users ON DELETE CASCADE,
+    scope VARCHAR(16) NOT NULL,
+    throttle_key VARCHAR(255) NOT NULL,
+    failed_count INTEGER NOT NULL,
+    locked_until TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX account_lockouts_user_id_fk ON account_lockouts(user_id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX login_challenges_user_id_fk;
+DROP TABLE login_challenges;
+DROP INDEX totp_recovery_codes_user_id_fk;
+DROP TABLE totp_recovery_codes;
+DROP TABLE user_totp;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX api_keys_user_id_fk;
+DROP TABLE api_keys;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE oidc_login_states;
+DROP INDEX user_identities_user_id_fk;
+DROP TABLE user_identities;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX rate_limit_buckets_updated_at_idx;
+DROP TABLE rate_limit_buckets;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX email_outbox_status_idx;
+DROP INDEX email_outbox_due_idx;
+DROP TABLE email_outbox;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE email_outbox DROP COLUMN html_body;
+ALTER TABLE users DROP COLUMN language;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN language VARCHAR(16) NOT NULL DEFAULT 'en';
+ALTER TABLE email_outbox ADD COLUMN html_body TEXT;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE inbound_email_attachments;
+DROP TABLE inbound_emails;
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+CREATE TABLE inbound_emails (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+    client_id INTEGER REFERENCES clients ON DELETE SET NULL,
+    message_id VARCHAR(255),
+    sender VARCHAR NOT NULL,
+    recipients TEXT[] NOT NULL DEFAULT '{}',
+    subject VARCHAR NOT NULL DEFAULT '',
+    body_text TEXT NOT NULL DEFAULT '',
+    body_html TEXT,
+    sent_at TIMESTAMP,
+    received_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (user_id, message_id)
+);
+
+CREATE INDEX inbound_emails_client_id_idx ON inbound_emails(client_id, received_at);
+-- the review queue, emails no client matched
+CREATE INDEX inbound_emails_review_idx ON inbound_emails(user_id, received_at) WHERE client_id IS NULL;
+
+CREATE TABLE inbound_email_attachments (
+    id SERIAL PRIMARY KEY,
+    inbound_email_id INTEGER NOT NULL REFERENCES inbound_emails ON DELETE CASCADE,
+    filename VARCHAR NOT NULL,
+    content_type VARCHAR NOT NULL,
+    size INTEGER NOT NULL,
+    content BYTEA NOT NULL
+);
+
+CREATE INDEX inbound_email_attachments_email_id_fk ON inbound_email_attachments(inbound_email_id);
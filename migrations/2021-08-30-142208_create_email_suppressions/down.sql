@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE clients DROP COLUMN email_bouncing;
+ALTER TABLE emails DROP COLUMN bouncing;
+DROP TABLE email_suppressions;
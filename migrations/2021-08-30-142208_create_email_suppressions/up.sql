@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE email_suppressions (
+    id SERIAL PRIMARY KEY,
+    -- lowercased
+    address VARCHAR NOT NULL UNIQUE,
+    reason VARCHAR(16) NOT NULL,
+    detail TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE emails ADD COLUMN bouncing BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE clients ADD COLUMN email_bouncing BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE account_deletions;
+DROP TABLE account_exports;
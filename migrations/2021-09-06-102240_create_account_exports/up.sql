@@ -0,0 +1,25 @@
+-- Your SQL goes here
+CREATE TABLE account_exports (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+    -- in the emailed download link
+    token VARCHAR(64) NOT NULL UNIQUE,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    -- the ZIP, dropped once the link expired
+    archive BYTEA,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at TIMESTAMP,
+    expires_at TIMESTAMP
+);
+
+CREATE INDEX account_exports_user_id_fk ON account_exports(user_id);
+CREATE INDEX account_exports_pending_idx ON account_exports(created_at) WHERE status = 'pending';
+
+CREATE TABLE account_deletions (
+    user_id INTEGER PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    requested_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delete_after TIMESTAMP NOT NULL
+);
+
+CREATE INDEX account_deletions_due_idx ON account_deletions(delete_after);
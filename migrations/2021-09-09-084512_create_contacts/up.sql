@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE contacts (
+    id SERIAL PRIMARY KEY,
+    client_id INTEGER NOT NULL REFERENCES clients ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    -- their role at the client, eg. `Billing` or `Project lead`
+    title VARCHAR(255) NOT NULL DEFAULT '',
+    email VARCHAR NOT NULL DEFAULT '',
+    phone VARCHAR(64) NOT NULL DEFAULT '',
+    is_primary BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+SELECT diesel_manage_updated_at('contacts');
+CREATE INDEX contacts_client_id_fk ON contacts(client_id);
+-- a client has one primary contact at most
+CREATE UNIQUE INDEX contacts_primary_idx ON contacts(client_id) WHERE is_primary;
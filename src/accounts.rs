@@ -0,0 +1,133 @@
+use std::io::{Cursor, Write};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use log::{error, info};
+use thiserror::Error as ThisError;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::config::Accounts;
+use crate::db::Repo;
+use crate::email::{send_account_export_email, EmailError};
+use crate::models::account::{
+    account_data, claim_pending_export, complete_export, delete_due_accounts, fail_export,
+    purge_expired_exports, AccountData,
+};
+
+// accounts deleted in one go, the next ones wait for the next round
+const DELETION_BATCH_SIZE: i64 = 20;
+
+#[derive(ThisError, Debug)]
+pub enum AccountWorkError {
+    #[error("{0}")]
+    Email(#[from] EmailError),
+
+    #[error("{0}")]
+    Database(#[from] diesel::result::Error),
+}
+
+/// The ZIP of an export: `user.json`, `emails.json`, `clients.json`,
+/// `contacts.json` and `companies.json`.
+pub fn build_archive(data: &AccountData) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let files = [
+        ("user.json", serde_json::to_vec_pretty(&data.user)),
+        ("emails.json", serde_json::to_vec_pretty(&data.emails)),
+        ("clients.json", serde_json::to_vec_pretty(&data.clients)),
+        ("contacts.json", serde_json::to_vec_pretty(&data.contacts)),
+        ("companies.json", serde_json::to_vec_pretty(&data.companies)),
+    ];
+    for (name, content) in files.iter() {
+        // the records are plain structs, they always serialize
+        let content = content.as_ref().expect("failed to serialize the export");
+        zip.start_file(*name, options)?;
+        zip.write_all(content)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Build the oldest pending export and email its download link, returns
+/// false when there was none.
+pub fn build_next_export(repo: &Repo, config: &Accounts) -> Result<bool, AccountWorkError> {
+    let conn = repo.connection()?;
+
+    conn.transaction(|| {
+        let export = match claim_pending_export(&conn)? {
+            Some(export) => export,
+            None => return Ok(false),
+        };
+
+        let data = account_data(&conn, export.user_id)?;
+        let archive = match build_archive(&data) {
+            Ok(archive) => archive,
+            Err(e) => {
+                error!("Failed to build the export {}: {}", export.id, e);
+                fail_export(&conn, export.id, &e.to_string())?;
+                return Ok(true);
+            }
+        };
+
+        let expires_at = Utc::now().naive_utc() + ChronoDuration::days(config.export_expiry_days);
+        complete_export(&conn, export.id, &archive, expires_at)?;
+        if let Some(address) = data.emails.first() {
+            send_account_export_email(
+                &conn,
+                &address.email,
+                &data.user.username,
+                &data.user.language,
+                &export.token,
+                &expires_at,
+            )?;
+        }
+        info!(
+            "Built the export {} of user {}, {} bytes",
+            export.id,
+            export.user_id,
+            archive.len()
+        );
+
+        Ok(true)
+    })
+}
+
+// one round of the worker, returns whether there may be more to do
+fn run_account_work(repo: &Repo, config: &Accounts) -> Result<bool, AccountWorkError> {
+    let built = build_next_export(repo, config)?;
+
+    let conn = repo.connection()?;
+    let deleted = delete_due_accounts(&conn, DELETION_BATCH_SIZE)?;
+    for user_id in &deleted {
+        info!("Deleted the account of user {}", user_id);
+    }
+    purge_expired_exports(&conn)?;
+
+    Ok(built || deleted.len() as i64 >= DELETION_BATCH_SIZE)
+}
+
+/// Build the data exports and delete the accounts past their grace period
+/// in the background, several instances can run it.
+pub fn spawn_account_worker(repo: Repo, config: Accounts) -> thread::JoinHandle<()> {
+    info!("Starting the account worker");
+
+    thread::Builder::new()
+        .name("account-worker".into())
+        .spawn(move || loop {
+            let wait = match run_account_work(&repo, &config) {
+                // there may be more right away
+                Ok(true) => continue,
+                Ok(false) => config.poll_interval,
+                Err(e) => {
+                    error!("Failed to process the account exports and deletions: {}", e);
+                    config.poll_interval
+                }
+            };
+            thread::sleep(Duration::from_secs(wait));
+        })
+        .expect("failed to spawn the account worker")
+}
@@ -0,0 +1,442 @@
+use std::fs;
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use once_cell::sync::OnceCell;
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error as ThisError;
+
+use crate::config::Auth;
+
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+#[derive(ThisError, Debug)]
+pub enum KeyError {
+    #[error("Failed to read key file `{path}`: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid key file `{path}`: {reason}")]
+    Invalid { path: String, reason: String },
+
+    #[error("No token signing key set, use `auth.signing_key_file` or `JWT_SIGNING_KEY_FILE`")]
+    NoSigningKey,
+}
+
+impl KeyError {
+    fn invalid<R: ToString>(path: &str, reason: R) -> Self {
+        KeyError::Invalid {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// the public half of a key, as published in the JWKS
+#[derive(Debug, Clone)]
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ed25519 { x: Vec<u8> },
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl PublicKey {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            PublicKey::Rsa { .. } => Algorithm::RS256,
+            PublicKey::Ed25519 { .. } => Algorithm::EdDSA,
+        }
+    }
+
+    // only the required members, in lexicographic order, so it can be used
+    // for the RFC 7638 thumbprint as well.
+    fn jwk(&self) -> Value {
+        match self {
+            PublicKey::Rsa { n, e } => json!({ "e": b64(e), "kty": "RSA", "n": b64(n) }),
+            PublicKey::Ed25519 { x } => json!({ "crv": "Ed25519", "kty": "OKP", "x": b64(x) }),
+        }
+    }
+
+    fn thumbprint(&self) -> String {
+        b64(&Sha256::digest(self.jwk().to_string().as_bytes()))
+    }
+
+    fn decoding_key(&self) -> Result<DecodingKey, jsonwebtoken::errors::Error> {
+        match self {
+            PublicKey::Rsa { n, e } => DecodingKey::from_rsa_components(&b64(n), &b64(e)),
+            PublicKey::Ed25519 { x } => DecodingKey::from_ed_components(&b64(x)),
+        }
+    }
+}
+
+// just enough DER to take a SubjectPublicKeyInfo apart, returns the content
+// of the expected element and what follows it.
+fn der_expect(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, rest) = input.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    if found != tag {
+        return None;
+    }
+
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let count = (len & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, remaining) = rest.split_at(count);
+        rest = remaining;
+        bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize)
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+fn unsigned(integer: &[u8]) -> Vec<u8> {
+    let start = integer
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(integer.len());
+    integer[start..].to_vec()
+}
+
+fn parse_public_key(der: &[u8]) -> Option<PublicKey> {
+    let (spki, _) = der_expect(der, 0x30)?;
+    let (algorithm, rest) = der_expect(spki, 0x30)?;
+    let (oid, _) = der_expect(algorithm, 0x06)?;
+    let (bits, _) = der_expect(rest, 0x03)?;
+    let key = bits.strip_prefix(&[0])?;
+
+    if oid == OID_RSA_ENCRYPTION {
+        let (rsa, _) = der_expect(key, 0x30)?;
+        let (n, rest) = der_expect(rsa, 0x02)?;
+        let (e, _) = der_expect(rest, 0x02)?;
+        Some(PublicKey::Rsa {
+            n: unsigned(n),
+            e: unsigned(e),
+        })
+    } else if oid == OID_ED25519 && key.len() == 32 {
+        Some(PublicKey::Ed25519 { x: key.to_vec() })
+    } else {
+        None
+    }
+}
+
+fn private_public_key(pem: &pem::Pem) -> Option<PublicKey> {
+    let rsa = match pem.tag.as_str() {
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(&pem.contents).ok(),
+        "PRIVATE KEY" => RsaKeyPair::from_pkcs8(&pem.contents).ok(),
+        _ => return None,
+    };
+
+    match rsa {
+        Some(pair) => Some(PublicKey::Rsa {
+            n: pair
+                .public_key()
+                .modulus()
+                .big_endian_without_leading_zero()
+                .to_vec(),
+            e: pair
+                .public_key()
+                .exponent()
+                .big_endian_without_leading_zero()
+                .to_vec(),
+        }),
+        None => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pem.contents)
+            .ok()
+            .map(|pair| PublicKey::Ed25519 {
+                x: pair.public_key().as_ref().to_vec(),
+            }),
+    }
+}
+
+fn read_pem(path: &str) -> Result<(Vec<u8>, pem::Pem), KeyError> {
+    let contents = fs::read(path).map_err(|source| KeyError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let pem = pem::parse(&contents).map_err(|e| KeyError::invalid(path, e))?;
+
+    Ok((contents, pem))
+}
+
+/// the key new tokens are signed with
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding: EncodingKey,
+}
+
+impl SigningKey {
+    fn load(path: &str) -> Result<(SigningKey, VerificationKey), KeyError> {
+        let (contents, pem) = read_pem(path)?;
+        let public = private_public_key(&pem).ok_or_else(|| {
+            KeyError::invalid(path, "expected an RSA or Ed25519 private key in PEM format")
+        })?;
+
+        let encoding = match public {
+            PublicKey::Rsa { .. } => EncodingKey::from_rsa_pem(&contents),
+            PublicKey::Ed25519 { .. } => EncodingKey::from_ed_pem(&contents),
+        }
+        .map_err(|e| KeyError::invalid(path, e))?;
+
+        let verification = VerificationKey::new(path, public)?;
+        let signing = SigningKey {
+            kid: verification.kid.clone(),
+            algorithm: verification.algorithm,
+            encoding,
+        };
+
+        Ok((signing, verification))
+    }
+
+    pub fn header(&self) -> Header {
+        Header {
+            kid: Some(self.kid.clone()),
+            ..Header::new(self.algorithm)
+        }
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding
+    }
+}
+
+/// a key tokens are still accepted from
+pub struct VerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    decoding: DecodingKey,
+    public: PublicKey,
+}
+
+impl VerificationKey {
+    fn new(path: &str, public: PublicKey) -> Result<VerificationKey, KeyError> {
+        Ok(VerificationKey {
+            kid: public.thumbprint(),
+            algorithm: public.algorithm(),
+            decoding: public
+                .decoding_key()
+                .map_err(|e| KeyError::invalid(path, e))?,
+            public,
+        })
+    }
+
+    /// either a public key or a previous signing key
+    fn load(path: &str) -> Result<VerificationKey, KeyError> {
+        let (_, pem) = read_pem(path)?;
+        let public = match pem.tag.as_str() {
+            "PUBLIC KEY" => parse_public_key(&pem.contents),
+            _ => private_public_key(&pem),
+        }
+        .ok_or_else(|| KeyError::invalid(path, "expected an RSA or Ed25519 key in PEM format"))?;
+
+        VerificationKey::new(path, public)
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    fn jwk(&self) -> Value {
+        let mut jwk = self.public.jwk();
+        jwk["kid"] = json!(self.kid);
+        jwk["alg"] = json!(match self.algorithm {
+            Algorithm::EdDSA => "EdDSA",
+            _ => "RS256",
+        });
+        jwk["use"] = json!("sig");
+        jwk
+    }
+}
+
+/// The keys used for access tokens.
+///
+/// Tokens are signed with the key in `auth.signing_key_file`, an RSA (RS256)
+/// or Ed25519 (EdDSA) private key in PEM format. To rotate it, move the old
+/// key file to `auth.verification_key_files` (public or private key files)
+/// so tokens it signed stay valid until they expire. Keys are identified by
+/// their RFC 7638 thumbprint in the `kid` header.
+pub struct KeySet {
+    signing: SigningKey,
+    verification: Vec<VerificationKey>,
+}
+
+impl KeySet {
+    pub fn load(signing_path: &str, verification_paths: &[&str]) -> Result<KeySet, KeyError> {
+        let (signing, current) = SigningKey::load(signing_path)?;
+
+        let mut verification = vec![current];
+        for path in verification_paths {
+            let key = VerificationKey::load(path)?;
+            if verification.iter().all(|k| k.kid != key.kid) {
+                verification.push(key);
+            }
+        }
+
+        Ok(KeySet {
+            signing,
+            verification,
+        })
+    }
+
+    /// only the server needs the keys, the configuration doesn't require
+    /// them so the other commands run without
+    pub fn from_config(config: &Auth) -> Result<KeySet, KeyError> {
+        if config.signing_key_file.is_empty() {
+            return Err(KeyError::NoSigningKey);
+        }
+        let verification_paths: Vec<&str> = config
+            .verification_key_files
+            .iter()
+            .map(String::as_str)
+            .collect();
+
+        KeySet::load(&config.signing_key_file, &verification_paths)
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing
+    }
+
+    pub fn verification_key(&self, kid: &str) -> Option<&VerificationKey> {
+        self.verification.iter().find(|key| key.kid == kid)
+    }
+
+    /// the public keys as a JSON Web Key Set
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": self.verification.iter().map(VerificationKey::jwk).collect::<Vec<_>>()
+        })
+    }
+}
+
+static KEYS: OnceCell<KeySet> = OnceCell::new();
+
+/// load the token keys, has to be called before the server starts
+pub fn init_keys(config: &Auth) -> Result<(), KeyError> {
+    let keys = KeySet::from_config(config)?;
+    let _ = KEYS.set(keys);
+    Ok(())
+}
+
+pub fn keys() -> &'static KeySet {
+    KEYS.get()
+        .expect("token keys are not loaded, call `init_keys` first")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_KEY: &str = include_str!("testdata/rsa.pem");
+    const RSA_PUBLIC_KEY: &str = include_str!("testdata/rsa.pub.pem");
+    const ED25519_KEY: &str = include_str!("testdata/ed25519.pem");
+    const ED25519_PUBLIC_KEY: &str = include_str!("testdata/ed25519.pub.pem");
+
+    fn decode(value: &str) -> Vec<u8> {
+        base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap()
+    }
+
+    // the public key read from the private and the public PEM must agree
+    fn round_trip(private: &str, public: &str) -> PublicKey {
+        let from_private = private_public_key(&pem::parse(private).unwrap()).unwrap();
+        let from_public = parse_public_key(&pem::parse(public).unwrap().contents).unwrap();
+
+        assert_eq!(from_private.jwk(), from_public.jwk());
+        assert_eq!(from_private.thumbprint(), from_public.thumbprint());
+        from_public
+    }
+
+    #[test]
+    fn thumbprint_matches_the_rfc_7638_example() {
+        let key = PublicKey::Rsa {
+            n: decode(
+                "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            ),
+            e: decode("AQAB"),
+        };
+
+        assert_eq!(
+            key.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn reads_the_same_rsa_key_from_both_pems() {
+        let key = round_trip(RSA_KEY, RSA_PUBLIC_KEY);
+
+        assert_eq!(key.algorithm(), Algorithm::RS256);
+        assert!(
+            matches!(key, PublicKey::Rsa { ref n, ref e } if n.len() == 256 && e == &[1, 0, 1])
+        );
+    }
+
+    #[test]
+    fn reads_the_same_ed25519_key_from_both_pems() {
+        let key = round_trip(ED25519_KEY, ED25519_PUBLIC_KEY);
+
+        assert_eq!(key.algorithm(), Algorithm::EdDSA);
+        assert!(matches!(key, PublicKey::Ed25519 { ref x } if x.len() == 32));
+    }
+
+    #[test]
+    fn der_expect_reads_short_and_long_lengths() {
+        assert_eq!(
+            der_expect(&[0x02, 0x01, 0x07, 0xff], 0x02),
+            Some((&[0x07][..], &[0xff][..]))
+        );
+
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend(vec![0xaa; 0x80]);
+        assert_eq!(der_expect(&long, 0x04).map(|(c, _)| c.len()), Some(0x80));
+    }
+
+    #[test]
+    fn der_expect_refuses_bad_lengths() {
+        // wrong tag, then content shorter than its length
+        assert_eq!(der_expect(&[0x02, 0x01, 0x07], 0x30), None);
+        assert_eq!(der_expect(&[0x30, 0x05, 0x01, 0x02], 0x30), None);
+        assert_eq!(der_expect(&[0x30, 0x82, 0x01, 0x00, 0x00], 0x30), None);
+        assert_eq!(
+            der_expect(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00], 0x30),
+            None
+        );
+        // indefinite, more than 4 length bytes, and missing length bytes
+        assert_eq!(der_expect(&[0x30, 0x80, 0x00, 0x00], 0x30), None);
+        assert_eq!(
+            der_expect(&[0x30, 0x85, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00], 0x30),
+            None
+        );
+        assert_eq!(der_expect(&[0x30, 0x82, 0x01], 0x30), None);
+        assert_eq!(der_expect(&[0x30], 0x30), None);
+    }
+
+    #[test]
+    fn parse_public_key_refuses_a_truncated_key() {
+        let der = pem::parse(RSA_PUBLIC_KEY).unwrap().contents;
+
+        for len in &[0, 1, 10, der.len() / 2, der.len() - 1] {
+            assert!(parse_public_key(&der[..*len]).is_none(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn unsigned_strips_the_sign_byte() {
+        assert_eq!(unsigned(&[0x00, 0x80, 0x01]), vec![0x80, 0x01]);
+        assert_eq!(unsigned(&[0x01, 0x00]), vec![0x01, 0x00]);
+        assert_eq!(unsigned(&[0x00]), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,65 @@
+use gotham::state::StateData;
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::{decode, decode_header, encode, TokenData, Validation};
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::config;
+
+mod keys;
+
+pub use self::keys::{init_keys, keys, KeyError, KeySet, SigningKey, VerificationKey};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Claims {
+    sub: i32,
+    exp: u64,
+}
+
+impl Claims {
+    pub fn new(user_id: i32, expire_in: u64) -> Claims {
+        Claims {
+            sub: user_id,
+            exp: seconds_from_now(expire_in),
+        }
+    }
+
+    pub fn user_id(&self) -> i32 {
+        self.sub
+    }
+}
+
+/// the verified token of the current request, put in the state by the
+/// authentication middleware.
+pub struct AuthorizationToken<T>(pub TokenData<T>);
+
+impl<T: Send + 'static> StateData for AuthorizationToken<T> {}
+
+pub fn encode_token(sub: i32) -> String {
+    let key = keys().signing_key();
+    let claims = Claims::new(sub, config().auth.token_lifetime);
+    encode(&key.header(), &claims, key.encoding_key()).unwrap()
+}
+
+/// verify a token against the key named in its `kid` header
+pub fn decode_token(token: &str) -> Result<TokenData<Claims>, JwtError> {
+    let header = decode_header(token)?;
+    let key = header
+        .kid
+        .as_deref()
+        .and_then(|kid| keys().verification_key(kid))
+        .ok_or_else(|| JwtError::from(ErrorKind::InvalidToken))?;
+    if header.alg != key.algorithm {
+        return Err(ErrorKind::InvalidAlgorithm.into());
+    }
+
+    decode::<Claims>(token, key.decoding_key(), &Validation::new(key.algorithm))
+}
+
+fn seconds_from_now(secs: u64) -> u64 {
+    let expire_time =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(secs);
+
+    expire_time.as_secs()
+}
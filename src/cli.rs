@@ -0,0 +1,269 @@
+use std::error::Error;
+use std::io::{self, BufRead};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use validator::validate_email;
+
+use crate::accounts::spawn_account_worker;
+use crate::auth::init_keys;
+use crate::config::Config;
+use crate::db::{create_repo, establish_connection, pending_migrations, run_migrations};
+use crate::email::try_send_test_email;
+use crate::models::user::{find_user_by_username, list_users, register_user, set_user_password};
+use crate::outbox::spawn_outbox_worker;
+use crate::sql_types::Role;
+use crate::webhooks::spawn_webhook_worker;
+use crate::Lako;
+
+type CommandResult = Result<(), Box<dyn Error>>;
+
+// registration requires the same
+const MIN_USERNAME_LEN: usize = 5;
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// the `lako` command line, `serve` is the default subcommand
+pub fn app() -> App<'static, 'static> {
+    App::new("Lako")
+        .version("0.0.1")
+        .about("Project management tools")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .takes_value(true)
+                .short("c")
+                .long("config")
+                .env("LAKO_CONFIG")
+                .global(true)
+                .help("Path to the TOML configuration file"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .takes_value(true)
+                .short("a")
+                .long("address")
+                .global(true)
+                .help("Server binding address"),
+        )
+        .arg(
+            Arg::with_name("database-url")
+                .takes_value(true)
+                .long("database-url")
+                .global(true)
+                .help("Database connection URL"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .takes_value(true)
+                .long("log-level")
+                .global(true)
+                .help("Log filter, eg. `info,lako=debug`"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .takes_value(true)
+                .long("log-format")
+                .global(true)
+                .possible_values(&["text", "json"])
+                .help("Log output format"),
+        )
+        .arg(migrate_flag())
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Start the HTTP server (default)")
+                .arg(migrate_flag()),
+        )
+        .subcommand(SubCommand::with_name("migrate").about("Run the pending database migrations"))
+        .subcommand(
+            SubCommand::with_name("create-superuser")
+                .about("Register a superuser, the password is asked for")
+                .arg(
+                    Arg::with_name("username")
+                        .long("username")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("email")
+                        .long("email")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reset-password")
+                .about("Set a new password for a user, the password is asked for")
+                .arg(Arg::with_name("username").required(true)),
+        )
+        .subcommand(SubCommand::with_name("list-users").about("List the registered users"))
+        .subcommand(
+            SubCommand::with_name("send-test-email")
+                .about("Send an email to check the mail settings")
+                .arg(Arg::with_name("recipient").required(true))
+                .arg(
+                    Arg::with_name("language")
+                        .long("language")
+                        .takes_value(true)
+                        .help("Language of the email, `email.default_language` by default"),
+                ),
+        )
+}
+
+fn migrate_flag() -> Arg<'static, 'static> {
+    Arg::with_name("migrate")
+        .long("migrate")
+        .help("Run the pending migrations before starting the server")
+}
+
+// ask twice on a terminal, otherwise read a single line from stdin so it
+// can be piped in.
+fn read_new_password() -> Result<String, Box<dyn Error>> {
+    let password = match rpassword::read_password_from_tty(Some("Password: ")) {
+        Ok(password) => {
+            let confirmation = rpassword::read_password_from_tty(Some("Password (again): "))?;
+            if password != confirmation {
+                return Err("Passwords don't match".into());
+            }
+            password
+        }
+        Err(_) => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!(
+            "The password must be at least {} characters",
+            MIN_PASSWORD_LEN
+        )
+        .into());
+    }
+
+    Ok(password)
+}
+
+/// Start the server, the database has to be up to date unless `migrate` is
+/// set, then the pending migrations are run first.
+pub fn serve(config: Config, migrate: bool) -> CommandResult {
+    init_keys(&config.auth).map_err(|e| format!("Failed to load token keys: {}", e))?;
+
+    let repo = create_repo(&config)?;
+    let conn = repo.connection()?;
+    if migrate {
+        run_migrations(&conn)?;
+    } else {
+        let pending = pending_migrations(&conn)?;
+        if !pending.is_empty() {
+            return Err(format!(
+                "The database has {} pending migrations ({}), run `lako migrate` or start with `--migrate`",
+                pending.len(),
+                pending.join(", ")
+            )
+            .into());
+        }
+    }
+    drop(conn);
+
+    if config.email.outbox.worker {
+        spawn_outbox_worker(repo.clone(), config.email.outbox.clone());
+    }
+    if config.webhooks.worker {
+        spawn_webhook_worker(repo.clone(), config.webhooks.clone());
+    }
+    if config.accounts.worker {
+        spawn_account_worker(repo.clone(), config.accounts.clone());
+    }
+
+    // start the app!
+    let app = Lako::new(config);
+    app.run(repo)?;
+    Ok(())
+}
+
+pub fn migrate(config: &Config) -> CommandResult {
+    let conn = establish_connection(config)?;
+    run_migrations(&conn)?;
+
+    println!("Migrations are up to date");
+    Ok(())
+}
+
+pub fn create_superuser(config: &Config, args: &ArgMatches) -> CommandResult {
+    // usernames are lowercased on registration and login
+    let username = args.value_of("username").unwrap().to_ascii_lowercase();
+    let email = args.value_of("email").unwrap().to_ascii_lowercase();
+    if username.chars().count() < MIN_USERNAME_LEN {
+        return Err(format!(
+            "The username must be at least {} characters",
+            MIN_USERNAME_LEN
+        )
+        .into());
+    }
+    if !validate_email(email.as_str()) {
+        return Err(format!("`{}` is not a valid email address", email).into());
+    }
+    let password = read_new_password()?;
+
+    let conn = establish_connection(config)?;
+    let user = register_user(
+        &conn,
+        &username,
+        &email,
+        &password,
+        &Role::Superuser,
+        &config.email.default_language,
+    )?;
+
+    println!(
+        "Superuser `{}` created with id {}, a confirmation email to {} was queued, a running server delivers it",
+        user.username, user.id, email
+    );
+    Ok(())
+}
+
+pub fn reset_password(config: &Config, args: &ArgMatches) -> CommandResult {
+    let username = args.value_of("username").unwrap().to_ascii_lowercase();
+
+    let conn = establish_connection(config)?;
+    let user = find_user_by_username(&conn, &username)?
+        .ok_or_else(|| format!("No user named `{}`", username))?;
+    let password = read_new_password()?;
+    set_user_password(&conn, user.id, &password)?;
+
+    println!("Password of `{}` updated", user.username);
+    Ok(())
+}
+
+pub fn list_all_users(config: &Config) -> CommandResult {
+    let conn = establish_connection(config)?;
+    let users = list_users(&conn)?;
+
+    println!(
+        "{:>6}  {:<24}  {:<9}  {:<32}  VERIFIED",
+        "ID", "USERNAME", "ROLE", "EMAIL"
+    );
+    for (user, email) in users {
+        let (email, verified) = email.unwrap_or_default();
+        println!(
+            "{:>6}  {:<24}  {:<9}  {:<32}  {}",
+            user.id,
+            user.username,
+            format!("{:?}", user.role),
+            email,
+            if verified { "yes" } else { "no" }
+        );
+    }
+    Ok(())
+}
+
+pub fn send_test_email(config: &Config, args: &ArgMatches) -> CommandResult {
+    let recipient = args.value_of("recipient").unwrap();
+    let language = args
+        .value_of("language")
+        .unwrap_or(&config.email.default_language);
+    try_send_test_email(recipient, language)?;
+
+    println!("Test email sent to {}", recipient);
+    Ok(())
+}
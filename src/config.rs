@@ -1,27 +1,409 @@
 use std::env;
 use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
 
-use clap::{App, Arg};
+use clap::ArgMatches;
+use gotham::hyper::header::HeaderName;
+use gotham::hyper::Method;
+use lettre::smtp::SUBMISSION_PORT;
+use once_cell::sync::OnceCell;
 use serde_derive::{Deserialize, Serialize};
 
 // Server default address
 const DEFAULT_SERVER_ADDRESS: &str = "0.0.0.0:8000";
 
-#[derive(Serialize, Deserialize, Clone)]
+/// The application configuration.
+///
+/// Values are layered, each one overriding the previous: the defaults, the
+/// TOML file given with `--config`, environment variables and finally the
+/// command line arguments.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub server: Server,
+    pub database: Database,
+    pub auth: Auth,
+    pub email: Email,
+    pub logging: Logging,
+    pub cors: Cors,
+    pub rate_limit: RateLimit,
+    pub webhooks: Webhooks,
+    pub accounts: Accounts,
+    pub oidc: Oidc,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Server {
     pub address: String,
-    pub db_url: String,
+    /// where users reach the web application, links in emails point there
+    pub public_url: String,
+    /// seconds in-flight requests get to finish on SIGTERM or SIGINT
+    pub shutdown_timeout: u64,
+    /// serve HTTPS instead of HTTP
+    pub tls: Option<Tls>,
 }
 
-impl Config {
-    // create new config
-    pub fn new(server: Server) -> Config {
-        Config { server: server }
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            address: DEFAULT_SERVER_ADDRESS.to_string(),
+            public_url: "https://lako.io".to_string(),
+            shutdown_timeout: 30,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// PEM certificate chain, the server certificate first
+    pub cert_file: String,
+    /// PEM private key of the certificate, PKCS#8 or RSA
+    pub key_file: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_idle: Option<u32>,
+    /// seconds to wait for a connection from the pool
+    pub connection_timeout: u64,
+    /// seconds before an unused connection is closed
+    pub idle_timeout: Option<u64>,
+    /// milliseconds a statement may run before it's cancelled
+    pub statement_timeout: Option<u64>,
+    /// attempts to reach the database at startup, with a backoff between
+    pub connect_retries: u32,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            url: String::new(),
+            max_connections: 10,
+            min_idle: None,
+            connection_timeout: 30,
+            idle_timeout: Some(600),
+            statement_timeout: None,
+            connect_retries: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// PEM private key access tokens are signed with
+    pub signing_key_file: String,
+    /// keys of tokens still accepted, see `auth::KeySet`
+    pub verification_key_files: Vec<String>,
+    /// lifetime of access tokens, in seconds
+    pub token_lifetime: u64,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            signing_key_file: String::new(),
+            verification_key_files: Vec::new(),
+            token_lifetime: 86400,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Email {
+    pub from_address: String,
+    pub from_name: String,
+    /// `smtp`, `file`, `maildir`, `stdout` or `memory`, by default `smtp`
+    /// when there is an SMTP server and `file` otherwise
+    pub transport: Option<String>,
+    pub smtp: Option<SmtpConfig>,
+    /// directory of the `file` and `maildir` transports
+    pub output_dir: String,
+    /// `*.hbs` files replacing or adding to the built-in email templates
+    pub templates_dir: Option<String>,
+    /// language of the emails when there are no templates in the user one
+    pub default_language: String,
+    /// shared secret of `/api/v1/email/bounces`, the route is disabled
+    /// without one
+    pub bounce_secret: Option<String>,
+    pub outbox: Outbox,
+}
+
+impl Email {
+    /// the transport emails are delivered with, see `email.transport`
+    pub fn transport_name(&self) -> &str {
+        match (&self.transport, &self.smtp) {
+            (Some(transport), _) => transport,
+            (None, Some(_)) => "smtp",
+            (None, None) => "file",
+        }
+    }
+}
+
+impl Default for Email {
+    fn default() -> Self {
+        Email {
+            from_address: "test@localhost".to_string(),
+            from_name: "Lako".to_string(),
+            transport: None,
+            smtp: None,
+            output_dir: "/tmp".to_string(),
+            templates_dir: None,
+            default_language: "en".to_string(),
+            bounce_secret: None,
+            outbox: Outbox::default(),
+        }
+    }
+}
+
+/// Delivery of the queued emails, see `outbox::spawn_outbox_worker`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Outbox {
+    /// run the delivery worker in this instance
+    pub worker: bool,
+    /// seconds between two looks at the outbox when it's empty
+    pub poll_interval: u64,
+    pub batch_size: i64,
+    /// failed deliveries before an email is dead-lettered
+    pub max_attempts: i32,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            worker: true,
+            poll_interval: 5,
+            batch_size: 20,
+            max_attempts: 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    /// no authentication when empty
+    pub username: String,
+    pub password: String,
+    pub server: String,
+    pub port: u16,
+    /// `tls` for implicit TLS, `starttls`, or `none` for a local server
+    pub security: String,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            username: String::new(),
+            password: String::new(),
+            server: String::new(),
+            port: SUBMISSION_PORT,
+            security: "tls".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// filter in the `RUST_LOG` format, eg. `info,lako=debug`
+    pub level: String,
+    /// `text` for people, `json` for one JSON object per line
+    pub format: String,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "info".to_string(),
+            format: "text".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    /// origins allowed to call the API, eg. `https://app.example.com`, or
+    /// `*` for any. CORS is disabled when empty.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// request headers allowed, `*` for any
+    pub allowed_headers: Vec<String>,
+    /// response headers the browser may read
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// seconds a preflight response may be cached
+    pub max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        Cors {
+            allowed_origins: Vec::new(),
+            allowed_methods: strings(&["GET", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: strings(&[
+                "authorization",
+                "content-type",
+                "x-api-key",
+                "x-request-id",
+            ]),
+            exposed_headers: strings(&["x-request-id"]),
+            allow_credentials: false,
+            max_age: Some(3600),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub enabled: bool,
+    /// `memory` keeps the buckets in the process, `postgres` shares them
+    /// between instances
+    pub backend: String,
+    /// register, login, email confirmation and OIDC routes
+    pub auth: RateLimitGroup,
+    /// routes sending emails, eg. resending the confirmation
+    pub email: RateLimitGroup,
+    /// all the other authenticated routes
+    pub api: RateLimitGroup,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            enabled: true,
+            backend: "memory".to_string(),
+            auth: RateLimitGroup::new(10, 10, "ip"),
+            email: RateLimitGroup::new(3, 1, "user"),
+            api: RateLimitGroup::new(60, 120, "user"),
+        }
+    }
+}
+
+/// Delivery of the webhook events, see `webhooks::spawn_webhook_worker`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhooks {
+    /// run the delivery worker in this instance
+    pub worker: bool,
+    /// seconds between two looks at the deliveries when none is due
+    pub poll_interval: u64,
+    pub batch_size: i64,
+    /// failed deliveries before giving up on an event
+    pub max_attempts: i32,
+    /// seconds an endpoint gets to answer
+    pub timeout: u64,
+    /// allow webhooks to loopback, private and link-local addresses, only
+    /// when every user can be trusted with the network of the server
+    pub allow_private_networks: bool,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks {
+            worker: true,
+            poll_interval: 5,
+            batch_size: 20,
+            max_attempts: 8,
+            timeout: 10,
+            allow_private_networks: false,
+        }
+    }
+}
+
+/// Data exports and deletion of the accounts, see
+/// `accounts::spawn_account_worker`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Accounts {
+    /// run the export and deletion worker in this instance
+    pub worker: bool,
+    /// seconds between two looks for exports to build and accounts to delete
+    pub poll_interval: u64,
+    /// days the download link of an export works
+    pub export_expiry_days: i64,
+    /// days before the account is deleted, it can be cancelled meanwhile
+    pub deletion_grace_days: i64,
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Accounts {
+            worker: true,
+            poll_interval: 30,
+            export_expiry_days: 7,
+            deletion_grace_days: 14,
+        }
+    }
+}
+
+/// A token bucket holding `burst` requests, refilled with `per_minute`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitGroup {
+    pub burst: u32,
+    pub per_minute: u32,
+    /// `ip`, or `user` for the authenticated user falling back to the IP
+    pub key: String,
+}
+
+impl RateLimitGroup {
+    fn new(burst: u32, per_minute: u32, key: &str) -> Self {
+        RateLimitGroup {
+            burst,
+            per_minute,
+            key: key.to_string(),
+        }
+    }
+}
+
+/// Sign in with OpenID Connect, see `oidc::discover`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Oidc {
+    pub providers: Vec<OidcProvider>,
+}
+
+/// An OpenID Connect provider users can sign in with, at
+/// `/api/v1/oidc/<name>/authorize`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcProvider {
+    pub name: String,
+    /// the provider discovery document is fetched from
+    /// `<issuer>/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// where the provider sends the user back, `/api/v1/oidc/<name>/callback`
+    pub redirect_url: String,
+    /// space separated, `openid` is required
+    pub scopes: String,
+}
+
+impl Default for OidcProvider {
+    fn default() -> Self {
+        OidcProvider {
+            name: String::new(),
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_url: String::new(),
+            scopes: "openid email profile".to_string(),
+        }
     }
 }
 
@@ -44,37 +426,755 @@ impl fmt::Display for ConfigurationError {
     }
 }
 
-// Loads the configuration file from command
-pub fn load_configuration() -> Result<Config, ConfigurationError> {
-    let matches = App::new("Lako")
-        .version("0.0.1")
-        .about("Project management tools")
-        .arg(
-            Arg::with_name("address")
-                .takes_value(true)
-                .default_value(DEFAULT_SERVER_ADDRESS)
-                .short("a")
-                .long("address")
-                .help("Server binding address")
-                .required(true),
-        )
-        .get_matches();
+impl std::error::Error for ConfigurationError {}
 
-    let address = matches.value_of("address").unwrap().to_string();
+// parse the environment variable `key` into `target` when it's set
+fn env_override<T>(target: &mut T, key: &str) -> Result<(), ConfigurationError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(key) {
+        *target = value
+            .parse()
+            .map_err(|e| ConfigurationError::new(&format!("Invalid value for `{}`: {}", key, e)))?;
+    }
+    Ok(())
+}
+
+// same as `env_override` for optional values
+fn env_override_opt<T>(target: &mut Option<T>, key: &str) -> Result<(), ConfigurationError>
+where
+    T: FromStr + Default,
+    T::Err: fmt::Display,
+{
+    if env::var(key).is_ok() {
+        let mut value = T::default();
+        env_override(&mut value, key)?;
+        *target = Some(value);
+    }
+    Ok(())
+}
 
-    let db_url: String = match env::var("DATABASE_URL") {
-        Ok(val) => val,
-        Err(err) => {
-            return Err(ConfigurationError::new(&format!(
-                "No Database URL environment variable `DATABASE_URL` set. {}",
-                err
-            )))
+// comma separated list from the environment variable `key`
+fn env_override_list(target: &mut Vec<String>, key: &str) {
+    if let Ok(values) = env::var(key) {
+        *target = values
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+impl Config {
+    /// read the TOML configuration file
+    pub fn from_file(path: &str) -> Result<Config, ConfigurationError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            ConfigurationError::new(&format!("Failed to read configuration `{}`: {}", path, e))
+        })?;
+
+        toml::from_str(&contents).map_err(|e| {
+            ConfigurationError::new(&format!("Invalid configuration `{}`: {}", path, e))
+        })
+    }
+
+    /// apply the environment variables on top of the file
+    pub fn merge_env(&mut self) -> Result<(), ConfigurationError> {
+        env_override(&mut self.server.address, "SERVER_ADDRESS")?;
+        env_override(&mut self.server.public_url, "PUBLIC_URL")?;
+        env_override(&mut self.server.shutdown_timeout, "SERVER_SHUTDOWN_TIMEOUT")?;
+        if env::var("TLS_CERT_FILE").is_ok() && self.server.tls.is_none() {
+            self.server.tls = Some(Tls::default());
+        }
+        if let Some(tls) = self.server.tls.as_mut() {
+            env_override(&mut tls.cert_file, "TLS_CERT_FILE")?;
+            env_override(&mut tls.key_file, "TLS_KEY_FILE")?;
+        }
+
+        env_override(&mut self.database.url, "DATABASE_URL")?;
+        env_override(
+            &mut self.database.max_connections,
+            "DATABASE_MAX_CONNECTIONS",
+        )?;
+        env_override_opt(&mut self.database.min_idle, "DATABASE_MIN_IDLE")?;
+        env_override(
+            &mut self.database.connection_timeout,
+            "DATABASE_CONNECTION_TIMEOUT",
+        )?;
+        env_override_opt(&mut self.database.idle_timeout, "DATABASE_IDLE_TIMEOUT")?;
+        env_override_opt(
+            &mut self.database.statement_timeout,
+            "DATABASE_STATEMENT_TIMEOUT",
+        )?;
+        env_override(
+            &mut self.database.connect_retries,
+            "DATABASE_CONNECT_RETRIES",
+        )?;
+
+        env_override(&mut self.auth.signing_key_file, "JWT_SIGNING_KEY_FILE")?;
+        env_override_list(
+            &mut self.auth.verification_key_files,
+            "JWT_VERIFICATION_KEY_FILES",
+        );
+        env_override(&mut self.auth.token_lifetime, "JWT_TOKEN_LIFETIME")?;
+
+        env_override(&mut self.email.from_address, "MAIL_FROM_ADDRESS")?;
+        env_override(&mut self.email.from_name, "MAIL_FROM_NAME")?;
+        env_override_opt(&mut self.email.transport, "MAIL_TRANSPORT")?;
+        env_override(&mut self.email.output_dir, "MAIL_OUTPUT_DIR")?;
+        env_override_opt(&mut self.email.templates_dir, "MAIL_TEMPLATES_DIR")?;
+        env_override(&mut self.email.default_language, "MAIL_DEFAULT_LANGUAGE")?;
+        env_override_opt(&mut self.email.bounce_secret, "MAIL_BOUNCE_SECRET")?;
+        env_override(&mut self.email.outbox.worker, "MAIL_OUTBOX_WORKER")?;
+        env_override(&mut self.email.outbox.max_attempts, "MAIL_MAX_ATTEMPTS")?;
+        if env::var("SMTP_SERVER").is_ok() && self.email.smtp.is_none() {
+            self.email.smtp = Some(SmtpConfig::default());
+        }
+        if let Some(smtp) = self.email.smtp.as_mut() {
+            env_override(&mut smtp.server, "SMTP_SERVER")?;
+            env_override(&mut smtp.port, "SMTP_PORT")?;
+            env_override(&mut smtp.username, "SMTP_USERNAME")?;
+            env_override(&mut smtp.password, "SMTP_PASSWORD")?;
+            env_override(&mut smtp.security, "SMTP_SECURITY")?;
+        }
+
+        env_override(&mut self.logging.level, "RUST_LOG")?;
+        env_override(&mut self.logging.format, "LOG_FORMAT")?;
+
+        env_override_list(&mut self.cors.allowed_origins, "CORS_ALLOWED_ORIGINS");
+        env_override_list(&mut self.cors.allowed_methods, "CORS_ALLOWED_METHODS");
+        env_override_list(&mut self.cors.allowed_headers, "CORS_ALLOWED_HEADERS");
+        env_override_list(&mut self.cors.exposed_headers, "CORS_EXPOSED_HEADERS");
+        env_override(&mut self.cors.allow_credentials, "CORS_ALLOW_CREDENTIALS")?;
+        env_override_opt(&mut self.cors.max_age, "CORS_MAX_AGE")?;
+
+        env_override(&mut self.rate_limit.enabled, "RATE_LIMIT_ENABLED")?;
+        env_override(&mut self.rate_limit.backend, "RATE_LIMIT_BACKEND")?;
+        env_override(&mut self.webhooks.worker, "WEBHOOKS_WORKER")?;
+        env_override(&mut self.webhooks.max_attempts, "WEBHOOKS_MAX_ATTEMPTS")?;
+        env_override(
+            &mut self.webhooks.allow_private_networks,
+            "WEBHOOKS_ALLOW_PRIVATE_NETWORKS",
+        )?;
+        env_override(&mut self.accounts.worker, "ACCOUNTS_WORKER")?;
+        env_override(
+            &mut self.accounts.deletion_grace_days,
+            "ACCOUNTS_DELETION_GRACE_DAYS",
+        )?;
+
+        // `OIDC_PROVIDERS` lists the providers set with `OIDC_<NAME>_*`
+        let mut names = Vec::new();
+        env_override_list(&mut names, "OIDC_PROVIDERS");
+        for name in names {
+            let index = match self.oidc.providers.iter().position(|p| p.name == name) {
+                Some(index) => index,
+                None => {
+                    self.oidc.providers.push(OidcProvider {
+                        name: name.clone(),
+                        ..OidcProvider::default()
+                    });
+                    self.oidc.providers.len() - 1
+                }
+            };
+            let provider = &mut self.oidc.providers[index];
+            let prefix = format!("OIDC_{}_", name.to_ascii_uppercase());
+            env_override(&mut provider.issuer, &format!("{}ISSUER", prefix))?;
+            env_override(&mut provider.client_id, &format!("{}CLIENT_ID", prefix))?;
+            env_override(
+                &mut provider.client_secret,
+                &format!("{}CLIENT_SECRET", prefix),
+            )?;
+            env_override(
+                &mut provider.redirect_url,
+                &format!("{}REDIRECT_URL", prefix),
+            )?;
+            env_override(&mut provider.scopes, &format!("{}SCOPES", prefix))?;
+        }
+
+        Ok(())
+    }
+
+    /// check the configuration before starting anything with it
+    pub fn validate(&self) -> Result<(), ConfigurationError> {
+        let fail = |msg: &str| Err(ConfigurationError::new(msg));
+
+        let mut addrs = self.server.address.to_socket_addrs();
+        if !matches!(addrs.as_mut().map(Iterator::next), Ok(Some(_))) {
+            return fail(&format!(
+                "`server.address` `{}` is not a valid address",
+                self.server.address
+            ));
+        }
+
+        let public_url = &self.server.public_url;
+        if !(public_url.starts_with("http://") || public_url.starts_with("https://")) {
+            return fail(&format!(
+                "`server.public_url` `{}` must be an http or https URL",
+                public_url
+            ));
+        }
+
+        if let Some(tls) = &self.server.tls {
+            if tls.cert_file.is_empty() || tls.key_file.is_empty() {
+                return fail("`server.tls` needs a `cert_file` and `key_file`");
+            }
+        }
+
+        if self.database.url.is_empty() {
+            return fail("No database URL set, use `database.url` or `DATABASE_URL`");
+        }
+        if self.database.max_connections == 0 {
+            return fail("`database.max_connections` must be at least 1");
+        }
+        if matches!(self.database.min_idle, Some(min_idle) if min_idle > self.database.max_connections)
+        {
+            return fail("`database.min_idle` can't be more than `database.max_connections`");
+        }
+        if self.database.connection_timeout == 0 {
+            return fail("`database.connection_timeout` must be at least 1 second");
+        }
+        if self.database.idle_timeout == Some(0) {
+            return fail("`database.idle_timeout` must be at least 1 second");
+        }
+        if self.database.statement_timeout == Some(0) {
+            return fail("`database.statement_timeout` must be at least 1 millisecond");
+        }
+
+        if self.auth.token_lifetime == 0 {
+            return fail("`auth.token_lifetime` must be at least 1 second");
+        }
+
+        if !self.email.from_address.contains('@') {
+            return fail(&format!(
+                "`email.from_address` `{}` is not an email address",
+                self.email.from_address
+            ));
+        }
+        let transport = self.email.transport_name();
+        if !matches!(transport, "smtp" | "file" | "maildir" | "stdout" | "memory") {
+            return fail(&format!(
+                "`email.transport` `{}` must be one of `smtp`, `file`, `maildir`, `stdout` or `memory`",
+                transport
+            ));
+        }
+        if transport == "smtp" && self.email.smtp.is_none() {
+            return fail("The `smtp` transport needs an `[email.smtp]` section");
+        }
+        if let Some(smtp) = &self.email.smtp {
+            if smtp.server.is_empty() {
+                return fail("`email.smtp` needs a `server`");
+            }
+            if smtp.username.is_empty() != smtp.password.is_empty() {
+                return fail("`email.smtp` needs both a `username` and `password`, or neither");
+            }
+            if smtp.port == 0 {
+                return fail("`email.smtp.port` is not a valid port");
+            }
+            if !matches!(smtp.security.as_str(), "tls" | "starttls" | "none") {
+                return fail(&format!(
+                    "`email.smtp.security` `{}` must be one of `tls`, `starttls` or `none`",
+                    smtp.security
+                ));
+            }
+        }
+        if self.email.default_language.is_empty() {
+            return fail("`email.default_language` can't be empty");
+        }
+        if matches!(&self.email.bounce_secret, Some(secret) if secret.len() < 16) {
+            return fail("`email.bounce_secret` must be at least 16 characters");
+        }
+        if self.email.outbox.poll_interval == 0 {
+            return fail("`email.outbox.poll_interval` must be at least 1 second");
+        }
+        if self.email.outbox.batch_size < 1 {
+            return fail("`email.outbox.batch_size` must be at least 1");
+        }
+        if self.email.outbox.max_attempts < 1 {
+            return fail("`email.outbox.max_attempts` must be at least 1");
+        }
+
+        if self.logging.level.trim().is_empty() {
+            return fail("`logging.level` can't be empty");
+        }
+        if !matches!(self.logging.format.as_str(), "text" | "json") {
+            return fail(&format!(
+                "`logging.format` `{}` must be either `text` or `json`",
+                self.logging.format
+            ));
+        }
+
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && !(origin.starts_with("http://") || origin.starts_with("https://")) {
+                return fail(&format!(
+                    "`cors.allowed_origins` `{}` must be `*` or a scheme and host, eg. `https://example.com`",
+                    origin
+                ));
+            }
+        }
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|o| o == "*") {
+            return fail("`cors.allow_credentials` can't be used with the `*` origin");
+        }
+        for method in &self.cors.allowed_methods {
+            if method.parse::<Method>().is_err() {
+                return fail(&format!(
+                    "`cors.allowed_methods` `{}` is not an HTTP method",
+                    method
+                ));
+            }
+        }
+        for header in self
+            .cors
+            .allowed_headers
+            .iter()
+            .chain(&self.cors.exposed_headers)
+        {
+            if header != "*" && header.parse::<HeaderName>().is_err() {
+                return fail(&format!("`{}` is not a valid header name", header));
+            }
+        }
+
+        if !matches!(self.rate_limit.backend.as_str(), "memory" | "postgres") {
+            return fail(&format!(
+                "`rate_limit.backend` `{}` must be either `memory` or `postgres`",
+                self.rate_limit.backend
+            ));
+        }
+        let groups = [
+            ("auth", &self.rate_limit.auth),
+            ("email", &self.rate_limit.email),
+            ("api", &self.rate_limit.api),
+        ];
+        for (name, group) in groups.iter() {
+            if group.burst == 0 || group.per_minute == 0 {
+                return fail(&format!(
+                    "`rate_limit.{}` needs a `burst` and `per_minute` of at least 1",
+                    name
+                ));
+            }
+            if !matches!(group.key.as_str(), "ip" | "user") {
+                return fail(&format!(
+                    "`rate_limit.{}.key` `{}` must be either `ip` or `user`",
+                    name, group.key
+                ));
+            }
+        }
+
+        if self.webhooks.poll_interval == 0 {
+            return fail("`webhooks.poll_interval` must be at least 1 second");
+        }
+        if self.webhooks.batch_size < 1 {
+            return fail("`webhooks.batch_size` must be at least 1");
+        }
+        if self.webhooks.max_attempts < 1 {
+            return fail("`webhooks.max_attempts` must be at least 1");
         }
+        if self.webhooks.timeout == 0 {
+            return fail("`webhooks.timeout` must be at least 1 second");
+        }
+        if self.accounts.poll_interval == 0 {
+            return fail("`accounts.poll_interval` must be at least 1 second");
+        }
+        if self.accounts.export_expiry_days < 1 {
+            return fail("`accounts.export_expiry_days` must be at least 1");
+        }
+        if self.accounts.deletion_grace_days < 0 {
+            return fail("`accounts.deletion_grace_days` can't be negative");
+        }
+
+        let is_http_url = |url: &str| url.starts_with("http://") || url.starts_with("https://");
+        for (i, provider) in self.oidc.providers.iter().enumerate() {
+            let name = &provider.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+            {
+                return fail(&format!(
+                    "`oidc.providers` name `{}` must be lowercase letters, digits, `-` or `_`",
+                    name
+                ));
+            }
+            if self.oidc.providers[..i].iter().any(|p| &p.name == name) {
+                return fail(&format!("`oidc.providers` `{}` is set twice", name));
+            }
+            if !is_http_url(&provider.issuer) {
+                return fail(&format!(
+                    "`oidc.providers` `{}` needs an http or https `issuer`",
+                    name
+                ));
+            }
+            if provider.client_id.is_empty() || provider.client_secret.is_empty() {
+                return fail(&format!(
+                    "`oidc.providers` `{}` needs a `client_id` and `client_secret`",
+                    name
+                ));
+            }
+            if !is_http_url(&provider.redirect_url) {
+                return fail(&format!(
+                    "`oidc.providers` `{}` needs an http or https `redirect_url`",
+                    name
+                ));
+            }
+            if !provider
+                .scopes
+                .split_whitespace()
+                .any(|scope| scope == "openid")
+            {
+                return fail(&format!(
+                    "`oidc.providers` `{}` scopes must include `openid`",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// make the configuration available through `config()`
+pub fn init_config(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+pub fn config() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("configuration is not loaded, call `init_config` first")
+}
+
+// Loads the configuration file from the command line arguments
+pub fn load_configuration(matches: &ArgMatches) -> Result<Config, ConfigurationError> {
+    // global arguments may come before or after the subcommand
+    let value_of = |name: &str| {
+        matches
+            .subcommand()
+            .1
+            .and_then(|sub| sub.value_of(name))
+            .or_else(|| matches.value_of(name))
+            .map(String::from)
+    };
+
+    let mut configuration = match value_of("config") {
+        Some(path) => Config::from_file(&path)?,
+        None => Config::default(),
     };
+    configuration.merge_env()?;
 
-    let server = Server { address, db_url };
+    if let Some(address) = value_of("address") {
+        configuration.server.address = address;
+    }
+    if let Some(db_url) = value_of("database-url") {
+        configuration.database.url = db_url;
+    }
+    if let Some(level) = value_of("log-level") {
+        configuration.logging.level = level;
+    }
+    if let Some(format) = value_of("log-format") {
+        configuration.logging.format = format;
+    }
 
-    let configuration = Config::new(server);
+    configuration.validate()?;
 
     Ok(configuration)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.database.url = "postgres://localhost/lako".to_string();
+        config
+    }
+
+    fn provider(name: &str) -> OidcProvider {
+        OidcProvider {
+            name: name.to_string(),
+            issuer: "https://accounts.example.com".to_string(),
+            client_id: "lako".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: format!("https://lako.io/api/v1/oidc/{}/callback", name),
+            ..OidcProvider::default()
+        }
+    }
+
+    fn smtp() -> SmtpConfig {
+        SmtpConfig {
+            server: "smtp.example.com".to_string(),
+            username: "lako".to_string(),
+            password: "secret".to_string(),
+            ..SmtpConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_defaults_with_a_database_are_valid() {
+        assert!(valid().validate().is_ok());
+        assert!(Config::default().validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_each_invalid_value() {
+        let cases: Vec<(fn(&mut Config), &str)> = vec![
+            (|c| c.server.address = "nowhere".into(), "server.address"),
+            (
+                |c| c.server.public_url = "lako.io".into(),
+                "server.public_url",
+            ),
+            (|c| c.server.tls = Some(Tls::default()), "server.tls"),
+            (|c| c.database.url.clear(), "database URL"),
+            (|c| c.database.max_connections = 0, "max_connections"),
+            (|c| c.database.min_idle = Some(11), "min_idle"),
+            (|c| c.database.connection_timeout = 0, "connection_timeout"),
+            (|c| c.database.idle_timeout = Some(0), "idle_timeout"),
+            (
+                |c| c.database.statement_timeout = Some(0),
+                "statement_timeout",
+            ),
+            (|c| c.auth.token_lifetime = 0, "token_lifetime"),
+            (|c| c.email.from_address = "lako".into(), "from_address"),
+            (
+                |c| c.email.transport = Some("pigeon".into()),
+                "email.transport",
+            ),
+            (|c| c.email.transport = Some("smtp".into()), "[email.smtp]"),
+            (
+                |c| c.email.smtp = Some(SmtpConfig::default()),
+                "needs a `server`",
+            ),
+            (
+                |c| {
+                    c.email.smtp = Some(SmtpConfig {
+                        password: String::new(),
+                        ..smtp()
+                    })
+                },
+                "or neither",
+            ),
+            (
+                |c| c.email.smtp = Some(SmtpConfig { port: 0, ..smtp() }),
+                "smtp.port",
+            ),
+            (
+                |c| {
+                    c.email.smtp = Some(SmtpConfig {
+                        security: "ssl".into(),
+                        ..smtp()
+                    })
+                },
+                "smtp.security",
+            ),
+            (|c| c.email.default_language.clear(), "default_language"),
+            (
+                |c| c.email.bounce_secret = Some("short".into()),
+                "bounce_secret",
+            ),
+            (|c| c.email.outbox.poll_interval = 0, "outbox.poll_interval"),
+            (|c| c.email.outbox.batch_size = 0, "outbox.batch_size"),
+            (|c| c.email.outbox.max_attempts = 0, "outbox.max_attempts"),
+            (|c| c.logging.level = " ".into(), "logging.level"),
+            (|c| c.logging.format = "xml".into(), "logging.format"),
+            (
+                |c| c.cors.allowed_origins = vec!["lako.io".into()],
+                "allowed_origins",
+            ),
+            (
+                |c| {
+                    c.cors.allowed_origins = vec!["*".into()];
+                    c.cors.allow_credentials = true;
+                },
+                "allow_credentials",
+            ),
+            (
+                |c| c.cors.allowed_methods = vec!["GE T".into()],
+                "allowed_methods",
+            ),
+            (
+                |c| c.cors.exposed_headers = vec!["x request".into()],
+                "valid header name",
+            ),
+            (
+                |c| c.rate_limit.backend = "redis".into(),
+                "rate_limit.backend",
+            ),
+            (|c| c.rate_limit.email.burst = 0, "rate_limit.email"),
+            (
+                |c| c.rate_limit.api.key = "host".into(),
+                "rate_limit.api.key",
+            ),
+            (|c| c.webhooks.poll_interval = 0, "webhooks.poll_interval"),
+            (|c| c.webhooks.batch_size = 0, "webhooks.batch_size"),
+            (|c| c.webhooks.max_attempts = 0, "webhooks.max_attempts"),
+            (|c| c.webhooks.timeout = 0, "webhooks.timeout"),
+            (|c| c.accounts.poll_interval = 0, "accounts.poll_interval"),
+            (|c| c.accounts.export_expiry_days = 0, "export_expiry_days"),
+            (
+                |c| c.accounts.deletion_grace_days = -1,
+                "deletion_grace_days",
+            ),
+            (
+                |c| c.oidc.providers = vec![provider("Google")],
+                "must be lowercase",
+            ),
+            (
+                |c| c.oidc.providers = vec![provider("google"), provider("google")],
+                "set twice",
+            ),
+            (
+                |c| {
+                    c.oidc.providers = vec![OidcProvider {
+                        issuer: "accounts.example.com".into(),
+                        ..provider("google")
+                    }]
+                },
+                "`issuer`",
+            ),
+            (
+                |c| {
+                    c.oidc.providers = vec![OidcProvider {
+                        client_secret: String::new(),
+                        ..provider("google")
+                    }]
+                },
+                "`client_secret`",
+            ),
+            (
+                |c| {
+                    c.oidc.providers = vec![OidcProvider {
+                        redirect_url: "/callback".into(),
+                        ..provider("google")
+                    }]
+                },
+                "`redirect_url`",
+            ),
+            (
+                |c| {
+                    c.oidc.providers = vec![OidcProvider {
+                        scopes: "email profile".into(),
+                        ..provider("google")
+                    }]
+                },
+                "`openid`",
+            ),
+        ];
+
+        for (change, expected) in cases {
+            let mut config = valid();
+            change(&mut config);
+            let error = config
+                .validate()
+                .err()
+                .unwrap_or_else(|| panic!("expected an error about {}", expected))
+                .to_string();
+            assert!(error.contains(expected), "{} in {}", expected, error);
+        }
+    }
+
+    // the environment is shared by the whole process, keep all the
+    // variables in one test
+    #[test]
+    fn merge_env_overrides_the_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [database]
+            url = "postgres://localhost/lako"
+            max_connections = 4
+
+            [[oidc.providers]]
+            name = "google"
+            issuer = "https://accounts.google.com"
+            client_id = "from-file"
+            client_secret = "secret"
+            redirect_url = "https://lako.io/api/v1/oidc/google/callback"
+            "#,
+        )
+        .unwrap();
+
+        env::set_var("DATABASE_MAX_CONNECTIONS", "12");
+        env::set_var("JWT_VERIFICATION_KEY_FILES", "old.pem, ,older.pem");
+        env::set_var("OIDC_PROVIDERS", "google,gitlab");
+        env::set_var("OIDC_GOOGLE_CLIENT_ID", "from-env");
+        env::set_var("OIDC_GITLAB_ISSUER", "https://gitlab.com");
+        let result = config.merge_env();
+        env::set_var("DATABASE_MAX_CONNECTIONS", "many");
+        let invalid = valid().merge_env();
+        for key in &[
+            "DATABASE_MAX_CONNECTIONS",
+            "JWT_VERIFICATION_KEY_FILES",
+            "OIDC_PROVIDERS",
+            "OIDC_GOOGLE_CLIENT_ID",
+            "OIDC_GITLAB_ISSUER",
+        ] {
+            env::remove_var(key);
+        }
+
+        assert!(result.is_ok());
+        assert_eq!(config.database.max_connections, 12);
+        assert_eq!(
+            config.auth.verification_key_files,
+            vec!["old.pem", "older.pem"]
+        );
+        let providers = &config.oidc.providers;
+        assert_eq!(providers.len(), 2);
+        assert_eq!(providers[0].client_id, "from-env");
+        assert_eq!(providers[0].client_secret, "secret");
+        assert_eq!(providers[1].name, "gitlab");
+        assert_eq!(providers[1].issuer, "https://gitlab.com");
+        assert_eq!(providers[1].scopes, "openid email profile");
+
+        let error = invalid.err().unwrap().to_string();
+        assert!(error.contains("DATABASE_MAX_CONNECTIONS"), "{}", error);
+    }
+
+    #[test]
+    fn oidc_providers_are_a_toml_array_of_tables() {
+        let config: Config = toml::from_str(
+            r#"
+            [database]
+            url = "postgres://localhost/lako"
+
+            [[oidc.providers]]
+            name = "google"
+            issuer = "https://accounts.google.com"
+            client_id = "lako"
+            client_secret = "secret"
+            redirect_url = "https://lako.io/api/v1/oidc/google/callback"
+
+            [[oidc.providers]]
+            name = "gitlab"
+            issuer = "https://gitlab.com"
+            client_id = "lako"
+            client_secret = "secret"
+            redirect_url = "https://lako.io/api/v1/oidc/gitlab/callback"
+            scopes = "openid email"
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.validate().is_ok());
+        let names: Vec<_> = config
+            .oidc
+            .providers
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["google", "gitlab"]);
+        assert_eq!(config.oidc.providers[0].scopes, "openid email profile");
+        assert_eq!(config.oidc.providers[1].scopes, "openid email");
+
+        let unknown =
+            toml::from_str::<Config>("[[oidc.providers]]\nname = \"a\"\nsecret = \"b\"\n");
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn the_example_configuration_is_valid() {
+        let config: Config = toml::from_str(include_str!("../lako.example.toml")).unwrap();
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,11 +1,202 @@
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use diesel::connection::SimpleConnection;
 use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::sql_types::BigInt;
+use diesel::{sql_query, Connection, ConnectionError, ConnectionResult, RunQueryDsl};
+use diesel_migrations::{setup_database, MigrationConnection, RunMigrationsError};
+use log::warn;
+use serde_derive::Serialize;
+use thiserror::Error as ThisError;
+use tokio::task;
+
+use crate::config::{Config, Database};
+
+pub type PgPool = Pool<ConnectionManager<PgConnection>>;
+pub type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+// longest wait between two attempts to reach the database at startup
+const MAX_CONNECT_BACKOFF: u64 = 30;
+
+// the migrations directory, compiled in the binary
+embed_migrations!("migrations");
+
+// the versions of the embedded migrations, generated by `build.rs`
+include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+// key of the advisory lock held while migrating, "lako" in ASCII
+const MIGRATION_LOCK: i64 = 0x6c61_6b6f;
+
+/// The database connection pool, put in the state of every request.
+#[derive(Clone, StateData)]
+pub struct Repo {
+    pool: PgPool,
+}
+
+/// connections of the pool, as reported by r2d2
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+impl Repo {
+    pub fn stats(&self) -> PoolStats {
+        let state = self.pool.state();
+        PoolStats {
+            max_size: self.pool.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }
+
+    /// a connection for blocking code, waits up to the connection timeout
+    pub fn connection(&self) -> Result<PgPooledConnection, DieselError> {
+        self.pool.get().map_err(pool_error)
+    }
+
+    /// Run a blocking database task with a connection of the pool. Failing
+    /// to get a connection in time is reported as a database error.
+    pub async fn run<F, R, E>(&self, f: F) -> Result<R, E>
+    where
+        F: FnOnce(PgPooledConnection) -> Result<R, E> + Send + 'static,
+        R: Send + 'static,
+        E: From<DieselError> + Send + 'static,
+    {
+        let repo = self.clone();
+        task::spawn_blocking(move || f(repo.connection()?))
+            .await
+            .unwrap_or_else(|e| panic!("Error running async database task: {:?}", e))
+    }
+}
+
+fn pool_error(e: diesel::r2d2::PoolError) -> DieselError {
+    DieselError::DatabaseError(
+        DatabaseErrorKind::UnableToSendCommand,
+        Box::new(e.to_string()),
+    )
+}
+
+// TIMESTAMP columns are in UTC, like `Utc::now().naive_utc()`, so the
+// `CURRENT_TIMESTAMP` of defaults and queries must be too
+const SET_TIME_ZONE: &str = "SET TIME ZONE 'UTC'";
 
-use crate::config::Config;
+// applies the session settings to every new connection
+#[derive(Debug)]
+struct SessionCustomizer {
+    statement_timeout: Option<u64>,
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for SessionCustomizer {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(SET_TIME_ZONE)
+            .map_err(diesel::r2d2::Error::QueryError)?;
+        if let Some(timeout) = self.statement_timeout {
+            conn.batch_execute(&format!("SET statement_timeout = {}", timeout))
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+        Ok(())
+    }
+}
+
+// Retry `f` with an exponential backoff, the database may still be starting
+// along with us.
+fn with_retry<T, E, F>(database: &Database, mut f: F) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Result<T, E>,
+{
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < database.connect_retries => {
+                let backoff = (1u64 << attempt.min(5)).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
+                warn!(
+                    "Failed to connect to the database ({}), retrying in {}s ({}/{})",
+                    e.to_string().trim(),
+                    backoff,
+                    attempt,
+                    database.connect_retries
+                );
+                thread::sleep(Duration::from_secs(backoff));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[derive(ThisError, Debug)]
+pub enum ConnectError {
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+
+    #[error("{0}")]
+    Pool(#[from] diesel::r2d2::PoolError),
+}
+
+/// Create the connection pool, once the database can be reached.
+pub fn create_repo(config: &Config) -> Result<Repo, ConnectError> {
+    let database = &config.database;
+    establish_connection(config)?;
 
-pub type Repo = gotham_middleware_diesel::Repo<PgConnection>;
+    let manager = ConnectionManager::<PgConnection>::new(database.url.as_str());
+    let pool = Pool::builder()
+        .max_size(database.max_connections)
+        .min_idle(database.min_idle)
+        .idle_timeout(database.idle_timeout.map(Duration::from_secs))
+        .connection_timeout(Duration::from_secs(database.connection_timeout))
+        .connection_customizer(Box::new(SessionCustomizer {
+            statement_timeout: database.statement_timeout,
+        }))
+        .build(manager)?;
+
+    Ok(Repo { pool })
+}
+
+/// a single connection, for the command line tools
+pub fn establish_connection(config: &Config) -> ConnectionResult<PgConnection> {
+    let conn = with_retry(&config.database, || {
+        PgConnection::establish(&config.database.url)
+    })?;
+    conn.batch_execute(SET_TIME_ZONE)
+        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    Ok(conn)
+}
+
+/// Apply the pending migrations, printing the ones that ran.
+///
+/// A session advisory lock is held meanwhile, so replicas starting at the
+/// same time wait for the first one instead of racing it.
+pub fn run_migrations(conn: &PgConnection) -> Result<(), RunMigrationsError> {
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(conn)?;
+
+    let result = embedded_migrations::run_with_output(conn, &mut io::stdout());
+
+    sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(conn)?;
+
+    result
+}
 
-pub fn create_repo(config: &Config) -> Repo {
-    let db_url = config.server.db_url.as_str();
+/// versions of the embedded migrations the database is missing
+pub fn pending_migrations(conn: &PgConnection) -> Result<Vec<&'static str>, RunMigrationsError> {
+    setup_database(conn)?;
+    let applied = conn.previously_run_migration_versions()?;
 
-    Repo::new(db_url)
+    Ok(MIGRATION_VERSIONS
+        .iter()
+        .filter(|version| !applied.contains(**version))
+        .copied()
+        .collect())
 }
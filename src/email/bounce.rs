@@ -0,0 +1,317 @@
+use mailparse::{parse_headers, parse_mail, MailHeaderMap, MailParseError, ParsedMail};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BounceKind {
+    /// a permanent delivery failure, soft bounces are left to the MTA
+    Bounce,
+    /// the recipient marked an email as spam
+    Complaint,
+}
+
+impl BounceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BounceKind::Bounce => "bounce",
+            BounceKind::Complaint => "complaint",
+        }
+    }
+}
+
+/// an address further emails must not be sent to
+#[derive(Debug)]
+pub struct BounceReport {
+    /// lowercased
+    pub address: String,
+    pub kind: BounceKind,
+    /// the diagnostic of the MTA or provider, may be empty
+    pub detail: String,
+}
+
+impl BounceReport {
+    fn new(address: &str, kind: BounceKind, detail: &str) -> Option<Self> {
+        // `rfc822; <user@example.com>` in reports, `user@example.com` elsewhere
+        let address = address
+            .rsplit(';')
+            .next()?
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_ascii_lowercase();
+        if !address.contains('@') {
+            return None;
+        }
+        Some(BounceReport {
+            address,
+            kind,
+            detail: detail.trim().to_string(),
+        })
+    }
+}
+
+// the field groups of a report, separated by empty lines
+fn for_each_field_group<F>(body: &str, mut f: F) -> Result<(), MailParseError>
+where
+    F: FnMut(&[mailparse::MailHeader]),
+{
+    let body = body.replace("\r\n", "\n");
+    let mut rest = body.trim_start().as_bytes();
+    while !rest.is_empty() {
+        let (fields, consumed) = parse_headers(rest)?;
+        if consumed == 0 {
+            break;
+        }
+        f(&fields);
+        rest = &rest[consumed..];
+        while rest.first() == Some(&b'\n') {
+            rest = &rest[1..];
+        }
+    }
+    Ok(())
+}
+
+fn collect_reports(
+    part: &ParsedMail,
+    reports: &mut Vec<BounceReport>,
+) -> Result<(), MailParseError> {
+    match part.ctype.mimetype.as_str() {
+        // RFC 3464, one group per message then one per recipient
+        "message/delivery-status" => for_each_field_group(&part.get_body()?, |fields| {
+            let failed = matches!(
+                fields.get_first_value("Action"),
+                Some(action) if action.trim().eq_ignore_ascii_case("failed")
+            );
+            let recipient = fields
+                .get_first_value("Final-Recipient")
+                .or_else(|| fields.get_first_value("Original-Recipient"));
+            if let (true, Some(recipient)) = (failed, recipient) {
+                let detail = fields
+                    .get_first_value("Diagnostic-Code")
+                    .or_else(|| fields.get_first_value("Status"))
+                    .unwrap_or_default();
+                reports.extend(BounceReport::new(&recipient, BounceKind::Bounce, &detail));
+            }
+        })?,
+        // RFC 5965 abuse reports, sent by mailbox providers' feedback loops
+        "message/feedback-report" => for_each_field_group(&part.get_body()?, |fields| {
+            if let Some(recipient) = fields.get_first_value("Original-Rcpt-To") {
+                let detail = fields.get_first_value("Feedback-Type").unwrap_or_default();
+                reports.extend(BounceReport::new(
+                    &recipient,
+                    BounceKind::Complaint,
+                    &detail,
+                ));
+            }
+        })?,
+        _ => {
+            for sub in &part.subparts {
+                collect_reports(sub, reports)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The failed recipients of a delivery status notification, or the
+/// complaining one of an abuse report, both are `multipart/report` messages.
+pub fn parse_report(raw: &[u8]) -> Result<Vec<BounceReport>, MailParseError> {
+    let mut reports = Vec::new();
+    collect_reports(&parse_mail(raw)?, &mut reports)?;
+    Ok(reports)
+}
+
+fn first_str<'a>(object: &'a Map<String, Value>, keys: &[&str]) -> Option<&'a str> {
+    keys.iter()
+        .find_map(|key| object.get(*key).and_then(Value::as_str))
+}
+
+// Amazon SES notifications, straight or through SNS
+fn ses_reports(notification: &Map<String, Value>) -> Option<Vec<BounceReport>> {
+    let kind = match first_str(notification, &["notificationType", "eventType"])? {
+        "Bounce" => BounceKind::Bounce,
+        "Complaint" => BounceKind::Complaint,
+        _ => return Some(Vec::new()),
+    };
+    let (details, recipients) = match kind {
+        BounceKind::Bounce => (notification.get("bounce")?, "bouncedRecipients"),
+        BounceKind::Complaint => (notification.get("complaint")?, "complainedRecipients"),
+    };
+    if kind == BounceKind::Bounce && details.get("bounceType")?.as_str() != Some("Permanent") {
+        return Some(Vec::new());
+    }
+    let feedback = details
+        .get("complaintFeedbackType")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+
+    Some(
+        details
+            .get(recipients)?
+            .as_array()?
+            .iter()
+            .filter_map(|recipient| {
+                let recipient = recipient.as_object()?;
+                let detail = first_str(recipient, &["diagnosticCode"]).unwrap_or(feedback);
+                BounceReport::new(first_str(recipient, &["emailAddress"])?, kind, detail)
+            })
+            .collect(),
+    )
+}
+
+// the events of Mailgun, Postmark, SendGrid... or a plain
+// `{"event": "bounce", "email": "user@example.com", "reason": "..."}`
+fn event_report(event: &Map<String, Value>) -> Option<BounceReport> {
+    let event = event
+        .get("event-data")
+        .and_then(Value::as_object)
+        .unwrap_or(event);
+
+    let name = first_str(event, &["event", "RecordType", "type"])?.to_ascii_lowercase();
+    let kind = match name.as_str() {
+        "bounce" | "bounced" | "hard_bounce" | "hardbounce" | "failed" => BounceKind::Bounce,
+        "complaint" | "complained" | "spam_complaint" | "spamcomplaint" | "spamreport" => {
+            BounceKind::Complaint
+        }
+        _ => return None,
+    };
+    let soft = matches!(first_str(event, &["severity"]), Some(severity) if severity != "permanent")
+        || matches!(first_str(event, &["Type"]), Some(t) if t != "HardBounce" && t != "SpamComplaint");
+    if kind == BounceKind::Bounce && soft {
+        return None;
+    }
+
+    let detail = first_str(event, &["reason", "description", "Description", "Details"])
+        .or_else(|| {
+            event
+                .get("delivery-status")
+                .and_then(Value::as_object)
+                .and_then(|status| first_str(status, &["description", "message"]))
+        })
+        .unwrap_or_default();
+    BounceReport::new(
+        first_str(event, &["email", "recipient", "Email"])?,
+        kind,
+        detail,
+    )
+}
+
+/// The bounces and complaints of a provider webhook payload: one event or an
+/// array of them. Other events, deliveries or soft bounces, are ignored.
+pub fn parse_webhook(payload: &Value) -> Vec<BounceReport> {
+    match payload {
+        Value::Array(events) => events.iter().flat_map(parse_webhook).collect(),
+        Value::Object(object) => {
+            // SNS wraps the SES notification in a string
+            if let Some(message) = first_str(object, &["Message"]) {
+                return serde_json::from_str(message)
+                    .map(|message: Value| parse_webhook(&message))
+                    .unwrap_or_default();
+            }
+            ses_reports(object)
+                .or_else(|| event_report(object).map(|report| vec![report]))
+                .unwrap_or_default()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const DSN: &[u8] = b"From: MAILER-DAEMON@mx.example.com\r
+To: bounces@lako.example.com\r
+Subject: Undelivered Mail Returned to Sender\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain\r
+\r
+The mail could not be delivered.\r
+--b\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mx.example.com\r
+\r
+Final-Recipient: rfc822; <Ann@Example.com>\r
+Action: failed\r
+Status: 5.1.1\r
+Diagnostic-Code: smtp; 550 5.1.1 no such user\r
+\r
+Final-Recipient: rfc822; bob@example.com\r
+Action: delayed\r
+Status: 4.4.1\r
+--b--\r
+";
+
+    const ARF: &[u8] = b"From: fbl@isp.example.com\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=feedback-report; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: message/feedback-report\r
+\r
+Feedback-Type: abuse\r
+User-Agent: FBL/1.0\r
+Version: 1\r
+Original-Rcpt-To: carol@example.com\r
+--b--\r
+";
+
+    #[test]
+    fn reports_the_failed_recipients_of_a_dsn() {
+        let reports = parse_report(DSN).unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, "ann@example.com");
+        assert_eq!(reports[0].kind, BounceKind::Bounce);
+        assert_eq!(reports[0].detail, "smtp; 550 5.1.1 no such user");
+    }
+
+    #[test]
+    fn reports_the_complaint_of_an_abuse_report() {
+        let reports = parse_report(ARF).unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, "carol@example.com");
+        assert_eq!(reports[0].kind, BounceKind::Complaint);
+        assert_eq!(reports[0].detail, "abuse");
+    }
+
+    #[test]
+    fn parses_ses_notifications_through_sns() {
+        let notification = json!({
+            "notificationType": "Bounce",
+            "bounce": {
+                "bounceType": "Permanent",
+                "bouncedRecipients": [
+                    {"emailAddress": "ann@example.com", "diagnosticCode": "550 unknown"}
+                ]
+            }
+        });
+        let payload = json!({"Type": "Notification", "Message": notification.to_string()});
+        let reports = parse_webhook(&payload);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, "ann@example.com");
+        assert_eq!(reports[0].detail, "550 unknown");
+    }
+
+    #[test]
+    fn ignores_soft_bounces_and_other_events() {
+        let payload = json!([
+            {"event-data": {"event": "failed", "severity": "temporary", "recipient": "a@example.com"}},
+            {"event": "delivered", "email": "b@example.com"},
+            {"notificationType": "Bounce", "bounce": {"bounceType": "Transient", "bouncedRecipients": []}},
+            {"event": "bounce", "email": "c@example.com", "reason": "mailbox full"},
+        ]);
+        let reports = parse_webhook(&payload);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, "c@example.com");
+        assert_eq!(reports[0].detail, "mailbox full");
+    }
+}
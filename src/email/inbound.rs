@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+
+use chrono::NaiveDateTime;
+use mailparse::{
+    addrparse, dateparse, parse_mail, DispositionType, MailAddr, MailHeaderMap, MailParseError,
+    ParsedMail,
+};
+
+/// a file attached to a received email
+#[derive(Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    /// of the content, what the `size` column holds
+    pub size: i32,
+    pub content: Vec<u8>,
+}
+
+// size of the `inbound_emails.message_id` column
+const MAX_MESSAGE_ID_LEN: usize = 255;
+
+/// The parts of a received RFC 5322 message Lako keeps.
+#[derive(Debug, Default)]
+pub struct InboundMessage {
+    /// without the angle brackets, cut to `MAX_MESSAGE_ID_LEN` characters
+    pub message_id: Option<String>,
+    /// address of the first `From` mailbox, lowercased
+    pub sender: String,
+    /// the `To` and `Cc` addresses
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body_text: String,
+    pub body_html: Option<String>,
+    /// from the `Date` header
+    pub sent_at: Option<NaiveDateTime>,
+    pub attachments: Vec<Attachment>,
+}
+
+// the addresses of a header, groups flattened
+fn addresses(header: &str) -> Vec<String> {
+    let list = match addrparse(header) {
+        Ok(list) => list.into_inner(),
+        Err(_) => return Vec::new(),
+    };
+
+    list.into_iter()
+        .flat_map(|addr| match addr {
+            MailAddr::Single(info) => vec![info.addr],
+            MailAddr::Group(group) => group.addrs.into_iter().map(|info| info.addr).collect(),
+        })
+        .map(|addr| addr.to_ascii_lowercase())
+        .collect()
+}
+
+// walk the MIME tree, the first text/plain and text/html parts are the body
+fn collect_parts(part: &ParsedMail, message: &mut InboundMessage) -> Result<(), MailParseError> {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        for sub in &part.subparts {
+            collect_parts(sub, message)?;
+        }
+        return Ok(());
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned();
+    let is_attachment = disposition.disposition == DispositionType::Attachment
+        || filename.is_some()
+        || !part.ctype.mimetype.starts_with("text/");
+
+    if is_attachment {
+        let content = part.get_body_raw()?;
+        let size = i32::try_from(content.len())
+            .map_err(|_| MailParseError::Generic("an attachment is too large"))?;
+        message.attachments.push(Attachment {
+            filename: filename.unwrap_or_else(|| "attachment".to_string()),
+            content_type: part.ctype.mimetype.clone(),
+            size,
+            content,
+        });
+    } else if part.ctype.mimetype == "text/html" {
+        if message.body_html.is_none() {
+            message.body_html = Some(part.get_body()?);
+        }
+    } else if message.body_text.is_empty() {
+        message.body_text = part.get_body()?;
+    }
+    Ok(())
+}
+
+/// Parse a raw message, fails when it has no `From` address.
+pub fn parse_message(raw: &[u8]) -> Result<InboundMessage, MailParseError> {
+    let mail = parse_mail(raw)?;
+    let headers = &mail.headers;
+
+    let sender = headers
+        .get_first_value("From")
+        .map(|from| addresses(&from))
+        .and_then(|from| from.into_iter().next())
+        .ok_or(MailParseError::Generic("the message has no From address"))?;
+
+    let mut recipients = Vec::new();
+    for key in &["To", "Cc"] {
+        for value in headers.get_all_values(key) {
+            recipients.extend(addresses(&value));
+        }
+    }
+
+    let mut message = InboundMessage {
+        message_id: headers
+            .get_first_value("Message-ID")
+            .map(|id| {
+                id.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .chars()
+                    .take(MAX_MESSAGE_ID_LEN)
+                    .collect::<String>()
+            })
+            .filter(|id| !id.is_empty()),
+        sender,
+        recipients,
+        subject: headers.get_first_value("Subject").unwrap_or_default(),
+        sent_at: headers
+            .get_first_value("Date")
+            .and_then(|date| dateparse(&date).ok())
+            // any year parses, those chrono can't represent are no date
+            .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0)),
+        ..InboundMessage::default()
+    };
+    collect_parts(&mail, &mut message)?;
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(date: &str) -> String {
+        format!(
+            "From: Ann <Ann@Example.com>\r\nTo: me@lako.io\r\nMessage-ID: <1@example.com>\r\nDate: {}\r\nSubject: Hi\r\n\r\nHello\r\n",
+            date
+        )
+    }
+
+    #[test]
+    fn parses_the_headers_and_body() {
+        let parsed = parse_message(message("Tue, 1 Jun 2021 10:00:00 +0000").as_bytes()).unwrap();
+
+        assert_eq!(parsed.sender, "ann@example.com");
+        assert_eq!(parsed.recipients, vec!["me@lako.io".to_string()]);
+        assert_eq!(parsed.message_id.as_deref(), Some("1@example.com"));
+        assert_eq!(parsed.subject, "Hi");
+        assert_eq!(parsed.body_text.trim(), "Hello");
+        assert_eq!(
+            parsed.sent_at.map(|date| date.to_string()).as_deref(),
+            Some("2021-06-01 10:00:00")
+        );
+    }
+
+    #[test]
+    fn cuts_a_long_message_id() {
+        let raw = message("Tue, 1 Jun 2021 10:00:00 +0000")
+            .replace("1@example.com", &format!("{}@example.com", "é".repeat(300)));
+        let parsed = parse_message(raw.as_bytes()).unwrap();
+
+        assert_eq!(
+            parsed.message_id.map(|id| id.chars().count()),
+            Some(MAX_MESSAGE_ID_LEN)
+        );
+    }
+
+    #[test]
+    fn a_date_out_of_range_is_no_date() {
+        let parsed =
+            parse_message(message("Tue, 1 Jun 99999999 10:00:00 +0000").as_bytes()).unwrap();
+
+        assert_eq!(parsed.sent_at, None);
+    }
+
+    #[test]
+    fn keeps_the_attachments_with_their_size() {
+        let raw = "From: ann@example.com\r\n\
+            Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+            --b\r\nContent-Type: text/plain\r\n\r\nSee attached\r\n\
+            --b\r\nContent-Type: application/pdf\r\n\
+            Content-Disposition: attachment; filename=quote.pdf\r\n\
+            Content-Transfer-Encoding: base64\r\n\r\nJVBERi0xLjQ=\r\n\
+            --b--\r\n";
+        let parsed = parse_message(raw.as_bytes()).unwrap();
+
+        assert_eq!(parsed.body_text.trim(), "See attached");
+        assert_eq!(parsed.attachments.len(), 1);
+        assert_eq!(parsed.attachments[0].filename, "quote.pdf");
+        assert_eq!(parsed.attachments[0].content, b"%PDF-1.4");
+        assert_eq!(parsed.attachments[0].size, 8);
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use diesel::PgConnection;
+use failure::Fail;
+use lettre::SendableEmail;
+use log::info;
+use serde_json::json;
+use thiserror::Error as ThisError;
+
+use lettre_email::Email;
+
+use crate::config::config;
+use crate::metrics;
+use crate::models::outbox::enqueue_email;
+use crate::models::suppression::is_suppressed;
+
+pub mod bounce;
+pub mod inbound;
+pub mod templates;
+pub mod transport;
+
+use self::templates::{templates, EmailTemplateError, RenderedEmail};
+use self::transport::transport;
+
+#[derive(ThisError, Debug)]
+pub enum EmailError {
+    #[error("{0}")]
+    Template(#[from] EmailTemplateError),
+
+    #[error("{0}")]
+    Database(#[from] DieselError),
+}
+
+fn get_email_sender() -> (String, String) {
+    let email = &config().email;
+    (email.from_address.clone(), email.from_name.clone())
+}
+
+// queue the rendered email, in the transaction of `conn`, unless the
+// address bounced or complained
+fn enqueue(conn: &PgConnection, recipient: &str, email: RenderedEmail) -> Result<(), EmailError> {
+    if is_suppressed(conn, recipient)? {
+        info!(
+            "Not sending `{}` to suppressed `{}`",
+            email.subject, recipient
+        );
+        return Ok(());
+    }
+    enqueue_email(
+        conn,
+        recipient,
+        &email.subject,
+        &email.text,
+        Some(&email.html),
+    )?;
+    Ok(())
+}
+
+/// queue the email address confirmation, in the transaction of `conn`
+pub fn send_user_confirm_email(
+    conn: &PgConnection,
+    email: &str,
+    user_name: &str,
+    language: &str,
+    token: &str,
+) -> Result<(), EmailError> {
+    let templates = templates();
+    let rendered = templates.render(
+        "confirm_email",
+        language,
+        json!({
+            "user_name": user_name,
+            "confirm_url": format!("{}/confirm/{}", templates.base_url(), token),
+        }),
+    )?;
+
+    enqueue(conn, email, rendered)
+}
+
+pub fn send_account_locked_email(
+    conn: &PgConnection,
+    email: &str,
+    user_name: &str,
+    language: &str,
+    locked_until: &NaiveDateTime,
+) -> Result<(), EmailError> {
+    let rendered = templates().render(
+        "account_locked",
+        language,
+        json!({
+            "user_name": user_name,
+            "locked_until": locked_until.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }),
+    )?;
+
+    enqueue(conn, email, rendered)
+}
+
+/// queue the download link of a data export, in the transaction of `conn`
+pub fn send_account_export_email(
+    conn: &PgConnection,
+    email: &str,
+    user_name: &str,
+    language: &str,
+    token: &str,
+    expires_at: &NaiveDateTime,
+) -> Result<(), EmailError> {
+    let templates = templates();
+    let rendered = templates.render(
+        "account_export",
+        language,
+        json!({
+            "user_name": user_name,
+            // a download served by the API itself, not a page of the web application
+            "download_url": format!(
+                "{}/api/v1/account/exports/{}",
+                templates.base_url(),
+                token
+            ),
+            "expires_at": expires_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }),
+    )?;
+
+    enqueue(conn, email, rendered)
+}
+
+/// queue the notice of a scheduled account deletion, in the transaction of
+/// `conn`
+pub fn send_account_deletion_email(
+    conn: &PgConnection,
+    email: &str,
+    user_name: &str,
+    language: &str,
+    delete_after: &NaiveDateTime,
+) -> Result<(), EmailError> {
+    let rendered = templates().render(
+        "account_deletion",
+        language,
+        json!({
+            "user_name": user_name,
+            "delete_after": delete_after.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }),
+    )?;
+
+    enqueue(conn, email, rendered)
+}
+
+/// check the mail settings, used by `lako send-test-email`
+pub fn try_send_test_email(email: &str, language: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let rendered = templates().render("test_email", language, json!({}))?;
+
+    send_email(
+        email,
+        &rendered.subject,
+        &rendered.text,
+        Some(&rendered.html),
+    )
+}
+
+/// Check the mail settings can work: the SMTP server name resolves, or
+/// emails can be written to the output directory.
+pub fn check_mail_transport() -> Result<(), String> {
+    transport().check()
+}
+
+fn build_email(
+    recipient: &str,
+    subject: &str,
+    body: &str,
+    html_body: Option<&str>,
+) -> Result<SendableEmail, Box<dyn std::error::Error>> {
+    let builder = Email::builder()
+        .to(recipient)
+        .from(get_email_sender())
+        .subject(subject);
+    let builder = match html_body {
+        Some(html_body) => builder.alternative(html_body, body),
+        None => builder.body(body),
+    };
+    let email = builder.build().map_err(|e| e.compat())?;
+
+    Ok(email.into())
+}
+
+/// deliver an email right away, the outbox worker uses it for queued ones
+pub fn send_email(
+    recipient: &str,
+    subject: &str,
+    body: &str,
+    html_body: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = deliver_email(recipient, subject, body, html_body);
+    match result {
+        Ok(_) => metrics::email_sent(),
+        Err(_) => metrics::email_failed(),
+    }
+    result
+}
+
+fn deliver_email(
+    recipient: &str,
+    subject: &str,
+    body: &str,
+    html_body: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let email = build_email(recipient, subject, body, html_body)?;
+    transport().send(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::transport::{set_transport, MemoryTransport};
+    use super::*;
+    use crate::config::{init_config, Config};
+
+    #[test]
+    fn send_email_goes_through_the_transport_in_use() {
+        init_config(Config::default());
+        let outbox = MemoryTransport::new();
+        assert!(set_transport(Box::new(outbox.clone())));
+
+        send_email("ann@example.com", "Hi Ann", "Hello", Some("<p>Hello</p>")).unwrap();
+
+        let emails = outbox.take();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, vec!["ann@example.com".to_string()]);
+        assert_eq!(emails[0].from.as_deref(), Some("test@localhost"));
+        assert_eq!(emails[0].subject(), Some("Hi Ann"));
+    }
+}
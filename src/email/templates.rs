@@ -0,0 +1,423 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+
+use handlebars::{no_escape, Handlebars, RenderError, TemplateError};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Map, Value};
+use thiserror::Error as ThisError;
+
+use crate::config::Config;
+
+macro_rules! default_templates {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_str!(concat!("../../templates/email/", $name, ".hbs")))),*]
+    };
+}
+
+// the templates built in the binary, a file of `email.templates_dir` with
+// the same name replaces one
+const DEFAULT_TEMPLATES: &[(&str, &str)] = default_templates![
+    "layout.html",
+    "layout.txt",
+    "confirm_email.en.subject",
+    "confirm_email.en.txt",
+    "confirm_email.en.html",
+    "confirm_email.id.subject",
+    "confirm_email.id.txt",
+    "confirm_email.id.html",
+    "account_locked.en.subject",
+    "account_locked.en.txt",
+    "account_locked.en.html",
+    "account_locked.id.subject",
+    "account_locked.id.txt",
+    "account_locked.id.html",
+    "account_export.en.subject",
+    "account_export.en.txt",
+    "account_export.en.html",
+    "account_export.id.subject",
+    "account_export.id.txt",
+    "account_export.id.html",
+    "account_deletion.en.subject",
+    "account_deletion.en.txt",
+    "account_deletion.en.html",
+    "account_deletion.id.subject",
+    "account_deletion.id.txt",
+    "account_deletion.id.html",
+    "test_email.en.subject",
+    "test_email.en.txt",
+    "test_email.en.html",
+    "test_email.id.subject",
+    "test_email.id.txt",
+    "test_email.id.html",
+];
+
+/// the emails sent by Lako, each one has a template per language
+pub const EMAILS: &[&str] = &[
+    "confirm_email",
+    "account_locked",
+    "account_export",
+    "account_deletion",
+    "test_email",
+];
+
+// every email has templates in this language
+const FALLBACK_LANGUAGE: &str = "en";
+
+#[derive(ThisError, Debug)]
+pub enum EmailTemplateError {
+    #[error("Failed to read `{path}`: {source}")]
+    Io { path: String, source: io::Error },
+
+    #[error("Invalid template `{name}`: {source}")]
+    Syntax {
+        name: String,
+        source: Box<TemplateError>,
+    },
+
+    #[error("Failed to render `{name}`: {source}")]
+    Render {
+        name: String,
+        source: Box<RenderError>,
+    },
+
+    #[error("Email `{0}` has no `{1}` template")]
+    Missing(String, String),
+}
+
+/// an email ready to be sent, `text` is the plain text alternative of `html`
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// The email templates, by name: `layout.html`, `confirm_email.en.txt`...
+///
+/// Every email is made of a `subject`, a plain text `txt` and an `html`
+/// template for each language. Layouts and other partials are included with
+/// `{{#> layout.html}}...{{/layout.html}}`, only HTML templates are escaped.
+pub struct Templates {
+    html: Handlebars<'static>,
+    text: Handlebars<'static>,
+    default_language: String,
+    app_name: String,
+    base_url: String,
+}
+
+impl Templates {
+    /// the built-in templates, replaced or extended by the `*.hbs` files of
+    /// `email.templates_dir`
+    pub fn load(config: &Config) -> Result<Self, EmailTemplateError> {
+        let mut text = Handlebars::new();
+        text.register_escape_fn(no_escape);
+        let mut templates = Templates {
+            html: Handlebars::new(),
+            text,
+            default_language: config.email.default_language.clone(),
+            app_name: config.email.from_name.clone(),
+            base_url: config.server.public_url.trim_end_matches('/').to_string(),
+        };
+
+        for (name, source) in DEFAULT_TEMPLATES {
+            templates.register(name, source)?;
+        }
+
+        if let Some(dir) = &config.email.templates_dir {
+            let io_error = |source| EmailTemplateError::Io {
+                path: dir.clone(),
+                source,
+            };
+            for entry in fs::read_dir(dir).map_err(io_error)? {
+                let path = entry.map_err(io_error)?.path();
+                let name = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(file_name) if file_name.ends_with(".hbs") => {
+                        file_name.trim_end_matches(".hbs").to_string()
+                    }
+                    _ => continue,
+                };
+                let source =
+                    fs::read_to_string(&path).map_err(|source| EmailTemplateError::Io {
+                        path: path.display().to_string(),
+                        source,
+                    })?;
+                templates.register(&name, &source)?;
+            }
+        }
+
+        templates.check()?;
+        Ok(templates)
+    }
+
+    fn register(&mut self, name: &str, source: &str) -> Result<(), EmailTemplateError> {
+        let registry = if name.ends_with(".html") {
+            &mut self.html
+        } else {
+            &mut self.text
+        };
+        registry
+            .register_template_string(name, source)
+            .map_err(|source| EmailTemplateError::Syntax {
+                name: name.to_string(),
+                source: Box::new(source),
+            })
+    }
+
+    // every language has all the templates, and they can be rendered
+    fn check(&self) -> Result<(), EmailTemplateError> {
+        for language in self.languages() {
+            for email in EMAILS {
+                for kind in &["subject", "txt", "html"] {
+                    let name = format!("{}.{}.{}", email, language, kind);
+                    if !self.html.has_template(&name) && !self.text.has_template(&name) {
+                        return Err(EmailTemplateError::Missing(email.to_string(), name));
+                    }
+                }
+                self.render(email, &language, json!({}))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the public URL of the web application, without a trailing slash
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// the languages emails can be sent in
+    pub fn languages(&self) -> BTreeSet<String> {
+        self.text
+            .get_templates()
+            .keys()
+            .filter_map(|name| {
+                let mut parts = name.split('.');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(_), Some(language), Some("subject"), None) => Some(language.to_string()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    // the user language when there are templates in it, otherwise the
+    // configured default one
+    fn pick_language<'a>(&'a self, email: &str, language: &'a str) -> &'a str {
+        let has = |language: &str| {
+            self.text
+                .has_template(&format!("{}.{}.subject", email, language))
+        };
+        if has(language) {
+            language
+        } else if has(&self.default_language) {
+            &self.default_language
+        } else {
+            FALLBACK_LANGUAGE
+        }
+    }
+
+    /// Render `email` in `language`. `data` is an object, `app_name`,
+    /// `base_url` and `language` are available to all the templates.
+    pub fn render(
+        &self,
+        email: &str,
+        language: &str,
+        data: Value,
+    ) -> Result<RenderedEmail, EmailTemplateError> {
+        let language = self.pick_language(email, language);
+
+        let mut context = match data {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        };
+        context.insert("app_name".into(), self.app_name.clone().into());
+        context.insert("base_url".into(), self.base_url.clone().into());
+        context.insert("language".into(), language.into());
+        let context = Value::Object(context);
+
+        let render = |registry: &Handlebars, kind: &str| {
+            let name = format!("{}.{}.{}", email, language, kind);
+            registry
+                .render(&name, &context)
+                .map_err(|source| EmailTemplateError::Render {
+                    name,
+                    source: Box::new(source),
+                })
+        };
+
+        Ok(RenderedEmail {
+            subject: render(&self.text, "subject")?.trim().to_string(),
+            text: render(&self.text, "txt")?.trim().to_string(),
+            html: render(&self.html, "html")?,
+        })
+    }
+}
+
+static TEMPLATES: OnceCell<Templates> = OnceCell::new();
+
+/// load the email templates, available through `templates()` afterwards
+pub fn init_templates(config: &Config) -> Result<(), EmailTemplateError> {
+    let templates = Templates::load(config)?;
+    let _ = TEMPLATES.set(templates);
+    Ok(())
+}
+
+pub fn templates() -> &'static Templates {
+    TEMPLATES
+        .get()
+        .expect("email templates are not loaded, call `init_templates` first")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn load() -> Templates {
+        Templates::load(&Config::default()).unwrap()
+    }
+
+    // a `email.templates_dir` with the given files, removed by the caller
+    fn templates_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lako-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        dir
+    }
+
+    fn load_from(dir: &PathBuf) -> Result<Templates, EmailTemplateError> {
+        let mut config = Config::default();
+        config.email.templates_dir = Some(dir.display().to_string());
+        Templates::load(&config)
+    }
+
+    #[test]
+    fn every_shipped_email_renders_in_every_language() {
+        let templates = load();
+        let data = json!({
+            "user_name": "<Ann>",
+            "confirm_url": "https://lako.io/confirm/t0k3n",
+            "locked_until": "2021-06-01 10:15:00",
+            "download_url": "https://lako.io/api/v1/account/exports/t0k3n",
+            "expires_at": "2021-06-08 10:00:00",
+            "delete_after": "2021-06-15 10:00:00",
+        });
+        let expected = [
+            ("confirm_email", "https://lako.io/confirm/t0k3n"),
+            ("account_locked", "2021-06-01 10:15:00"),
+            (
+                "account_export",
+                "https://lako.io/api/v1/account/exports/t0k3n",
+            ),
+            ("account_deletion", "2021-06-15 10:00:00"),
+            ("test_email", "Lako"),
+        ];
+        assert_eq!(expected.len(), EMAILS.len());
+        assert_eq!(
+            templates.languages().into_iter().collect::<Vec<_>>(),
+            vec!["en", "id"]
+        );
+
+        for language in templates.languages() {
+            for (email, value) in expected.iter() {
+                let rendered = templates.render(email, &language, data.clone()).unwrap();
+                let context = format!("{} in {}", email, language);
+
+                assert!(!rendered.subject.is_empty(), "{}", context);
+                assert!(!rendered.subject.contains('\n'), "{}", context);
+                assert!(rendered.text.contains(value), "{}", context);
+                assert!(rendered.html.contains(value), "{}", context);
+                assert!(
+                    rendered
+                        .html
+                        .contains(&format!("<html lang=\"{}\">", language)),
+                    "{}",
+                    context
+                );
+                if *email != "test_email" {
+                    assert!(rendered.text.contains("<Ann>"), "{}", context);
+                    assert!(rendered.html.contains("&lt;Ann&gt;"), "{}", context);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_languages_fall_back_to_the_default_one() {
+        let templates = load();
+        let rendered = templates.render("confirm_email", "fr", json!({})).unwrap();
+        assert_eq!(
+            rendered.subject,
+            templates
+                .render("confirm_email", "en", json!({}))
+                .unwrap()
+                .subject
+        );
+
+        let mut config = Config::default();
+        config.email.default_language = "id".to_string();
+        let templates = Templates::load(&config).unwrap();
+        let rendered = templates.render("confirm_email", "fr", json!({})).unwrap();
+        assert_eq!(rendered.subject, "Mohon konfirmasi alamat email Anda");
+        assert!(rendered.html.contains("<html lang=\"id\">"));
+
+        // a default language without templates ends up in English
+        let mut config = Config::default();
+        config.email.default_language = "de".to_string();
+        let templates = Templates::load(&config).unwrap();
+        let rendered = templates.render("confirm_email", "fr", json!({})).unwrap();
+        assert!(rendered.html.contains("<html lang=\"en\">"));
+    }
+
+    #[test]
+    fn templates_dir_replaces_the_built_in_templates() {
+        let dir = templates_dir(
+            "replace",
+            &[
+                ("test_email.en.subject.hbs", "Hello from {{app_name}}"),
+                ("README.md", "not a template"),
+            ],
+        );
+        let templates = load_from(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let rendered = templates
+            .unwrap()
+            .render("test_email", "en", json!({}))
+            .unwrap();
+        assert_eq!(rendered.subject, "Hello from Lako");
+    }
+
+    #[test]
+    fn a_language_missing_a_template_is_refused() {
+        let dir = templates_dir(
+            "missing",
+            &[
+                ("confirm_email.fr.subject.hbs", "Confirmez votre adresse"),
+                ("confirm_email.fr.txt.hbs", "{{confirm_url}}"),
+            ],
+        );
+        let templates = load_from(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match templates {
+            Err(EmailTemplateError::Missing(email, name)) => {
+                assert_eq!(email, "confirm_email");
+                assert_eq!(name, "confirm_email.fr.html");
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the `fr` templates are incomplete"),
+        }
+    }
+
+    #[test]
+    fn rendering_an_unknown_email_fails() {
+        let error = load().render("newsletter", "en", json!({})).err().unwrap();
+        assert!(
+            matches!(error, EmailTemplateError::Render { name, .. } if name == "newsletter.en.subject")
+        );
+    }
+}
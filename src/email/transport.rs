@@ -0,0 +1,355 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lettre::file::FileTransport as LettreFileTransport;
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::smtp::client::net::{ClientTlsParameters, DEFAULT_TLS_PROTOCOLS};
+use lettre::smtp::{ClientSecurity, SmtpClient};
+use lettre::{SendableEmail, Transport};
+use native_tls::TlsConnector;
+use once_cell::sync::OnceCell;
+
+use crate::config::{self, SmtpConfig};
+
+pub type TransportResult = Result<(), Box<dyn Error>>;
+
+/// Delivers the emails, picked with `email.transport`.
+pub trait MailTransport: Send + Sync {
+    fn send(&self, email: SendableEmail) -> TransportResult;
+
+    /// whether emails can be delivered, used by the readiness probe
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// a directory emails can be written to
+fn check_writable_dir(dir: &Path) -> Result<(), String> {
+    match fs::metadata(dir) {
+        Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => Ok(()),
+        Ok(_) => Err(format!("`{}` is not a writable directory", dir.display())),
+        Err(e) => Err(format!("`{}`: {}", dir.display(), e)),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SmtpSecurity {
+    /// TLS from the start of the connection, usually on port 465
+    Tls,
+    /// upgrade the connection with STARTTLS, usually on port 587
+    StartTls,
+    /// no encryption, for local servers like MailHog on port 1025
+    None,
+}
+
+/// Send through an SMTP server, authenticating when a username is set.
+pub struct SmtpTransport {
+    config: SmtpConfig,
+    security: SmtpSecurity,
+}
+
+impl SmtpTransport {
+    pub fn new(config: &SmtpConfig) -> Self {
+        let security = match config.security.as_str() {
+            "starttls" => SmtpSecurity::StartTls,
+            "none" => SmtpSecurity::None,
+            _ => SmtpSecurity::Tls,
+        };
+        SmtpTransport {
+            config: config.clone(),
+            security,
+        }
+    }
+
+    fn tls_parameters(&self) -> Result<ClientTlsParameters, Box<dyn Error>> {
+        let mut tls_builder = TlsConnector::builder();
+        tls_builder.min_protocol_version(Some(DEFAULT_TLS_PROTOCOLS[0]));
+
+        Ok(ClientTlsParameters::new(
+            self.config.server.clone(),
+            tls_builder.build()?,
+        ))
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&self, email: SendableEmail) -> TransportResult {
+        let security = match self.security {
+            SmtpSecurity::Tls => ClientSecurity::Wrapper(self.tls_parameters()?),
+            SmtpSecurity::StartTls => ClientSecurity::Required(self.tls_parameters()?),
+            SmtpSecurity::None => ClientSecurity::None,
+        };
+        let mut client =
+            SmtpClient::new((self.config.server.as_str(), self.config.port), security)?
+                .smtp_utf8(true);
+        if !self.config.username.is_empty() {
+            client = client
+                .credentials(Credentials::new(
+                    self.config.username.clone(),
+                    self.config.password.clone(),
+                ))
+                .authentication_mechanism(Mechanism::Plain);
+        }
+
+        client.transport().send(email)?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let server = &self.config.server;
+        match (server.as_str(), self.config.port)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next())
+        {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(format!("SMTP server `{}` has no address", server)),
+            Err(e) => Err(format!("SMTP server `{}`: {}", server, e)),
+        }
+    }
+}
+
+/// Write every email as a JSON file, with its envelope, in a directory.
+pub struct FileTransport {
+    dir: PathBuf,
+}
+
+impl FileTransport {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        FileTransport {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl MailTransport for FileTransport {
+    fn send(&self, email: SendableEmail) -> TransportResult {
+        LettreFileTransport::new(&self.dir).send(email)?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        check_writable_dir(&self.dir)
+    }
+}
+
+/// Deliver to a maildir, mail clients like mutt can read it directly. The
+/// `tmp`, `new` and `cur` subdirectories are created when missing.
+pub struct MaildirTransport {
+    dir: PathBuf,
+}
+
+impl MaildirTransport {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        MaildirTransport {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl MailTransport for MaildirTransport {
+    fn send(&self, email: SendableEmail) -> TransportResult {
+        for sub in &["tmp", "new", "cur"] {
+            fs::create_dir_all(self.dir.join(sub))?;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let name = format!(
+            "{}.{}_{}.lako",
+            now.as_secs(),
+            now.subsec_micros(),
+            email.message_id()
+        );
+        // written in `tmp` first so readers never see a partial email
+        let tmp = self.dir.join("tmp").join(&name);
+        fs::write(&tmp, email.message_to_string()?)?;
+        fs::rename(&tmp, self.dir.join("new").join(&name))?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        check_writable_dir(&self.dir)
+    }
+}
+
+/// Print the emails on the standard output, handy in development.
+pub struct StdoutTransport;
+
+impl MailTransport for StdoutTransport {
+    fn send(&self, email: SendableEmail) -> TransportResult {
+        let message = email.message_to_string()?;
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        writeln!(out, "{}\n{}", message, "-".repeat(72))?;
+        Ok(())
+    }
+}
+
+/// an email kept by `MemoryTransport`
+#[derive(Debug, Clone)]
+pub struct CapturedEmail {
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub message_id: String,
+    /// the whole message, headers included
+    pub message: String,
+}
+
+impl CapturedEmail {
+    /// the value of the `name` header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.message
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| {
+                let (key, value) = line.split_at(line.find(':')?);
+                if key.eq_ignore_ascii_case(name) {
+                    Some(value[1..].trim())
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.header("Subject")
+    }
+}
+
+/// Keep the emails in memory so tests can assert on them, eg.
+///
+/// ```no_run
+/// use lako::email::transport::{set_transport, MemoryTransport};
+///
+/// let outbox = MemoryTransport::new();
+/// set_transport(Box::new(outbox.clone()));
+/// // ... register a user and run the outbox worker
+/// assert_eq!(outbox.emails().len(), 1);
+/// ```
+///
+/// Clones share the same emails. Nothing is ever dropped, so it's not meant
+/// for a server running for long.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    emails: Arc<Mutex<Vec<CapturedEmail>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+
+    /// the emails sent so far, oldest first
+    pub fn emails(&self) -> Vec<CapturedEmail> {
+        self.emails.lock().unwrap().clone()
+    }
+
+    /// the emails sent so far, forgetting them
+    pub fn take(&self) -> Vec<CapturedEmail> {
+        self.emails.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl MailTransport for MemoryTransport {
+    fn send(&self, email: SendableEmail) -> TransportResult {
+        let from = email.envelope().from().map(|from| from.to_string());
+        let to = email
+            .envelope()
+            .to()
+            .iter()
+            .map(|to| to.to_string())
+            .collect();
+        let message_id = email.message_id().to_string();
+        let message = email.message_to_string()?;
+
+        self.emails.lock().unwrap().push(CapturedEmail {
+            from,
+            to,
+            message_id,
+            message,
+        });
+        Ok(())
+    }
+}
+
+/// the transport configured with `email.transport`
+pub fn transport_from_config(config: &config::Email) -> Box<dyn MailTransport> {
+    match (config.transport_name(), &config.smtp) {
+        ("smtp", Some(smtp)) => Box::new(SmtpTransport::new(smtp)),
+        ("maildir", _) => Box::new(MaildirTransport::new(&config.output_dir)),
+        ("stdout", _) => Box::new(StdoutTransport),
+        ("memory", _) => Box::new(MemoryTransport::new()),
+        _ => Box::new(FileTransport::new(&config.output_dir)),
+    }
+}
+
+static TRANSPORT: OnceCell<Box<dyn MailTransport>> = OnceCell::new();
+
+/// Use `transport` for all the emails instead of the configured one. Returns
+/// `false` when a transport is already in use.
+pub fn set_transport(transport: Box<dyn MailTransport>) -> bool {
+    TRANSPORT.set(transport).is_ok()
+}
+
+/// the transport emails are delivered with
+pub fn transport() -> &'static dyn MailTransport {
+    TRANSPORT
+        .get_or_init(|| transport_from_config(&config::config().email))
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use lettre_email::Email;
+
+    use super::*;
+
+    fn email(to: &str, subject: &str) -> SendableEmail {
+        Email::builder()
+            .to(to)
+            .from(("lako@example.com", "Lako"))
+            .subject(subject)
+            .alternative("<p>Hello</p>", "Hello")
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn memory_transport_captures_the_emails() {
+        let outbox = MemoryTransport::new();
+        // clones share the captured emails
+        outbox
+            .clone()
+            .send(email("ann@example.com", "Welcome to Lako"))
+            .unwrap();
+
+        let emails = outbox.emails();
+        assert_eq!(emails.len(), 1);
+        let captured = &emails[0];
+        assert_eq!(captured.from.as_deref(), Some("lako@example.com"));
+        assert_eq!(captured.to, vec!["ann@example.com".to_string()]);
+        assert_eq!(captured.subject(), Some("Welcome to Lako"));
+        assert_eq!(captured.header("to"), Some("<ann@example.com>"));
+        assert!(captured.message.contains("Hello"));
+    }
+
+    #[test]
+    fn take_forgets_the_emails() {
+        let outbox = MemoryTransport::new();
+        outbox.send(email("ann@example.com", "One")).unwrap();
+        outbox.send(email("bob@example.com", "Two")).unwrap();
+
+        let subjects = outbox
+            .take()
+            .iter()
+            .map(|email| email.subject().unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(subjects, vec!["One", "Two"]);
+        assert!(outbox.emails().is_empty());
+    }
+}
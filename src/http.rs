@@ -2,22 +2,61 @@ use gotham::pipeline::new_pipeline;
 use gotham::pipeline::set::{finalize_pipeline_set, new_pipeline_set};
 use gotham::router::{builder::*, Router};
 use gotham::state::State;
-use gotham_middleware_diesel::DieselMiddleware;
-use gotham_middleware_jwt::JWTMiddleware;
 
-use crate::auth::{get_jwt_secret_key, Claims};
+use crate::config::config;
 use crate::db::Repo;
+use crate::middleware::access_log::AccessLogMiddleware;
+use crate::middleware::auth::AuthMiddleware;
+use crate::middleware::metrics::MetricsMiddleware;
+use crate::middleware::rate_limit::{RateLimitMiddleware, RateLimiter};
+use crate::middleware::repo::RepoMiddleware;
+use crate::routes::account::{
+    cancel_deletion_handler, download_export_handler, get_deletion_handler, list_exports_handler,
+    request_export_handler, schedule_deletion_handler,
+};
+use crate::routes::admin::{
+    clear_suppression_handler, list_lockouts_handler, list_outbox_handler,
+    list_suppressions_handler, retry_outbox_handler, unlock_lockout_handler,
+};
+use crate::routes::api_keys::{
+    create_api_key_handler, list_api_keys_handler, revoke_api_key_handler,
+};
 use crate::routes::auth::{
-    confirm_user_email, get_user, login_user_handler, regenerate_token_and_send,
-    register_user_handler, user_update_detail_handler,
+    confirm_user_email, get_user, jwks_handler, login_two_factor_handler, login_user_handler,
+    regenerate_token_and_send, register_user_handler, user_update_detail_handler,
 };
+use crate::routes::bounces::receive_bounces_handler;
 use crate::routes::clients::{
-    create_client_handler, delete_client_handler, list_client_handler, update_client_handler,
+    client_timeline_handler, create_client_handler, delete_client_handler, list_client_handler,
+    update_client_handler,
 };
 use crate::routes::companies::{
     create_company_handler, delete_company_handler, list_company_handler, update_company_handler,
 };
-use crate::routes::paths::{PaginationExtractor, ResourceIDPath, TokenPath};
+use crate::routes::contacts::{
+    create_contact_handler, delete_contact_handler, list_contacts_handler, update_contact_handler,
+};
+use crate::routes::exports::{export_clients_handler, export_companies_handler};
+use crate::routes::health::{healthz_handler, metrics_handler, readyz_handler};
+use crate::routes::imports::import_clients_handler;
+use crate::routes::inbound::{
+    assign_inbound_email_handler, delete_inbound_email_handler, download_attachment_handler,
+    list_review_queue_handler, receive_inbound_email_handler,
+};
+use crate::routes::oidc::{oidc_authorize_handler, oidc_callback_handler};
+use crate::routes::paths::{
+    BounceTokenExtractor, ClientContactPath, ExportQueryExtractor, ImportQueryExtractor,
+    OidcCallbackExtractor, OutboxQueryExtractor, PaginationExtractor, ProviderPath, ResourceIDPath,
+    TokenPath,
+};
+use crate::routes::two_factor::{
+    confirm_two_factor_handler, disable_two_factor_handler, enrol_two_factor_handler,
+    regenerate_recovery_codes_handler,
+};
+use crate::routes::webhooks::{
+    create_webhook_handler, delete_webhook_handler, list_deliveries_handler, list_webhooks_handler,
+    redeliver_handler, update_webhook_handler,
+};
 
 const HELLO_WORLD: &str = "Hello World!";
 
@@ -26,52 +65,139 @@ fn say_hello(state: State) -> (State, &'static str) {
 }
 
 pub fn router(repo: Repo) -> Router {
-    // Add the diesel middleware to a new pipeline
+    // Add the database pool to a new pipeline
     let pipelines = new_pipeline_set();
-    let (pipelines, default) =
-        pipelines.add(new_pipeline().add(DieselMiddleware::new(repo)).build());
-    let (pipelines, authenticated) = pipelines.add(
+    let (pipelines, default) = pipelines.add(
         new_pipeline()
-            .add(JWTMiddleware::<Claims>::new(get_jwt_secret_key()))
+            .add(AccessLogMiddleware::new())
+            .add(MetricsMiddleware::new())
+            .add(RepoMiddleware::new(repo))
             .build(),
     );
+    let (pipelines, authenticated) =
+        pipelines.add(new_pipeline().add(AuthMiddleware::new()).build());
+
+    // rate limits of the route groups, see `config::RateLimit`
+    let rate_limit = &config().rate_limit;
+    let limiter = RateLimiter::new(rate_limit);
+    let (pipelines, auth_limit) = pipelines.add(
+        new_pipeline()
+            .add(RateLimitMiddleware::new(&limiter, "auth", &rate_limit.auth))
+            .build(),
+    );
+    let (pipelines, email_limit) = pipelines.add(
+        new_pipeline()
+            .add(RateLimitMiddleware::new(
+                &limiter,
+                "email",
+                &rate_limit.email,
+            ))
+            .build(),
+    );
+    let (pipelines, api_limit) = pipelines.add(
+        new_pipeline()
+            .add(RateLimitMiddleware::new(&limiter, "api", &rate_limit.api))
+            .build(),
+    );
+
     // finalize this
     let pipeline_set = finalize_pipeline_set(pipelines);
     let default_chain = (default, ());
+    let public_chain = (auth_limit, default_chain);
     let auth_chain = (authenticated, default_chain);
+    let email_chain = (email_limit, auth_chain);
+    let api_chain = (api_limit, auth_chain);
+    // no user, the `api` limit applies per IP address
+    let bounce_chain = (api_limit, default_chain);
 
     build_router(default_chain, pipeline_set, |route| {
         route.get("/").to(say_hello);
+        route.get("/.well-known/jwks.json").to(jwks_handler);
+        route.get("/healthz").to(healthz_handler);
+        route.get("/readyz").to(readyz_handler);
+        route.get("/metrics").to(metrics_handler);
         // api routes
         route.scope("/api/v1", |route| {
+            // called by the MTA or email provider, with `email.bounce_secret`
+            route.with_pipeline_chain(bounce_chain, |route| {
+                route
+                    .post("/email/bounces")
+                    .with_query_string_extractor::<BounceTokenExtractor>()
+                    .to(receive_bounces_handler);
+            });
+
             // public route
-            route.post("/register").to(register_user_handler);
-            route.post("/login").to(login_user_handler);
-            route
-                .put("/confirm/:token")
-                .with_path_extractor::<TokenPath>()
-                .to(confirm_user_email);
+            route.with_pipeline_chain(public_chain, |route| {
+                route.post("/register").to(register_user_handler);
+                route.post("/login").to(login_user_handler);
+                route.post("/login/2fa").to(login_two_factor_handler);
+                route
+                    .put("/confirm/:token")
+                    .with_path_extractor::<TokenPath>()
+                    .to(confirm_user_email);
+                route
+                    .get("/account/exports/:token")
+                    .with_path_extractor::<TokenPath>()
+                    .to(download_export_handler);
 
-            // route that need to protected
-            route.with_pipeline_chain(auth_chain, |route| {
-                route.get("/me").to(get_user);
-                route.patch("/me").to(user_update_detail_handler);
+                route.scope("/oidc/:provider", |route| {
+                    route
+                        .get("/authorize")
+                        .with_path_extractor::<ProviderPath>()
+                        .to(oidc_authorize_handler);
+                    route
+                        .get("/callback")
+                        .with_path_extractor::<ProviderPath>()
+                        .with_query_string_extractor::<OidcCallbackExtractor>()
+                        .to(oidc_callback_handler);
+                });
+            });
 
-                // scope user
+            // routes sending emails
+            route.with_pipeline_chain(email_chain, |route| {
                 route.scope("/users", |route| {
                     route
                         .put("/:id/resend")
                         .with_path_extractor::<ResourceIDPath>()
                         .to(regenerate_token_and_send);
                 });
+            });
+
+            // route that need to protected
+            route.with_pipeline_chain(api_chain, |route| {
+                route.get("/me").to(get_user);
+                route.patch("/me").to(user_update_detail_handler);
+                route.post("/me/export").to(request_export_handler);
+                route.get("/me/exports").to(list_exports_handler);
+                route.get("/me/deletion").to(get_deletion_handler);
+                route.post("/me/deletion").to(schedule_deletion_handler);
+                route.delete("/me/deletion").to(cancel_deletion_handler);
+
+                route.scope("/me/2fa", |route| {
+                    route.post("/").to(enrol_two_factor_handler);
+                    route.post("/confirm").to(confirm_two_factor_handler);
+                    route
+                        .post("/recovery-codes")
+                        .to(regenerate_recovery_codes_handler);
+                    route.post("/disable").to(disable_two_factor_handler);
+                });
 
                 route.scope("/clients", |route| {
                     route.post("/").to(create_client_handler);
+                    route
+                        .post("/import")
+                        .with_query_string_extractor::<ImportQueryExtractor>()
+                        .to(import_clients_handler);
                     route
                         .get("/")
                         .with_query_string_extractor::<PaginationExtractor>()
                         .to(list_client_handler);
 
+                    route
+                        .get("/export")
+                        .with_query_string_extractor::<ExportQueryExtractor>()
+                        .to(export_clients_handler);
+
                     route
                         .patch("/:id")
                         .with_path_extractor::<ResourceIDPath>()
@@ -81,6 +207,55 @@ pub fn router(repo: Repo) -> Router {
                         .delete("/:id")
                         .with_path_extractor::<ResourceIDPath>()
                         .to(delete_client_handler);
+
+                    route
+                        .get("/:id/timeline")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .with_query_string_extractor::<PaginationExtractor>()
+                        .to(client_timeline_handler);
+
+                    route
+                        .get("/:id/contacts")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(list_contacts_handler);
+
+                    route
+                        .post("/:id/contacts")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(create_contact_handler);
+
+                    route
+                        .patch("/:id/contacts/:contact_id")
+                        .with_path_extractor::<ClientContactPath>()
+                        .to(update_contact_handler);
+
+                    route
+                        .delete("/:id/contacts/:contact_id")
+                        .with_path_extractor::<ClientContactPath>()
+                        .to(delete_contact_handler);
+                });
+
+                route.scope("/inbound", |route| {
+                    route.post("/emails").to(receive_inbound_email_handler);
+                    route
+                        .get("/review")
+                        .with_query_string_extractor::<PaginationExtractor>()
+                        .to(list_review_queue_handler);
+
+                    route
+                        .post("/emails/:id/assign")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(assign_inbound_email_handler);
+
+                    route
+                        .delete("/emails/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(delete_inbound_email_handler);
+
+                    route
+                        .get("/attachments/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(download_attachment_handler);
                 });
 
                 route.scope("/companies", |route| {
@@ -90,6 +265,11 @@ pub fn router(repo: Repo) -> Router {
                         .with_query_string_extractor::<PaginationExtractor>()
                         .to(list_company_handler);
 
+                    route
+                        .get("/export")
+                        .with_query_string_extractor::<ExportQueryExtractor>()
+                        .to(export_companies_handler);
+
                     route
                         .patch("/:id")
                         .with_path_extractor::<ResourceIDPath>()
@@ -99,6 +279,73 @@ pub fn router(repo: Repo) -> Router {
                         .delete("/:id")
                         .with_path_extractor::<ResourceIDPath>()
                         .to(delete_company_handler);
+                });
+
+                route.scope("/api-keys", |route| {
+                    route.post("/").to(create_api_key_handler);
+                    route.get("/").to(list_api_keys_handler);
+                    route
+                        .delete("/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(revoke_api_key_handler);
+                });
+
+                route.scope("/webhooks", |route| {
+                    route.post("/").to(create_webhook_handler);
+                    route.get("/").to(list_webhooks_handler);
+
+                    route
+                        .patch("/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(update_webhook_handler);
+
+                    route
+                        .delete("/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(delete_webhook_handler);
+
+                    route
+                        .get("/:id/deliveries")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .with_query_string_extractor::<PaginationExtractor>()
+                        .to(list_deliveries_handler);
+
+                    route
+                        .post("/deliveries/:id/redeliver")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(redeliver_handler);
+                });
+
+                route.scope("/admin", |route| {
+                    route
+                        .get("/lockouts")
+                        .with_query_string_extractor::<PaginationExtractor>()
+                        .to(list_lockouts_handler);
+
+                    route
+                        .delete("/lockouts/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(unlock_lockout_handler);
+
+                    route
+                        .get("/outbox")
+                        .with_query_string_extractor::<OutboxQueryExtractor>()
+                        .to(list_outbox_handler);
+
+                    route
+                        .post("/outbox/:id/retry")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(retry_outbox_handler);
+
+                    route
+                        .get("/suppressions")
+                        .with_query_string_extractor::<PaginationExtractor>()
+                        .to(list_suppressions_handler);
+
+                    route
+                        .delete("/suppressions/:id")
+                        .with_path_extractor::<ResourceIDPath>()
+                        .to(clear_suppression_handler);
                 })
             });
         });
@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate diesel;
 
+#[macro_use]
+extern crate diesel_migrations;
+
 #[allow(unused)]
 #[macro_use]
 extern crate gotham_derive;
@@ -8,34 +11,71 @@ extern crate gotham_derive;
 #[macro_use]
 extern crate validator_derive;
 
-use log::{error, info};
+use log::info;
 use std::process;
 
 use crate::config::Config;
-use crate::db::create_repo;
+use crate::db::Repo;
+use crate::middleware::cors::CorsHandler;
+use crate::server::ServerError;
+pub mod accounts;
 pub mod auth;
+pub mod cli;
 pub mod config;
 pub mod db;
 pub mod email;
 pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
 pub mod models;
+pub mod oidc;
+pub mod outbox;
 pub mod routes;
 pub mod schema;
+pub mod server;
 pub mod sql_types;
 pub(crate) mod sqlx;
+pub mod totp;
+pub mod webhooks;
 
 pub fn bootstrap() {
-    let cfg = match config::load_configuration() {
+    let matches = cli::app().get_matches();
+
+    let cfg = match config::load_configuration(&matches) {
         Ok(cfg) => cfg,
         Err(e) => {
-            error!("Failed to load configuration: {}", e);
+            eprintln!("Failed to load configuration: {}", e);
             process::exit(0x0100);
         }
     };
 
-    // start the app!
-    let app = Lako::new(cfg);
-    app.run();
+    logging::init_logging(&cfg.logging);
+
+    config::init_config(cfg.clone());
+
+    if let Err(e) = email::templates::init_templates(&cfg) {
+        eprintln!("Failed to load email templates: {}", e);
+        process::exit(1);
+    }
+
+    let result = match matches.subcommand() {
+        ("migrate", _) => cli::migrate(&cfg),
+        ("create-superuser", Some(args)) => cli::create_superuser(&cfg, args),
+        ("reset-password", Some(args)) => cli::reset_password(&cfg, args),
+        ("list-users", _) => cli::list_all_users(&cfg),
+        ("send-test-email", Some(args)) => cli::send_test_email(&cfg, args),
+        (_, args) => {
+            let migrate = matches.is_present("migrate")
+                || matches!(args, Some(args) if args.is_present("migrate"));
+            cli::serve(cfg, migrate)
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
 
 pub struct Lako {
@@ -47,10 +87,13 @@ impl Lako {
         Lako { config: cfg }
     }
 
-    pub fn run(&self) {
+    pub fn run(&self, repo: Repo) -> Result<(), ServerError> {
         info!("Starting Lako");
-        let addr = self.config.server.address.to_string();
+        let router = http::router(repo);
 
-        gotham::start(addr, http::router(create_repo(&self.config)))
+        server::serve(
+            &self.config.server,
+            CorsHandler::new(&self.config.cors, router),
+        )
     }
 }
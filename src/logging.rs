@@ -0,0 +1,47 @@
+use chrono::{SecondsFormat, Utc};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+
+use crate::config::Logging;
+
+// collects the key-values of a record into the JSON object
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let value = serde_json::to_value(&value).map_err(KvError::boxed)?;
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// Set up the logger for the configured `logging.format`.
+///
+/// `text` is the colored human readable output. `json` writes every record
+/// as a single line object with `timestamp`, `level`, `target` and `message`
+/// plus the record key-values, eg. `status` or `latency_ms` of access logs.
+pub fn init_logging(config: &Logging) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.parse_filters(&config.level);
+
+    if config.format == "json" {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert(
+                "timestamp".into(),
+                Utc::now()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            );
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+            writeln!(buf, "{}", JsonValue::Object(line))
+        });
+    }
+
+    builder.init();
+}
@@ -1,8 +1,6 @@
 use lako::bootstrap;
 
 fn main() {
-    pretty_env_logger::init();
-
     // Load configuration and start Lako
     bootstrap();
 }
@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::db::PoolStats;
+
+// The fixed segments of the routes in `http::router`, the other ones are
+// parameters. A route missing here is still counted, only with a less
+// telling label.
+const ROUTE_SEGMENTS: &[&str] = &[
+    ".well-known",
+    "2fa",
+    "account",
+    "admin",
+    "api",
+    "api-keys",
+    "assign",
+    "attachments",
+    "authorize",
+    "bounces",
+    "callback",
+    "clients",
+    "companies",
+    "confirm",
+    "contacts",
+    "deletion",
+    "deliveries",
+    "disable",
+    "email",
+    "emails",
+    "export",
+    "exports",
+    "healthz",
+    "import",
+    "inbound",
+    "jwks.json",
+    "lockouts",
+    "login",
+    "me",
+    "metrics",
+    "oidc",
+    "outbox",
+    "readyz",
+    "recovery-codes",
+    "redeliver",
+    "register",
+    "resend",
+    "retry",
+    "review",
+    "suppressions",
+    "timeline",
+    "users",
+    "v1",
+    "webhooks",
+];
+
+static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(Some("lako".into()), None).unwrap());
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served"),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time to serve HTTP requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap(),
+    )
+});
+
+static DB_POOL_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("db_pool_connections", "Open database connections").unwrap())
+});
+
+static DB_POOL_IDLE_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("db_pool_idle_connections", "Idle database connections").unwrap())
+});
+
+static DB_POOL_MAX_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("db_pool_max_size", "Maximum database connections").unwrap())
+});
+
+static EMAILS_SENT: Lazy<IntCounter> =
+    Lazy::new(|| register(IntCounter::new("emails_sent_total", "Emails sent").unwrap()));
+
+static EMAILS_FAILED: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new("emails_failed_total", "Emails that failed to send").unwrap())
+});
+
+/// The route a path belongs to, ids, tokens and names are replaced by a
+/// placeholder so every client doesn't get its own series.
+pub fn route_label(path: &str) -> String {
+    let label = path
+        .split('/')
+        .map(|segment| {
+            if segment.is_empty() || ROUTE_SEGMENTS.contains(&segment) {
+                segment
+            } else if segment.bytes().all(|b| b.is_ascii_digit()) {
+                ":id"
+            } else {
+                ":param"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    match label.trim_end_matches('/') {
+        "" => "/".to_string(),
+        label => label.to_string(),
+    }
+}
+
+pub fn observe_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[method, route, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[method, route])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn observe_pool(stats: PoolStats) {
+    DB_POOL_CONNECTIONS.set(stats.connections.into());
+    DB_POOL_IDLE_CONNECTIONS.set(stats.idle_connections.into());
+    DB_POOL_MAX_SIZE.set(stats.max_size.into());
+}
+
+pub fn email_sent() {
+    EMAILS_SENT.inc();
+}
+
+pub fn email_failed() {
+    EMAILS_FAILED.inc();
+}
+
+/// every metric, in the Prometheus text format
+pub fn render() -> String {
+    // make sure all of them are registered, even before their first use
+    Lazy::force(&HTTP_REQUESTS);
+    Lazy::force(&HTTP_REQUEST_DURATION);
+    Lazy::force(&DB_POOL_CONNECTIONS);
+    Lazy::force(&DB_POOL_IDLE_CONNECTIONS);
+    Lazy::force(&DB_POOL_MAX_SIZE);
+    Lazy::force(&EMAILS_SENT);
+    Lazy::force(&EMAILS_FAILED);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_fixed_segments() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/api/v1/clients/"), "/api/v1/clients");
+        assert_eq!(
+            route_label("/api/v1/me/2fa/confirm"),
+            "/api/v1/me/2fa/confirm"
+        );
+    }
+
+    #[test]
+    fn replaces_the_parameters() {
+        assert_eq!(
+            route_label("/api/v1/clients/42/contacts/7"),
+            "/api/v1/clients/:id/contacts/:id"
+        );
+        assert_eq!(
+            route_label("/api/v1/oidc/some-provider/authorize"),
+            "/api/v1/oidc/:param/authorize"
+        );
+        assert_eq!(route_label("/api/v1/confirm/abc"), "/api/v1/confirm/:param");
+        assert_eq!(
+            route_label("/api/v1/account/exports/0123456789abcdef0123456789abcdef"),
+            "/api/v1/account/exports/:param"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use futures::prelude::*;
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::header::HeaderValue;
+use gotham::hyper::{Method, Uri};
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{request_id, FromState, State};
+use log::info;
+use std::pin::Pin;
+use std::time::Instant;
+
+use crate::auth::{AuthorizationToken, Claims};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Log every request once it's answered and echo its id back.
+///
+/// Gotham takes the request id from the incoming `X-Request-Id` header, or
+/// generates one, error logs of the handlers are prefixed with it. The access
+/// log carries the method, path, status, latency and the authenticated user
+/// as key-values, see `logging::init_logging`.
+#[derive(Clone, Default)]
+pub struct AccessLogMiddleware {}
+
+impl AccessLogMiddleware {
+    pub fn new() -> Self {
+        AccessLogMiddleware {}
+    }
+}
+
+impl Middleware for AccessLogMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let start = Instant::now();
+        let method = Method::borrow_from(&state).to_string();
+        let path = Uri::borrow_from(&state).path().to_string();
+
+        chain(state)
+            .map(move |result| {
+                let (state, status) = match &result {
+                    Ok((state, res)) => (state, res.status()),
+                    Err((state, e)) => (state, e.status()),
+                };
+                let id = request_id(state).to_string();
+                // put there by the authentication middleware
+                let user_id = AuthorizationToken::<Claims>::try_borrow_from(state)
+                    .map(|token| token.0.claims.user_id());
+                let latency = start.elapsed().as_secs_f64() * 1000.0;
+
+                info!(
+                    target: "lako::access",
+                    request_id = id.as_str(),
+                    method = method.as_str(),
+                    path = path.as_str(),
+                    status = status.as_u16(),
+                    latency_ms = latency,
+                    user_id = user_id;
+                    "[{}] {} {} {} {:.3}ms", id, method, path, status.as_u16(), latency
+                );
+
+                result.map(|(state, mut res)| {
+                    if let Ok(value) = HeaderValue::from_str(&id) {
+                        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    }
+                    (state, res)
+                })
+            })
+            .boxed()
+    }
+}
+
+impl NewMiddleware for AccessLogMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
@@ -0,0 +1,224 @@
+use futures::prelude::*;
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::header::{HeaderMap, AUTHORIZATION};
+use gotham::hyper::{Method, StatusCode, Uri};
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{request_id, FromState, State};
+use jsonwebtoken::{Header, TokenData};
+use log::{debug, error};
+use std::pin::Pin;
+
+use crate::auth::{decode_token, AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::api_key::{authenticate_api_key, KEY_PREFIX};
+use crate::routes::utils::json_response_forbidden;
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Authenticate requests with either a JWT bearer token, or a personal API
+/// key given in the `X-Api-Key` header (or as the bearer token). Both end up
+/// as an `AuthorizationToken<Claims>` in the state, so handlers don't have
+/// to care which one was used.
+///
+/// API keys are limited to the scopes they were created with, and can't be
+/// used on routes without a scope (API keys management, 2FA, admin).
+#[derive(Clone, Default)]
+pub struct AuthMiddleware {}
+
+impl AuthMiddleware {
+    pub fn new() -> Self {
+        AuthMiddleware {}
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+fn api_key_from_headers(headers: &HeaderMap) -> Option<String> {
+    let key = match headers.get(API_KEY_HEADER) {
+        Some(key) => key.to_str().ok(),
+        None => bearer_token(headers),
+    };
+
+    key.map(str::trim)
+        .filter(|k| k.starts_with(KEY_PREFIX))
+        .map(String::from)
+}
+
+/// the scope an API key needs for a route, `None` when the route can only
+/// be used with a user token.
+fn required_scope(method: &Method, path: &str) -> Option<String> {
+    let mut segments = path
+        .strip_prefix("/api/v1/")?
+        .split('/')
+        .filter(|s| !s.is_empty());
+
+    let resource = match (segments.next(), segments.next()) {
+        (Some("me"), None) | (Some("users"), _) => "profile",
+        (Some("clients"), _) => "clients",
+        (Some("companies"), _) => "companies",
+        (Some("inbound"), _) => "inbound",
+        (Some("webhooks"), _) => "webhooks",
+        _ => return None,
+    };
+    let access = if method == Method::GET || method == Method::HEAD {
+        "read"
+    } else {
+        "write"
+    };
+
+    Some(format!("{}:{}", resource, access))
+}
+
+// a user token signed with one of our keys
+fn authenticate_token<Chain>(mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+where
+    Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+{
+    let token = match bearer_token(HeaderMap::borrow_from(&state)) {
+        Some(token) => decode_token(token),
+        None => {
+            let res = create_empty_response(&state, StatusCode::BAD_REQUEST);
+            return future::ok((state, res)).boxed();
+        }
+    };
+
+    match token {
+        Ok(token) => {
+            state.put(AuthorizationToken(token));
+            chain(state)
+        }
+        Err(e) => {
+            debug!("[{}] Rejected access token: {}", request_id(&state), e);
+            let res = create_empty_response(&state, StatusCode::UNAUTHORIZED);
+            future::ok((state, res)).boxed()
+        }
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let key = match api_key_from_headers(HeaderMap::borrow_from(&state)) {
+            Some(key) => key,
+            None => return authenticate_token(state, chain),
+        };
+
+        let scope = required_scope(Method::borrow_from(&state), Uri::borrow_from(&state).path());
+        let repo = Repo::borrow_from(&state).clone();
+
+        async move {
+            let result = repo
+                .run(move |conn| authenticate_api_key(&conn, key.as_str()))
+                .await;
+
+            match result {
+                Ok(Some(api_key)) => {
+                    if !matches!(&scope, Some(scope) if api_key.allows(scope)) {
+                        let res = json_response_forbidden(
+                            &state,
+                            "This API key is not allowed to access this resource.".into(),
+                        );
+                        return Ok((state, res));
+                    }
+
+                    state.put(AuthorizationToken(TokenData {
+                        header: Header::default(),
+                        claims: Claims::new(api_key.user_id, 0),
+                    }));
+
+                    chain(state).await
+                }
+                Ok(None) => {
+                    let res = create_empty_response(&state, StatusCode::UNAUTHORIZED);
+                    Ok((state, res))
+                }
+                Err(e) => {
+                    error!(
+                        "[{}] Failed to authenticate API key: {}",
+                        request_id(&state),
+                        e
+                    );
+                    let res = create_empty_response(&state, StatusCode::INTERNAL_SERVER_ERROR);
+                    Ok((state, res))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+impl NewMiddleware for AuthMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_scope_follows_the_route_group() {
+        let cases = [
+            (Method::GET, "/api/v1/me", Some("profile:read")),
+            (Method::PATCH, "/api/v1/me", Some("profile:write")),
+            (Method::PUT, "/api/v1/users/1/resend", Some("profile:write")),
+            (Method::GET, "/api/v1/clients/", Some("clients:read")),
+            (Method::HEAD, "/api/v1/clients/export", Some("clients:read")),
+            (
+                Method::POST,
+                "/api/v1/clients/1/contacts",
+                Some("clients:write"),
+            ),
+            (Method::DELETE, "/api/v1/clients/1", Some("clients:write")),
+            (Method::GET, "/api/v1/companies", Some("companies:read")),
+            (
+                Method::PATCH,
+                "/api/v1/companies/1",
+                Some("companies:write"),
+            ),
+            (Method::GET, "/api/v1/inbound/review", Some("inbound:read")),
+            (
+                Method::POST,
+                "/api/v1/inbound/emails/1/assign",
+                Some("inbound:write"),
+            ),
+            (
+                Method::GET,
+                "/api/v1/webhooks/1/deliveries",
+                Some("webhooks:read"),
+            ),
+            (Method::POST, "/api/v1/webhooks/", Some("webhooks:write")),
+            (Method::GET, "/api/v1/api-keys", None),
+            (Method::POST, "/api/v1/api-keys/", None),
+            (Method::GET, "/api/v1/admin/lockouts", None),
+            (Method::POST, "/api/v1/me/2fa/disable", None),
+            (Method::GET, "/api/v1/me/deletion", None),
+            (Method::DELETE, "/api/v1/me/deletion", None),
+            (Method::GET, "/api/v1", None),
+            (Method::GET, "/healthz", None),
+        ];
+
+        for (method, path, scope) in cases.iter() {
+            assert_eq!(
+                required_scope(method, path).as_deref(),
+                *scope,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use futures::prelude::*;
+use gotham::anyhow;
+use gotham::handler::{Handler, HandlerFuture, IntoResponse, NewHandler};
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use gotham::hyper::{Body, Method, Response, StatusCode};
+use gotham::state::{FromState, State};
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::config;
+
+/// The CORS rules from the `cors` configuration.
+struct CorsPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    any_header: bool,
+    headers: Vec<String>,
+    exposed_headers: Option<HeaderValue>,
+    credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+// join a list into a single header value
+fn header_list<T: AsRef<str>>(values: &[T]) -> Option<HeaderValue> {
+    if values.is_empty() {
+        return None;
+    }
+    let joined = values
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&joined).ok()
+}
+
+impl CorsPolicy {
+    fn new(config: &config::Cors) -> Self {
+        CorsPolicy {
+            any_origin: config.allowed_origins.iter().any(|o| o == "*"),
+            origins: config
+                .allowed_origins
+                .iter()
+                .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            methods: config
+                .allowed_methods
+                .iter()
+                .filter_map(|m| m.to_ascii_uppercase().parse().ok())
+                .collect(),
+            any_header: config.allowed_headers.iter().any(|h| h == "*"),
+            headers: config
+                .allowed_headers
+                .iter()
+                .map(|h| h.to_ascii_lowercase())
+                .collect(),
+            exposed_headers: header_list(&config.exposed_headers),
+            credentials: config.allow_credentials,
+            max_age: config.max_age.map(HeaderValue::from),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+    }
+
+    // the requested headers of a preflight, `None` when one isn't allowed
+    fn allowed_request_headers(&self, headers: &HeaderMap) -> Option<Vec<String>> {
+        let mut requested = Vec::new();
+        for value in headers.get_all(ACCESS_CONTROL_REQUEST_HEADERS) {
+            let value = value.to_str().ok()?;
+            requested.extend(
+                value
+                    .split(',')
+                    .map(|h| h.trim().to_ascii_lowercase())
+                    .filter(|h| !h.is_empty()),
+            );
+        }
+
+        if self.any_header || requested.iter().all(|h| self.headers.contains(h)) {
+            Some(requested)
+        } else {
+            None
+        }
+    }
+
+    // the `Access-Control-Allow-Origin` value, the wildcard is only used
+    // when there are no credentials involved
+    fn allow_origin(&self, origin: &HeaderValue) -> HeaderValue {
+        if self.any_origin && !self.credentials {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        }
+    }
+
+    fn preflight(&self, state: &State, origin: &HeaderValue) -> Response<Body> {
+        let headers = HeaderMap::borrow_from(state);
+        let method = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| Method::from_bytes(m.as_bytes()).ok());
+        let request_headers = self.allowed_request_headers(headers);
+
+        let request_headers = match (method, request_headers) {
+            (Some(method), Some(request_headers)) if self.methods.contains(&method) => {
+                request_headers
+            }
+            _ => return create_empty_response(state, StatusCode::FORBIDDEN),
+        };
+
+        let mut res = create_empty_response(state, StatusCode::NO_CONTENT);
+        let res_headers = res.headers_mut();
+        res_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin(origin));
+        if let Some(methods) = header_list(&self.methods) {
+            res_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        // with `*` configured, the requested headers are echoed back
+        let allowed_headers = if self.any_header {
+            header_list(&request_headers)
+        } else {
+            header_list(&self.headers)
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            res_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        if self.credentials {
+            res_headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(max_age) = &self.max_age {
+            res_headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+        res_headers.append(VARY, HeaderValue::from_static("Origin"));
+        res_headers.append(
+            VARY,
+            HeaderValue::from_static(
+                "Access-Control-Request-Method, Access-Control-Request-Headers",
+            ),
+        );
+
+        res
+    }
+
+    fn decorate(&self, res: &mut Response<Body>, origin: &HeaderValue) {
+        let headers = res.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin(origin));
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(exposed) = &self.exposed_headers {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed.clone());
+        }
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+/// Answer CORS requests for every route of the wrapped router.
+///
+/// This wraps the router instead of being part of a pipeline: pipelines only
+/// run for matched routes, so preflight `OPTIONS` requests would get a 405,
+/// and 404 responses wouldn't be readable by the browser.
+#[derive(Clone)]
+pub struct CorsHandler<H> {
+    handler: H,
+    policy: Arc<CorsPolicy>,
+}
+
+impl<H> CorsHandler<H> {
+    pub fn new(config: &config::Cors, handler: H) -> Self {
+        CorsHandler {
+            handler,
+            policy: Arc::new(CorsPolicy::new(config)),
+        }
+    }
+}
+
+impl<H> Handler for CorsHandler<H>
+where
+    H: Handler + Send + 'static,
+{
+    fn handle(self, state: State) -> Pin<Box<HandlerFuture>> {
+        let origin = match HeaderMap::borrow_from(&state).get(ORIGIN) {
+            Some(origin) if self.policy.enabled() => origin.clone(),
+            _ => return self.handler.handle(state),
+        };
+        let allowed = matches!(origin.to_str(), Ok(o) if self.policy.allows_origin(o));
+
+        let is_preflight = Method::borrow_from(&state) == Method::OPTIONS
+            && HeaderMap::borrow_from(&state).contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        if is_preflight {
+            let res = if allowed {
+                self.policy.preflight(&state, &origin)
+            } else {
+                create_empty_response(&state, StatusCode::FORBIDDEN)
+            };
+            return future::ok((state, res)).boxed();
+        }
+
+        if !allowed {
+            return self.handler.handle(state);
+        }
+
+        let policy = self.policy;
+        self.handler
+            .handle(state)
+            .map(move |result| {
+                // turn errors into their response here, so it gets the
+                // headers as well
+                let (state, mut res) = match result {
+                    Ok((state, res)) => (state, res),
+                    Err((state, e)) => {
+                        let res = e.into_response(&state);
+                        (state, res)
+                    }
+                };
+                policy.decorate(&mut res, &origin);
+                Ok((state, res))
+            })
+            .boxed()
+    }
+}
+
+impl<H> NewHandler for CorsHandler<H>
+where
+    H: Handler + Clone + Send + Sync + RefUnwindSafe + 'static,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
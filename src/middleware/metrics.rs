@@ -0,0 +1,49 @@
+use futures::prelude::*;
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::{Method, Uri};
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use std::pin::Pin;
+use std::time::Instant;
+
+use crate::metrics::{observe_request, route_label};
+
+/// Count requests and their duration per route and status code.
+#[derive(Clone, Default)]
+pub struct MetricsMiddleware {}
+
+impl MetricsMiddleware {
+    pub fn new() -> Self {
+        MetricsMiddleware {}
+    }
+}
+
+impl Middleware for MetricsMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let start = Instant::now();
+        let method = Method::borrow_from(&state).to_string();
+        let route = route_label(Uri::borrow_from(&state).path());
+
+        chain(state)
+            .inspect(move |result| {
+                let status = match result {
+                    Ok((_, res)) => res.status(),
+                    Err((_, e)) => e.status(),
+                };
+                observe_request(&method, &route, status.as_u16(), start.elapsed());
+            })
+            .boxed()
+    }
+}
+
+impl NewMiddleware for MetricsMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
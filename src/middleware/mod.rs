@@ -0,0 +1,6 @@
+pub mod access_log;
+pub mod auth;
+pub mod cors;
+pub mod metrics;
+pub mod rate_limit;
+pub mod repo;
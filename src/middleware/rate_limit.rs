@@ -0,0 +1,272 @@
+use futures::prelude::*;
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{client_addr, request_id, FromState, State};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::config;
+use crate::db::Repo;
+use crate::models::rate_limit::{prune_buckets, take_token};
+use crate::routes::utils::json_response_too_many_requests;
+
+// empty buckets are forgotten every so many requests
+const PRUNE_EVERY: u64 = 1000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // when the bucket is full again, it can be dropped from then on
+    full_at: Instant,
+}
+
+enum Backend {
+    Memory(Mutex<HashMap<String, Bucket>>),
+    Postgres,
+}
+
+/// The token buckets of all the rate limited route groups.
+pub struct RateLimiter {
+    enabled: bool,
+    backend: Backend,
+    requests: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &config::RateLimit) -> Arc<Self> {
+        let backend = match config.backend.as_str() {
+            "postgres" => Backend::Postgres,
+            _ => Backend::Memory(Mutex::new(HashMap::new())),
+        };
+
+        Arc::new(RateLimiter {
+            enabled: config.enabled,
+            backend,
+            requests: AtomicU64::new(0),
+        })
+    }
+
+    fn should_prune(&self) -> bool {
+        self.requests.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1
+    }
+
+    // same as `models::rate_limit::take_token` for the process local buckets
+    fn take_memory(&self, key: String, burst: f64, rate: f64, now: Instant) -> Option<f64> {
+        let buckets = match &self.backend {
+            Backend::Memory(buckets) => buckets,
+            Backend::Postgres => return None,
+        };
+        let mut buckets = buckets.lock().unwrap();
+
+        if self.should_prune() {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+            full_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * rate).min(burst);
+        let taken = tokens >= 1.0;
+
+        bucket.tokens = if taken { tokens - 1.0 } else { tokens };
+        bucket.updated_at = now;
+        bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / rate);
+
+        if taken {
+            None
+        } else {
+            Some((1.0 - tokens) / rate)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyKind {
+    Ip,
+    User,
+}
+
+/// Limit the requests of a route group with a token bucket per client.
+///
+/// Clients are told when to come back with a 429 and `Retry-After`. Groups
+/// keyed by user have to come after the authentication middleware, requests
+/// without a user are keyed by IP address.
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    group: &'static str,
+    burst: f64,
+    // tokens a second
+    rate: f64,
+    key: KeyKind,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(
+        limiter: &Arc<RateLimiter>,
+        group: &'static str,
+        config: &config::RateLimitGroup,
+    ) -> Self {
+        RateLimitMiddleware {
+            group,
+            burst: config.burst as f64,
+            rate: config.per_minute as f64 / 60.0,
+            key: if config.key == "user" {
+                KeyKind::User
+            } else {
+                KeyKind::Ip
+            },
+            limiter: limiter.clone(),
+        }
+    }
+
+    fn bucket_key(&self, state: &State) -> Option<String> {
+        if self.key == KeyKind::User {
+            if let Some(token) = AuthorizationToken::<Claims>::try_borrow_from(state) {
+                return Some(format!("{}:user:{}", self.group, token.0.claims.user_id()));
+            }
+        }
+        client_addr(state).map(|addr| format!("{}:ip:{}", self.group, addr.ip()))
+    }
+
+    // seconds until a token is available, `None` when the request may go on
+    async fn take(&self, key: String, repo: Option<Repo>) -> Option<f64> {
+        let (burst, rate) = (self.burst, self.rate);
+        let repo = match (&self.limiter.backend, repo) {
+            (Backend::Postgres, Some(repo)) => repo,
+            _ => return self.limiter.take_memory(key, burst, rate, Instant::now()),
+        };
+
+        let prefix = format!("{}:", self.group);
+        let prune = self.limiter.should_prune();
+        let result = repo
+            .run(move |conn| {
+                if prune {
+                    prune_buckets(&conn, &prefix, burst / rate)?;
+                }
+                take_token(&conn, &key, burst, rate)
+            })
+            .await;
+
+        // rather let requests through than failing all of them
+        result.unwrap_or_else(|e| {
+            error!("Failed to take a rate limit token: {}", e);
+            None
+        })
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let key = match self.bucket_key(&state) {
+            Some(key) if self.limiter.enabled => key,
+            _ => return chain(state),
+        };
+        let repo = Repo::try_borrow_from(&state).cloned();
+
+        async move {
+            match self.take(key.clone(), repo).await {
+                None => chain(state).await,
+                Some(retry_after) => {
+                    debug!(
+                        "[{}] Rate limit `{}` exceeded by `{}`",
+                        request_id(&state),
+                        self.group,
+                        key
+                    );
+                    let res = json_response_too_many_requests(
+                        &state,
+                        "too many requests, try again later".into(),
+                        retry_after.ceil().max(1.0) as u64,
+                    );
+                    Ok((state, res))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+impl NewMiddleware for RateLimitMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Arc<RateLimiter> {
+        RateLimiter::new(&config::RateLimit::default())
+    }
+
+    fn take(limiter: &RateLimiter, now: Instant) -> Option<f64> {
+        // 3 requests, then one every 2 seconds
+        limiter.take_memory("auth:ip:127.0.0.1".into(), 3.0, 0.5, now)
+    }
+
+    #[test]
+    fn take_memory_allows_the_burst_then_tells_when_to_retry() {
+        let limiter = limiter();
+        let start = Instant::now();
+
+        assert_eq!(take(&limiter, start), None);
+        assert_eq!(take(&limiter, start), None);
+        assert_eq!(take(&limiter, start), None);
+        assert_eq!(take(&limiter, start), Some(2.0));
+
+        let retry_after = take(&limiter, start + Duration::from_millis(500)).unwrap();
+        assert!((retry_after - 1.5).abs() < 1e-9, "{}", retry_after);
+    }
+
+    #[test]
+    fn take_memory_refills_with_the_rate() {
+        let limiter = limiter();
+        let start = Instant::now();
+        for _ in 0..3 {
+            take(&limiter, start);
+        }
+
+        assert_eq!(take(&limiter, start + Duration::from_secs(2)), None);
+        assert!(take(&limiter, start + Duration::from_secs(2)).is_some());
+        assert_eq!(take(&limiter, start + Duration::from_secs(4)), None);
+    }
+
+    #[test]
+    fn take_memory_refills_up_to_the_burst() {
+        let limiter = limiter();
+        let start = Instant::now();
+        take(&limiter, start);
+
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert_eq!(take(&limiter, later), None);
+        }
+        assert!(take(&limiter, later).is_some());
+    }
+
+    #[test]
+    fn take_memory_keeps_a_bucket_per_key() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert_eq!(limiter.take_memory("a".into(), 1.0, 1.0, now), None);
+        assert!(limiter.take_memory("a".into(), 1.0, 1.0, now).is_some());
+        assert_eq!(limiter.take_memory("b".into(), 1.0, 1.0, now), None);
+    }
+}
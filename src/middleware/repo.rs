@@ -0,0 +1,49 @@
+use gotham::anyhow;
+use gotham::handler::HandlerFuture;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::State;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+
+use crate::db::Repo;
+
+/// Put the database pool in the state, handlers get it with
+/// `Repo::borrow_from(&state)`.
+///
+/// The pool isn't `RefUnwindSafe`, it is only cloned here though, which
+/// can't leave it in an inconsistent state.
+pub struct RepoMiddleware {
+    repo: AssertUnwindSafe<Repo>,
+}
+
+impl RepoMiddleware {
+    pub fn new(repo: Repo) -> Self {
+        RepoMiddleware {
+            repo: AssertUnwindSafe(repo),
+        }
+    }
+}
+
+impl Clone for RepoMiddleware {
+    fn clone(&self) -> Self {
+        RepoMiddleware::new(self.repo.0.clone())
+    }
+}
+
+impl Middleware for RepoMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        state.put(self.repo.0);
+        chain(state)
+    }
+}
+
+impl NewMiddleware for RepoMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
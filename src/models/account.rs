@@ -0,0 +1,361 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::{BigInt, Timestamp};
+use diesel::{self, delete, insert_into, sql_query, update};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::Serialize;
+
+use crate::email::{send_account_deletion_email, EmailError};
+use crate::models::client::Client;
+use crate::models::company::Company;
+use crate::models::contact::Contact;
+use crate::models::user::User;
+use crate::schema::{
+    account_deletions, account_exports, clients, companies, contacts, emails, users,
+};
+use crate::sql_types::Role;
+
+pub const EXPORT_PENDING: &str = "pending";
+pub const EXPORT_READY: &str = "ready";
+pub const EXPORT_FAILED: &str = "failed";
+// the download link expired, the archive is gone
+pub const EXPORT_EXPIRED: &str = "expired";
+
+const TOKEN_LEN: usize = 32;
+
+/// A data export asked by a user, the archive is loaded separately with
+/// `find_export_archive`.
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize)]
+#[belongs_to(User)]
+pub struct AccountExport {
+    pub id: i32,
+    pub user_id: i32,
+    /// the secret of the download link, only sent by email
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub status: String,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+type AccountExportColumns = (
+    account_exports::id,
+    account_exports::user_id,
+    account_exports::token,
+    account_exports::status,
+    account_exports::last_error,
+    account_exports::created_at,
+    account_exports::completed_at,
+    account_exports::expires_at,
+);
+
+const ACCOUNT_EXPORT_COLUMNS: AccountExportColumns = (
+    account_exports::id,
+    account_exports::user_id,
+    account_exports::token,
+    account_exports::status,
+    account_exports::last_error,
+    account_exports::created_at,
+    account_exports::completed_at,
+    account_exports::expires_at,
+);
+
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize)]
+#[belongs_to(User)]
+#[primary_key(user_id)]
+pub struct AccountDeletion {
+    pub user_id: i32,
+    pub requested_at: NaiveDateTime,
+    /// the account is deleted once this is past
+    pub delete_after: NaiveDateTime,
+}
+
+/// The user as exported, without the password hash.
+#[derive(Debug, Queryable, Serialize)]
+pub struct AccountProfile {
+    pub id: i32,
+    pub role: Role,
+    pub username: String,
+    pub profile_name: String,
+    pub profile_image: String,
+    pub language: String,
+    pub joined_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Serialize)]
+pub struct AccountEmail {
+    pub email: String,
+    pub verified: bool,
+    pub bouncing: bool,
+}
+
+/// Everything Lako holds about a user, see `accounts::build_archive`.
+#[derive(Debug)]
+pub struct AccountData {
+    pub user: AccountProfile,
+    pub emails: Vec<AccountEmail>,
+    pub clients: Vec<Client>,
+    pub contacts: Vec<Contact>,
+    pub companies: Vec<Company>,
+}
+
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Queue an export of the data of `owner_id`, unless one is already waiting
+/// to be built, it's returned then.
+pub fn request_export(conn: &PgConnection, owner_id: i32) -> Result<AccountExport, Error> {
+    conn.transaction(|| {
+        let pending = account_exports::table
+            .select(ACCOUNT_EXPORT_COLUMNS)
+            .filter(account_exports::user_id.eq(owner_id))
+            .filter(account_exports::status.eq(EXPORT_PENDING))
+            .first::<AccountExport>(conn)
+            .optional()?;
+        if let Some(pending) = pending {
+            return Ok(pending);
+        }
+
+        insert_into(account_exports::table)
+            .values((
+                account_exports::user_id.eq(owner_id),
+                account_exports::token.eq(random_token()),
+            ))
+            .returning(ACCOUNT_EXPORT_COLUMNS)
+            .get_result(conn)
+    })
+}
+
+pub fn list_exports(conn: &PgConnection, owner_id: i32) -> Result<Vec<AccountExport>, Error> {
+    account_exports::table
+        .select(ACCOUNT_EXPORT_COLUMNS)
+        .filter(account_exports::user_id.eq(owner_id))
+        .order(account_exports::created_at.desc())
+        .load(conn)
+}
+
+/// The ZIP of a ready export and when it was built, while its link works.
+pub fn find_export_archive(
+    conn: &PgConnection,
+    token: &str,
+) -> Result<Option<(Vec<u8>, NaiveDateTime)>, Error> {
+    let found = account_exports::table
+        .select((account_exports::archive, account_exports::completed_at))
+        .filter(account_exports::token.eq(token))
+        .filter(account_exports::status.eq(EXPORT_READY))
+        .filter(account_exports::expires_at.gt(Utc::now().naive_utc()))
+        .first::<(Option<Vec<u8>>, Option<NaiveDateTime>)>(conn)
+        .optional()?;
+
+    Ok(match found {
+        Some((Some(archive), Some(completed_at))) => Some((archive, completed_at)),
+        _ => None,
+    })
+}
+
+/// Lock the oldest export waiting to be built, other workers skip it until
+/// the transaction of `conn` ends. Must be called in a transaction.
+pub fn claim_pending_export(conn: &PgConnection) -> Result<Option<AccountExport>, Error> {
+    let id = account_exports::table
+        .select(account_exports::id)
+        .filter(account_exports::status.eq(EXPORT_PENDING))
+        .order(account_exports::created_at)
+        .for_update()
+        .skip_locked()
+        .first::<i32>(conn)
+        .optional()?;
+
+    match id {
+        Some(id) => account_exports::table
+            .find(id)
+            .select(ACCOUNT_EXPORT_COLUMNS)
+            .first(conn)
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn complete_export(
+    conn: &PgConnection,
+    export_id: i32,
+    archive: &[u8],
+    expires_at: NaiveDateTime,
+) -> Result<usize, Error> {
+    update(account_exports::table.find(export_id))
+        .set((
+            account_exports::status.eq(EXPORT_READY),
+            account_exports::archive.eq(archive),
+            account_exports::completed_at.eq(Utc::now().naive_utc()),
+            account_exports::expires_at.eq(expires_at),
+        ))
+        .execute(conn)
+}
+
+pub fn fail_export(conn: &PgConnection, export_id: i32, error: &str) -> Result<usize, Error> {
+    update(account_exports::table.find(export_id))
+        .set((
+            account_exports::status.eq(EXPORT_FAILED),
+            account_exports::last_error.eq(error),
+            account_exports::completed_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+}
+
+/// Drop the archives of the exports whose link expired.
+pub fn purge_expired_exports(conn: &PgConnection) -> Result<usize, Error> {
+    update(
+        account_exports::table
+            .filter(account_exports::status.eq(EXPORT_READY))
+            .filter(account_exports::expires_at.le(Utc::now().naive_utc())),
+    )
+    .set((
+        account_exports::status.eq(EXPORT_EXPIRED),
+        account_exports::archive.eq(None::<Vec<u8>>),
+    ))
+    .execute(conn)
+}
+
+/// Load everything to export about `owner_id`.
+pub fn account_data(conn: &PgConnection, owner_id: i32) -> Result<AccountData, Error> {
+    let user = users::table
+        .find(owner_id)
+        .select((
+            users::id,
+            users::role,
+            users::username,
+            users::profile_name,
+            users::profile_image,
+            users::language,
+            users::joined_at,
+            users::updated_at,
+        ))
+        .first(conn)?;
+    let emails = emails::table
+        .select((emails::email, emails::verified, emails::bouncing))
+        .filter(emails::user_id.eq(owner_id))
+        .order(emails::id)
+        .load(conn)?;
+    let clients = clients::table
+        .filter(clients::user_id.eq(owner_id))
+        .order(clients::id)
+        .load(conn)?;
+    let contacts = contacts::table
+        .filter(
+            contacts::client_id.eq_any(
+                clients::table
+                    .select(clients::id)
+                    .filter(clients::user_id.eq(owner_id)),
+            ),
+        )
+        .order(contacts::id)
+        .load(conn)?;
+    let companies = companies::table
+        .filter(companies::user_id.eq(owner_id))
+        .order(companies::id)
+        .load(conn)?;
+
+    Ok(AccountData {
+        user,
+        emails,
+        clients,
+        contacts,
+        companies,
+    })
+}
+
+pub fn find_deletion(conn: &PgConnection, owner_id: i32) -> Result<Option<AccountDeletion>, Error> {
+    account_deletions::table
+        .find(owner_id)
+        .first(conn)
+        .optional()
+}
+
+/// Schedule the deletion of the account of `owner_id` after `grace`, and let
+/// its owner know by email. Asking again keeps the first schedule.
+pub fn schedule_deletion(
+    conn: &PgConnection,
+    owner_id: i32,
+    grace: Duration,
+) -> Result<AccountDeletion, EmailError> {
+    conn.transaction(|| {
+        if let Some(scheduled) = find_deletion(conn, owner_id)? {
+            return Ok(scheduled);
+        }
+
+        let deletion = insert_into(account_deletions::table)
+            .values((
+                account_deletions::user_id.eq(owner_id),
+                account_deletions::delete_after.eq(Utc::now().naive_utc() + grace),
+            ))
+            .get_result::<AccountDeletion>(conn)?;
+
+        let (username, language) = users::table
+            .find(owner_id)
+            .select((users::username, users::language))
+            .first::<(String, String)>(conn)?;
+        let address = emails::table
+            .select(emails::email)
+            .filter(emails::user_id.eq(owner_id))
+            .first::<String>(conn)
+            .optional()?;
+        if let Some(address) = address {
+            send_account_deletion_email(
+                conn,
+                &address,
+                &username,
+                &language,
+                &deletion.delete_after,
+            )?;
+        }
+
+        Ok(deletion)
+    })
+}
+
+pub fn cancel_deletion(conn: &PgConnection, owner_id: i32) -> Result<usize, Error> {
+    delete(account_deletions::table.find(owner_id)).execute(conn)
+}
+
+/// Delete up to `limit` accounts whose grace period is over, returns their
+/// ids. The records of the users go with them through `ON DELETE CASCADE`,
+/// except their email addresses.
+pub fn delete_due_accounts(conn: &PgConnection, limit: i64) -> Result<Vec<i32>, Error> {
+    #[derive(QueryableByName)]
+    struct Due {
+        #[sql_type = "diesel::sql_types::Integer"]
+        user_id: i32,
+    }
+
+    conn.transaction(|| {
+        // the clock `schedule_deletion` and the notice email went by
+        let due = sql_query(
+            "SELECT user_id FROM account_deletions \
+             WHERE delete_after <= $2 \
+             ORDER BY delete_after \
+             LIMIT $1 \
+             FOR UPDATE SKIP LOCKED",
+        )
+        .bind::<BigInt, _>(limit)
+        .bind::<Timestamp, _>(Utc::now().naive_utc())
+        .load::<Due>(conn)?
+        .into_iter()
+        .map(|due| due.user_id)
+        .collect::<Vec<_>>();
+
+        if !due.is_empty() {
+            delete(emails::table.filter(emails::user_id.eq_any(&due))).execute(conn)?;
+            delete(users::table.filter(users::id.eq_any(&due))).execute(conn)?;
+        }
+        Ok(due)
+    })
+}
@@ -0,0 +1,150 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, insert_into};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::models::user::User;
+use crate::schema::api_keys;
+
+// every key starts with this, so they are easy to tell apart from JWTs
+// and to spot when leaked in a repository or a log.
+pub const KEY_PREFIX: &str = "lako_";
+const LOOKUP_PREFIX_LEN: usize = 8;
+const SECRET_LEN: usize = 32;
+
+/// The scopes an API key can be granted. Routes map to a `<resource>:<access>`
+/// scope, `read` for safe methods and `write` for the rest.
+pub const SCOPES: &[&str] = &[
+    "profile:read",
+    "profile:write",
+    "clients:read",
+    "clients:write",
+    "companies:read",
+    "companies:write",
+    "inbound:read",
+    "inbound:write",
+    "webhooks:read",
+    "webhooks:write",
+];
+
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(User)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub prefix: String,
+    #[serde(skip_serializing, default)]
+    pub hashed_key: String,
+    pub scopes: Vec<String>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "api_keys"]
+struct NewApiKey<'a> {
+    user_id: i32,
+    name: &'a str,
+    prefix: &'a str,
+    hashed_key: &'a str,
+    scopes: &'a [String],
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Create a new API key, only its hash is stored so the plain key returned
+/// here can't be recovered later.
+pub fn create_api_key(
+    conn: &PgConnection,
+    owner_id: i32,
+    name: &str,
+    scopes: &[String],
+) -> Result<(ApiKey, String), Error> {
+    let prefix = random_string(LOOKUP_PREFIX_LEN);
+    let key = format!("{}{}_{}", KEY_PREFIX, prefix, random_string(SECRET_LEN));
+
+    let api_key = insert_into(api_keys::table)
+        .values(&NewApiKey {
+            user_id: owner_id,
+            name,
+            prefix: &prefix,
+            hashed_key: &hash_key(&key),
+            scopes,
+        })
+        .get_result::<ApiKey>(conn)?;
+
+    Ok((api_key, key))
+}
+
+/// Look the key up and check it's still active, marking it as used.
+pub fn authenticate_api_key(conn: &PgConnection, key: &str) -> Result<Option<ApiKey>, Error> {
+    use crate::schema::api_keys::dsl::*;
+    use diesel::update;
+
+    let lookup = match key
+        .strip_prefix(KEY_PREFIX)
+        .and_then(|rest| rest.split('_').next())
+    {
+        Some(lookup) => lookup,
+        None => return Ok(None),
+    };
+
+    let api_key = api_keys
+        .filter(prefix.eq(lookup))
+        .filter(revoked_at.is_null())
+        .first::<ApiKey>(conn)
+        .optional()?;
+
+    match api_key {
+        Some(api_key) if api_key.hashed_key == hash_key(key) => update(&api_key)
+            .set(last_used_at.eq(Utc::now().naive_utc()))
+            .get_result::<ApiKey>(conn)
+            .map(Some),
+        _ => Ok(None),
+    }
+}
+
+pub fn list_api_keys(conn: &PgConnection, owner_id: i32) -> Result<Vec<ApiKey>, Error> {
+    use crate::schema::api_keys::dsl::*;
+
+    api_keys
+        .filter(user_id.eq(owner_id))
+        .order(created_at.desc())
+        .load::<ApiKey>(conn)
+}
+
+pub fn revoke_api_key(conn: &PgConnection, key_id: i32, owner_id: i32) -> Result<usize, Error> {
+    use crate::schema::api_keys::dsl::*;
+    use diesel::update;
+
+    update(api_keys.find(key_id))
+        .filter(user_id.eq(owner_id))
+        .filter(revoked_at.is_null())
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+}
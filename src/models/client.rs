@@ -1,11 +1,18 @@
+use std::collections::HashSet;
+
 use chrono::NaiveDateTime;
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::result::Error;
+use diesel::sql_types::Bool;
 use diesel::{self, insert_into};
 
+use crate::models::suppression::{is_suppressed, suppressed_addresses};
 use crate::models::user::User;
+use crate::models::webhook::{queue_event, queue_events};
 use crate::schema::clients;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 use validator::Validate;
 
 #[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
@@ -26,15 +33,28 @@ pub struct Client {
     pub notes: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// emails to the client bounced, or they complained about one
+    pub email_bouncing: bool,
 }
 
 pub fn delete_client(client_id: i32, owner_id: i32, conn: &PgConnection) -> Result<usize, Error> {
     use crate::schema::clients::dsl::*;
     use diesel::delete;
 
-    delete(clients.find(client_id))
-        .filter(user_id.eq(owner_id))
-        .execute(&*conn)
+    conn.transaction(|| {
+        let deleted = delete(clients.find(client_id))
+            .filter(user_id.eq(owner_id))
+            .execute(&*conn)?;
+        if deleted > 0 {
+            queue_event(
+                conn,
+                owner_id,
+                "client.deleted",
+                &json!({ "id": client_id }),
+            )?;
+        }
+        Ok(deleted)
+    })
 }
 
 #[derive(Debug, Insertable, Serialize, Deserialize)]
@@ -56,12 +76,127 @@ pub struct NewClient {
 
 impl NewClient {
     pub fn insert_client(self, conn: &PgConnection) -> Result<Client, Error> {
-        let client = insert_into(crate::schema::clients::table)
-            .values(&self)
-            .get_result::<Client>(&*conn)?;
+        conn.transaction(|| {
+            let bouncing = is_suppressed(conn, &self.email)?;
+            let client = insert_into(crate::schema::clients::table)
+                .values((&self, clients::email_bouncing.eq(bouncing)))
+                .get_result::<Client>(&*conn)?;
+
+            queue_event(conn, client.user_id, "client.created", &client)?;
+            Ok(client)
+        })
+    }
+}
+
+/// The emails, lowercased, of the clients of `owner_id` among `candidates`.
+pub fn existing_client_emails(
+    conn: &PgConnection,
+    owner_id: i32,
+    candidates: &[String],
+) -> Result<HashSet<String>, Error> {
+    use crate::schema::clients::dsl::*;
+    use crate::sqlx::lower;
+
+    let candidates = candidates
+        .iter()
+        .map(|candidate| candidate.to_lowercase())
+        .collect::<Vec<_>>();
+    let existing = clients
+        .filter(user_id.eq(owner_id))
+        .filter(lower(email).eq_any(&candidates))
+        .select(lower(email))
+        .load::<String>(conn)?;
+    Ok(existing.into_iter().collect())
+}
+
+// rows per INSERT, Postgres takes up to 65535 bind parameters
+const IMPORT_BATCH: usize = 1000;
+
+/// Insert all the clients of `owner_id`, or none of them. Unlike
+/// `insert_client` one at a time, the suppressions and webhooks are looked up
+/// once and the clients inserted a batch at a time.
+pub fn import_clients(
+    conn: &PgConnection,
+    owner_id: i32,
+    new_clients: Vec<NewClient>,
+) -> Result<Vec<Client>, Error> {
+    conn.transaction(|| {
+        let addresses = new_clients
+            .iter()
+            .map(|new_client| new_client.email.clone())
+            .collect::<Vec<_>>();
+        let suppressed = suppressed_addresses(conn, &addresses)?;
+
+        let rows = new_clients
+            .into_iter()
+            .map(|new_client| {
+                let bouncing = suppressed.contains(&new_client.email.to_ascii_lowercase());
+                (new_client, clients::email_bouncing.eq(bouncing))
+            })
+            .collect::<Vec<_>>();
+        let mut imported = Vec::with_capacity(rows.len());
+        for batch in rows.chunks(IMPORT_BATCH) {
+            imported.extend(
+                insert_into(clients::table)
+                    .values(batch)
+                    .get_results::<Client>(conn)?,
+            );
+        }
+
+        queue_events(conn, owner_id, "client.created", &imported)?;
+        Ok(imported)
+    })
+}
+
+/// The filter of the clients whose name starts with `search`, or the name or
+/// email of one of their contacts as a client is found by the people working
+/// there too.
+pub fn client_search(
+    search: &str,
+) -> Box<dyn BoxableExpression<clients::table, Pg, SqlType = Bool>> {
+    use crate::schema::contacts;
+
+    let pattern = format!("{}%", search);
+    let by_contact = contacts::table.select(contacts::client_id).filter(
+        contacts::name
+            .ilike(pattern.clone())
+            .or(contacts::email.ilike(pattern.clone())),
+    );
+    Box::new(
+        clients::name
+            .ilike(pattern)
+            .or(clients::id.eq_any(by_contact)),
+    )
+}
 
-        Ok(client)
+/// A batch of the clients of `owner_id` to export, newest first and after the
+/// `(created_at, id)` of the last client of the previous batch, filtered by
+/// `client_search` like the list of clients.
+pub fn client_export_batch(
+    conn: &PgConnection,
+    owner_id: i32,
+    search: Option<&str>,
+    after: Option<(NaiveDateTime, i32)>,
+    limit: i64,
+) -> Result<Vec<Client>, Error> {
+    use crate::schema::clients::dsl::*;
+
+    let mut query = clients
+        .filter(user_id.eq(owner_id))
+        .order((created_at.desc(), id.desc()))
+        .limit(limit)
+        .into_boxed();
+    if let Some(search) = search {
+        query = query.filter(client_search(search));
+    }
+    if let Some((last_created_at, last_id)) = after {
+        query = query.filter(
+            created_at
+                .lt(last_created_at)
+                .or(created_at.eq(last_created_at).and(id.lt(last_id))),
+        );
     }
+    query.load(conn)
 }
 
 #[derive(AsChangeset, Serialize, Deserialize, Validate)]
@@ -92,12 +227,20 @@ impl ChangeClient {
         use crate::schema::clients::dsl::*;
         use diesel::update;
 
-        let client = update(clients.find(client_id))
-            .filter(user_id.eq(owner_id))
-            .set(&self)
-            .get_result::<Client>(&*conn)?;
+        // a new address may be suppressed, or not anymore
+        let bouncing = match &self.email {
+            Some(new_email) => Some(email_bouncing.eq(is_suppressed(conn, new_email)?)),
+            None => None,
+        };
+        conn.transaction(|| {
+            let client = update(clients.find(client_id))
+                .filter(user_id.eq(owner_id))
+                .set((&self, bouncing))
+                .get_result::<Client>(&*conn)?;
 
-        Ok(client)
+            queue_event(conn, client.user_id, "client.updated", &client)?;
+            Ok(client)
+        })
     }
 }
 
@@ -111,3 +254,27 @@ pub struct CompactClient {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
+
+#[cfg(test)]
+mod tests {
+    use diesel::debug_query;
+
+    use super::*;
+
+    #[test]
+    fn client_search_looks_at_the_contacts_too() {
+        let query = clients::table
+            .select(clients::id)
+            .filter(client_search("ann"))
+            .into_boxed::<Pg>();
+        let sql = debug_query(&query).to_string();
+
+        assert!(sql.contains(r#""clients"."name" ILIKE $1"#), "{}", sql);
+        assert!(sql.contains(r#""contacts"."email" ILIKE $3"#), "{}", sql);
+        assert!(
+            sql.contains(r#"binds: ["ann%", "ann%", "ann%"]"#),
+            "{}",
+            sql
+        );
+    }
+}
@@ -1,11 +1,15 @@
 use chrono::NaiveDateTime;
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::result::Error;
+use diesel::sql_types::Bool;
 use diesel::{self, insert_into};
 
 use crate::models::user::User;
+use crate::models::webhook::queue_event;
 use crate::schema::companies;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 
 #[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
 #[belongs_to(User)]
@@ -39,9 +43,14 @@ pub struct NewCompany {
 
 impl NewCompany {
     pub fn insert_company(&self, conn: &PgConnection) -> Result<Company, Error> {
-        insert_into(crate::schema::companies::table)
-            .values(self)
-            .get_result(&*conn)
+        conn.transaction(|| {
+            let company = insert_into(crate::schema::companies::table)
+                .values(self)
+                .get_result::<Company>(&*conn)?;
+
+            queue_event(conn, company.user_id, "company.created", &company)?;
+            Ok(company)
+        })
     }
 }
 
@@ -49,9 +58,55 @@ pub fn delete_company(company_id: i32, owner_id: i32, conn: &PgConnection) -> Re
     use crate::schema::companies::dsl::*;
     use diesel::delete;
 
-    delete(companies.find(company_id))
+    conn.transaction(|| {
+        let deleted = delete(companies.find(company_id))
+            .filter(user_id.eq(owner_id))
+            .execute(&*conn)?;
+        if deleted > 0 {
+            queue_event(
+                conn,
+                owner_id,
+                "company.deleted",
+                &json!({ "id": company_id }),
+            )?;
+        }
+        Ok(deleted)
+    })
+}
+
+/// The filter of the companies whose name starts with `search`.
+pub fn company_search(
+    search: &str,
+) -> Box<dyn BoxableExpression<companies::table, Pg, SqlType = Bool>> {
+    Box::new(companies::name.ilike(format!("{}%", search)))
+}
+
+/// A batch of the companies of `owner_id` to export, see `client_export_batch`.
+pub fn company_export_batch(
+    conn: &PgConnection,
+    owner_id: i32,
+    search: Option<&str>,
+    after: Option<(NaiveDateTime, i32)>,
+    limit: i64,
+) -> Result<Vec<Company>, Error> {
+    use crate::schema::companies::dsl::*;
+
+    let mut query = companies
         .filter(user_id.eq(owner_id))
-        .execute(&*conn)
+        .order((created_at.desc(), id.desc()))
+        .limit(limit)
+        .into_boxed();
+    if let Some(search) = search {
+        query = query.filter(company_search(search));
+    }
+    if let Some((last_created_at, last_id)) = after {
+        query = query.filter(
+            created_at
+                .lt(last_created_at)
+                .or(created_at.eq(last_created_at).and(id.lt(last_id))),
+        );
+    }
+    query.load(conn)
 }
 
 #[derive(AsChangeset, Serialize, Deserialize)]
@@ -79,10 +134,15 @@ impl ChangeCompany {
         use crate::schema::companies::dsl::*;
         use diesel::update;
 
-        update(companies.find(company_id))
-            .filter(user_id.eq(owner_id))
-            .set(self)
-            .get_result::<Company>(&*conn)
+        conn.transaction(|| {
+            let company = update(companies.find(company_id))
+                .filter(user_id.eq(owner_id))
+                .set(self)
+                .get_result::<Company>(&*conn)?;
+
+            queue_event(conn, company.user_id, "company.updated", &company)?;
+            Ok(company)
+        })
     }
 }
 
@@ -0,0 +1,184 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, delete, insert_into, update};
+use serde_derive::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::models::client::Client;
+use crate::schema::{clients, contacts};
+
+/// A person at a client, eg. their billing contact.
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(Client)]
+pub struct Contact {
+    pub id: i32,
+    pub client_id: i32,
+    pub name: String,
+    /// their role at the client
+    pub title: String,
+    pub email: String,
+    pub phone: String,
+    /// the one to reach first, a client has one at most
+    pub is_primary: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "contacts"]
+pub struct NewContact {
+    pub client_id: i32,
+    pub name: String,
+    pub title: String,
+    pub email: String,
+    pub phone: String,
+    pub is_primary: bool,
+}
+
+#[derive(Debug, AsChangeset, Deserialize, Validate)]
+#[table_name = "contacts"]
+pub struct ChangeContact {
+    #[validate(length(min = 1, max = 255))]
+    pub name: Option<String>,
+    #[validate(length(max = 255))]
+    pub title: Option<String>,
+    #[validate(email)]
+    pub email: Option<String>,
+    #[validate(length(max = 64))]
+    pub phone: Option<String>,
+    pub is_primary: Option<bool>,
+}
+
+fn owns_client(conn: &PgConnection, owner_id: i32, client_id: i32) -> Result<bool, Error> {
+    use diesel::dsl::exists;
+    use diesel::select;
+
+    select(exists(
+        clients::table
+            .find(client_id)
+            .filter(clients::user_id.eq(owner_id)),
+    ))
+    .get_result(conn)
+}
+
+// `owns_client`, locking the client until the end of the transaction so
+// concurrent changes of its primary contact wait for each other
+fn lock_client(conn: &PgConnection, owner_id: i32, client_id: i32) -> Result<bool, Error> {
+    clients::table
+        .find(client_id)
+        .filter(clients::user_id.eq(owner_id))
+        .select(clients::id)
+        .for_update()
+        .first::<i32>(conn)
+        .optional()
+        .map(|client| client.is_some())
+}
+
+// the primary contact is about to change
+fn clear_primary(conn: &PgConnection, client_id: i32) -> Result<usize, Error> {
+    update(
+        contacts::table
+            .filter(contacts::client_id.eq(client_id))
+            .filter(contacts::is_primary.eq(true)),
+    )
+    .set(contacts::is_primary.eq(false))
+    .execute(conn)
+}
+
+/// The contacts of a client of `owner_id`, the primary one first, `None`
+/// when there is no such client.
+pub fn list_contacts(
+    conn: &PgConnection,
+    owner_id: i32,
+    client_id: i32,
+) -> Result<Option<Vec<Contact>>, Error> {
+    if !owns_client(conn, owner_id, client_id)? {
+        return Ok(None);
+    }
+
+    contacts::table
+        .filter(contacts::client_id.eq(client_id))
+        .order((contacts::is_primary.desc(), contacts::name))
+        .load(conn)
+        .map(Some)
+}
+
+/// Add a contact to a client of `owner_id`, `None` when there is no such
+/// client. The first contact of a client is its primary one.
+pub fn create_contact(
+    conn: &PgConnection,
+    owner_id: i32,
+    mut new_contact: NewContact,
+) -> Result<Option<Contact>, Error> {
+    conn.transaction(|| {
+        if !lock_client(conn, owner_id, new_contact.client_id)? {
+            return Ok(None);
+        }
+
+        if new_contact.is_primary {
+            clear_primary(conn, new_contact.client_id)?;
+        } else {
+            let has_primary = contacts::table
+                .filter(contacts::client_id.eq(new_contact.client_id))
+                .filter(contacts::is_primary.eq(true))
+                .count()
+                .get_result::<i64>(conn)?;
+            new_contact.is_primary = has_primary == 0;
+        }
+
+        insert_into(contacts::table)
+            .values(&new_contact)
+            .get_result(conn)
+            .map(Some)
+    })
+}
+
+impl ChangeContact {
+    /// `None` when the client isn't one of `owner_id` or has no such contact.
+    pub fn update(
+        &self,
+        conn: &PgConnection,
+        owner_id: i32,
+        client_id: i32,
+        contact_id: i32,
+    ) -> Result<Option<Contact>, Error> {
+        conn.transaction(|| {
+            if !lock_client(conn, owner_id, client_id)? {
+                return Err(Error::NotFound);
+            }
+            if self.is_primary == Some(true) {
+                clear_primary(conn, client_id)?;
+            }
+
+            // `NotFound` when there is no such contact, which rolls back
+            // clearing the primary one
+            update(
+                contacts::table
+                    .find(contact_id)
+                    .filter(contacts::client_id.eq(client_id)),
+            )
+            .set(self)
+            .get_result(conn)
+        })
+        .optional()
+    }
+}
+
+pub fn delete_contact(
+    conn: &PgConnection,
+    owner_id: i32,
+    client_id: i32,
+    contact_id: i32,
+) -> Result<usize, Error> {
+    if !owns_client(conn, owner_id, client_id)? {
+        return Ok(0);
+    }
+
+    delete(
+        contacts::table
+            .find(contact_id)
+            .filter(contacts::client_id.eq(client_id)),
+    )
+    .execute(conn)
+}
@@ -12,6 +12,8 @@ pub struct Email {
     pub token: String,
     pub verified: bool,
     pub token_generated_at: NaiveDateTime,
+    /// a delivery to the address bounced, see `models::suppression`
+    pub bouncing: bool,
 }
 
 #[derive(Debug, Insertable, AsChangeset)]
@@ -0,0 +1,222 @@
+use bcrypt::{hash as bcrypt_hash, DEFAULT_COST};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, insert_into};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::config::config;
+use crate::models::user::{find_user, insert_user, AuthenticationError, User};
+use crate::schema::{emails, user_identities, users};
+use crate::sql_types::Role;
+use crate::sqlx::lower;
+
+// the `state` of an authorization request, and how long the user has to
+// sign in at the provider
+const STATE_LEN: usize = 32;
+const STATE_MINUTES: i64 = 10;
+
+/// a user account linked to an external identity provider
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[table_name = "user_identities"]
+pub struct UserIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// the pending authorization request, looked up by its `state` when the
+/// provider redirects back.
+#[derive(Debug, Queryable)]
+pub struct OidcLoginState {
+    pub state_token: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub expires_at: NaiveDateTime,
+}
+
+pub fn create_login_state(
+    conn: &PgConnection,
+    provider_name: &str,
+    verifier: &str,
+    nonce_value: &str,
+) -> Result<String, Error> {
+    use crate::schema::oidc_login_states::dsl::*;
+
+    let token = random_string(STATE_LEN);
+    insert_into(oidc_login_states)
+        .values((
+            state_token.eq(&token),
+            provider.eq(provider_name),
+            code_verifier.eq(verifier),
+            nonce.eq(nonce_value),
+            expires_at.eq(Utc::now().naive_utc() + Duration::minutes(STATE_MINUTES)),
+        ))
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// Returns the pending request for the state and removes it, a state can only
+/// be used once. Expired states are cleaned up at the same time.
+pub fn take_login_state(
+    conn: &PgConnection,
+    provider_name: &str,
+    state: &str,
+) -> Result<Option<OidcLoginState>, Error> {
+    use crate::schema::oidc_login_states::dsl::*;
+    use diesel::delete;
+
+    let now = Utc::now().naive_utc();
+    let login_state = delete(
+        oidc_login_states
+            .filter(state_token.eq(state))
+            .filter(provider.eq(provider_name)),
+    )
+    .get_result::<OidcLoginState>(conn)
+    .optional()?;
+
+    delete(oidc_login_states.filter(expires_at.lt(now))).execute(conn)?;
+
+    Ok(login_state.filter(|s| s.expires_at > now))
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// lowercase the preferred username (or the email local part) and keep the
+// characters we allow in usernames, appending a number if it's taken.
+fn available_username(
+    conn: &PgConnection,
+    preferred: Option<&str>,
+    email: &str,
+) -> Result<String, Error> {
+    let base: String = preferred
+        .unwrap_or_else(|| email.split('@').next().unwrap_or(email))
+        .to_ascii_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.' || *c == '-')
+        .collect();
+    // registration requires at least 5 characters
+    let base = format!("{:_<5}", base);
+
+    let mut candidate = base.clone();
+    let mut suffix = 1;
+    loop {
+        let taken = users::table
+            .filter(users::username.eq(&candidate))
+            .select(users::id)
+            .first::<i32>(conn)
+            .optional()?
+            .is_some();
+        if !taken {
+            return Ok(candidate);
+        }
+        suffix += 1;
+        candidate = format!("{}{}", base, suffix);
+    }
+}
+
+/// Find the user linked to an identity from an OpenID Connect provider.
+///
+/// When there is no such link yet, the identity is linked to the user owning
+/// the same email address, as long as both the provider and Lako verified
+/// it. Otherwise a new customer is registered with a random password, its
+/// email address marked as verified when the provider says so.
+pub fn link_or_create_user(
+    conn: &PgConnection,
+    provider_name: &str,
+    subject_id: &str,
+    email_address: &str,
+    email_verified: bool,
+    preferred_username: Option<&str>,
+) -> Result<User, AuthenticationError> {
+    use diesel::update;
+
+    conn.transaction(|| {
+        let linked = user_identities::table
+            .filter(user_identities::provider.eq(provider_name))
+            .filter(user_identities::subject.eq(subject_id))
+            .select(user_identities::user_id)
+            .first::<i32>(conn)
+            .optional()?;
+
+        if let Some(user) = linked.map(|id| find_user(conn, id)).transpose()?.flatten() {
+            return Ok(user);
+        }
+
+        // the address must be verified on both sides, otherwise anyone could
+        // register it first and take over the account of its owner
+        let existing = if email_verified {
+            emails::table
+                .filter(lower(emails::email).eq(email_address.to_lowercase()))
+                .filter(emails::verified.eq(true))
+                .select(emails::user_id)
+                .first::<i32>(conn)
+                .optional()?
+        } else {
+            None
+        };
+
+        let user = match existing
+            .map(|id| find_user(conn, id))
+            .transpose()?
+            .flatten()
+        {
+            Some(user) => user,
+            None => {
+                let username = available_username(conn, preferred_username, email_address)?;
+                let hashed_password = bcrypt_hash(random_string(32), DEFAULT_COST)?;
+                let (user, token) = insert_user(
+                    conn,
+                    &username,
+                    email_address,
+                    &hashed_password,
+                    &Role::Customer,
+                    &config().email.default_language,
+                )?;
+
+                match token {
+                    Some(_) if email_verified => {
+                        update(emails::table.filter(emails::user_id.eq(user.id)))
+                            .set(emails::verified.eq(true))
+                            .execute(conn)?;
+                    }
+                    Some(token) => {
+                        crate::email::send_user_confirm_email(
+                            conn,
+                            email_address,
+                            &username,
+                            &user.language,
+                            &token,
+                        )?;
+                    }
+                    None => {}
+                }
+
+                user
+            }
+        };
+
+        insert_into(user_identities::table)
+            .values((
+                user_identities::user_id.eq(user.id),
+                user_identities::provider.eq(provider_name),
+                user_identities::subject.eq(subject_id),
+                user_identities::email.eq(email_address),
+            ))
+            .execute(conn)?;
+
+        Ok(user)
+    })
+}
@@ -0,0 +1,221 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, delete, insert_into, update};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::email::inbound::InboundMessage;
+use crate::models::user::User;
+use crate::schema::{clients, inbound_email_attachments, inbound_emails};
+use crate::sqlx::lower;
+
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(User)]
+pub struct InboundEmail {
+    pub id: i32,
+    pub user_id: i32,
+    /// `None` while the email waits in the review queue
+    pub client_id: Option<i32>,
+    pub message_id: Option<String>,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body_text: String,
+    pub body_html: Option<String>,
+    pub sent_at: Option<NaiveDateTime>,
+    pub received_at: NaiveDateTime,
+}
+
+/// an attachment, without its content
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(InboundEmail)]
+#[table_name = "inbound_email_attachments"]
+pub struct AttachmentInfo {
+    pub id: i32,
+    pub inbound_email_id: i32,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i32,
+}
+
+#[derive(Debug, Queryable)]
+pub struct InboundAttachment {
+    pub id: i32,
+    pub inbound_email_id: i32,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i32,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboundEmailDetail {
+    #[serde(flatten)]
+    pub email: InboundEmail,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "inbound_emails"]
+struct NewInboundEmail<'a> {
+    user_id: i32,
+    client_id: Option<i32>,
+    message_id: Option<&'a str>,
+    sender: &'a str,
+    recipients: &'a [String],
+    subject: &'a str,
+    body_text: &'a str,
+    body_html: Option<&'a str>,
+    sent_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "inbound_email_attachments"]
+struct NewInboundAttachment<'a> {
+    inbound_email_id: i32,
+    filename: &'a str,
+    content_type: &'a str,
+    size: i32,
+    content: &'a [u8],
+}
+
+// the client of `owner_id` with this email address, the most recently
+// updated one when several share it
+fn match_client(conn: &PgConnection, owner_id: i32, address: &str) -> Result<Option<i32>, Error> {
+    clients::table
+        .filter(clients::user_id.eq(owner_id))
+        .filter(lower(clients::email).eq(address))
+        .order(clients::updated_at.desc())
+        .select(clients::id)
+        .first(conn)
+        .optional()
+}
+
+/// Store an email received by `owner_id` on the timeline of the client who
+/// sent it, or in the review queue when the sender is not a client.
+///
+/// A message already received, by its `Message-ID`, is not stored twice. The
+/// second value is `false` in that case and the first one is the stored email.
+pub fn store_inbound_email(
+    conn: &PgConnection,
+    owner_id: i32,
+    message: &InboundMessage,
+) -> Result<(InboundEmail, bool), Error> {
+    conn.transaction(|| {
+        // a concurrent delivery of the same message may win the race
+        let inserted = insert_into(inbound_emails::table)
+            .values(&NewInboundEmail {
+                user_id: owner_id,
+                client_id: match_client(conn, owner_id, &message.sender)?,
+                message_id: message.message_id.as_deref(),
+                sender: &message.sender,
+                recipients: &message.recipients,
+                subject: &message.subject,
+                body_text: &message.body_text,
+                body_html: message.body_html.as_deref(),
+                sent_at: message.sent_at,
+            })
+            .on_conflict((inbound_emails::user_id, inbound_emails::message_id))
+            .do_nothing()
+            .get_result::<InboundEmail>(conn)
+            .optional()?;
+        let email = match inserted {
+            Some(email) => email,
+            // only a message with an id conflicts
+            None => {
+                let existing = inbound_emails::table
+                    .filter(inbound_emails::user_id.eq(owner_id))
+                    .filter(inbound_emails::message_id.eq(&message.message_id))
+                    .first::<InboundEmail>(conn)?;
+                return Ok((existing, false));
+            }
+        };
+
+        let attachments = message
+            .attachments
+            .iter()
+            .map(|attachment| NewInboundAttachment {
+                inbound_email_id: email.id,
+                filename: &attachment.filename,
+                content_type: &attachment.content_type,
+                size: attachment.size,
+                content: &attachment.content,
+            })
+            .collect::<Vec<_>>();
+        insert_into(inbound_email_attachments::table)
+            .values(&attachments)
+            .execute(conn)?;
+
+        Ok((email, true))
+    })
+}
+
+/// the emails along with their attachments, in the same order
+pub fn with_attachments(
+    conn: &PgConnection,
+    emails: Vec<InboundEmail>,
+) -> Result<Vec<InboundEmailDetail>, Error> {
+    use crate::schema::inbound_email_attachments::dsl::*;
+
+    let attachments = AttachmentInfo::belonging_to(&emails)
+        .select((id, inbound_email_id, filename, content_type, size))
+        .order(id)
+        .load::<AttachmentInfo>(conn)?
+        .grouped_by(&emails);
+
+    Ok(emails
+        .into_iter()
+        .zip(attachments)
+        .map(|(email, attachments)| InboundEmailDetail { email, attachments })
+        .collect())
+}
+
+/// Move an email of `owner_id` to the timeline of one of their clients.
+/// Returns `None` when either of them doesn't exist.
+pub fn assign_inbound_email(
+    conn: &PgConnection,
+    owner_id: i32,
+    email_id: i32,
+    new_client_id: i32,
+) -> Result<Option<InboundEmail>, Error> {
+    let client = clients::table
+        .find(new_client_id)
+        .filter(clients::user_id.eq(owner_id))
+        .select(clients::id)
+        .first::<i32>(conn)
+        .optional()?;
+    if client.is_none() {
+        return Ok(None);
+    }
+
+    update(inbound_emails::table.find(email_id))
+        .filter(inbound_emails::user_id.eq(owner_id))
+        .set(inbound_emails::client_id.eq(new_client_id))
+        .get_result(conn)
+        .optional()
+}
+
+pub fn delete_inbound_email(
+    conn: &PgConnection,
+    owner_id: i32,
+    email_id: i32,
+) -> Result<usize, Error> {
+    delete(inbound_emails::table.find(email_id))
+        .filter(inbound_emails::user_id.eq(owner_id))
+        .execute(conn)
+}
+
+/// an attachment of an email received by `owner_id`
+pub fn find_attachment(
+    conn: &PgConnection,
+    owner_id: i32,
+    attachment_id: i32,
+) -> Result<Option<InboundAttachment>, Error> {
+    inbound_email_attachments::table
+        .inner_join(inbound_emails::table)
+        .filter(inbound_email_attachments::id.eq(attachment_id))
+        .filter(inbound_emails::user_id.eq(owner_id))
+        .select(inbound_email_attachments::all_columns)
+        .first(conn)
+        .optional()
+}
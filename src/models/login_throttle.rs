@@ -0,0 +1,229 @@
+use std::cmp::min;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::{Integer, Text, Timestamp};
+use diesel::{self, insert_into, sql_query};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::schema::{account_lockouts, login_throttles};
+
+// number of consecutive failures before an account is locked
+pub const MAX_ACCOUNT_FAILURES: i32 = 5;
+// an IP address is shared by many accounts (NAT, offices) so it gets more room
+pub const MAX_IP_FAILURES: i32 = 20;
+// how long a lockout lasts, failures older than this are forgotten too
+const LOCKOUT_MINUTES: i64 = 15;
+// upper bound of the exponential backoff between two failed attempts
+const MAX_BACKOFF_SECONDS: i64 = 60;
+
+pub const SCOPE_ACCOUNT: &str = "account";
+pub const SCOPE_IP: &str = "ip";
+
+#[derive(Debug, Queryable, QueryableByName, Identifiable)]
+#[table_name = "login_throttles"]
+#[primary_key(throttle_key)]
+pub struct LoginThrottle {
+    pub throttle_key: String,
+    pub failed_count: i32,
+    pub last_failed_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+impl LoginThrottle {
+    /// returns the time the next login attempt is allowed, or `None`
+    /// when it may happen right away.
+    pub fn retry_at(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        if let Some(locked_until) = self.locked_until {
+            return if locked_until > now {
+                Some(locked_until)
+            } else {
+                None
+            };
+        }
+
+        if self.failed_count == 0 || self.is_stale(now) {
+            return None;
+        }
+
+        let next_attempt = self.last_failed_at + backoff(self.failed_count);
+        if next_attempt > now {
+            Some(next_attempt)
+        } else {
+            None
+        }
+    }
+
+    fn is_stale(&self, now: NaiveDateTime) -> bool {
+        self.last_failed_at + Duration::minutes(LOCKOUT_MINUTES) < now
+    }
+}
+
+// 1, 2, 4, 8... seconds, capped at MAX_BACKOFF_SECONDS
+fn backoff(failed_count: i32) -> Duration {
+    let exponent = min(failed_count.max(1) - 1, 16) as u32;
+    Duration::seconds(min(2i64.pow(exponent), MAX_BACKOFF_SECONDS))
+}
+
+pub fn account_key(username: &str) -> String {
+    format!("{}:{}", SCOPE_ACCOUNT, username)
+}
+
+pub fn ip_key(ip_address: &str) -> String {
+    format!("{}:{}", SCOPE_IP, ip_address)
+}
+
+pub fn find_throttle(conn: &PgConnection, key: &str) -> Result<Option<LoginThrottle>, Error> {
+    login_throttles::table
+        .find(key)
+        .first::<LoginThrottle>(conn)
+        .optional()
+}
+
+/// check whether a login attempt for the given throttle key is allowed now.
+pub fn login_retry_at(conn: &PgConnection, key: &str) -> Result<Option<NaiveDateTime>, Error> {
+    let now = Utc::now().naive_utc();
+    let throttle = find_throttle(conn, key)?;
+
+    Ok(throttle.and_then(|t| t.retry_at(now)))
+}
+
+/// record a failed login attempt, returns the lockout expiration when this
+/// failure locked the key.
+pub fn record_login_failure(
+    conn: &PgConnection,
+    key: &str,
+    max_failures: i32,
+) -> Result<Option<(i32, NaiveDateTime)>, Error> {
+    let now = Utc::now().naive_utc();
+    let stale_before = now - Duration::minutes(LOCKOUT_MINUTES);
+    let lock = now + Duration::minutes(LOCKOUT_MINUTES);
+
+    // the count is incremented by the database so concurrent failures are
+    // never lost, an expired lockout or an old failure starts a fresh window
+    let throttle = sql_query(
+        "INSERT INTO login_throttles (throttle_key, failed_count, last_failed_at, locked_until) \
+         VALUES ($1, 1, $2, CASE WHEN 1 >= $3 THEN $4 END) \
+         ON CONFLICT (throttle_key) DO UPDATE SET \
+         failed_count = CASE \
+             WHEN login_throttles.locked_until IS NOT NULL \
+               OR login_throttles.last_failed_at < $5 THEN 1 \
+             ELSE login_throttles.failed_count + 1 END, \
+         last_failed_at = $2, \
+         locked_until = CASE \
+             WHEN login_throttles.locked_until IS NOT NULL \
+               OR login_throttles.last_failed_at < $5 THEN CASE WHEN 1 >= $3 THEN $4 END \
+             WHEN login_throttles.failed_count + 1 >= $3 THEN $4 END \
+         RETURNING *",
+    )
+    .bind::<Text, _>(key)
+    .bind::<Timestamp, _>(now)
+    .bind::<Integer, _>(max_failures)
+    .bind::<Timestamp, _>(lock)
+    .bind::<Timestamp, _>(stale_before)
+    .get_result::<LoginThrottle>(conn)?;
+
+    Ok(throttle
+        .locked_until
+        .map(|until| (throttle.failed_count, until)))
+}
+
+/// forget every failure recorded for the key, used after a successful login
+/// or when an admin lifts a lockout.
+pub fn clear_login_failures(conn: &PgConnection, key: &str) -> Result<usize, Error> {
+    use diesel::delete;
+
+    delete(login_throttles::table.find(key)).execute(conn)
+}
+
+#[derive(Debug, Queryable, Identifiable, Serialize, Deserialize)]
+pub struct AccountLockout {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub scope: String,
+    pub throttle_key: String,
+    pub failed_count: i32,
+    pub locked_until: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "account_lockouts"]
+pub struct NewAccountLockout<'a> {
+    pub user_id: Option<i32>,
+    pub scope: &'a str,
+    pub throttle_key: &'a str,
+    pub failed_count: i32,
+    pub locked_until: NaiveDateTime,
+}
+
+impl<'a> NewAccountLockout<'a> {
+    pub fn insert_lockout(&self, conn: &PgConnection) -> Result<AccountLockout, Error> {
+        insert_into(account_lockouts::table)
+            .values(self)
+            .get_result(conn)
+    }
+}
+
+pub fn find_lockout(conn: &PgConnection, lockout_id: i32) -> Result<Option<AccountLockout>, Error> {
+    account_lockouts::table
+        .find(lockout_id)
+        .first::<AccountLockout>(conn)
+        .optional()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(seconds: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 6, 1).and_hms(10, 0, 0) + Duration::seconds(seconds)
+    }
+
+    fn throttle(failed_count: i32, locked_until: Option<NaiveDateTime>) -> LoginThrottle {
+        LoginThrottle {
+            throttle_key: account_key("ann"),
+            failed_count,
+            last_failed_at: at(0),
+            locked_until,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), Duration::seconds(1));
+        assert_eq!(backoff(1), Duration::seconds(1));
+        assert_eq!(backoff(2), Duration::seconds(2));
+        assert_eq!(backoff(4), Duration::seconds(8));
+        assert_eq!(backoff(7), Duration::seconds(MAX_BACKOFF_SECONDS));
+        assert_eq!(backoff(i32::MAX), Duration::seconds(MAX_BACKOFF_SECONDS));
+    }
+
+    #[test]
+    fn retry_at_waits_for_the_backoff() {
+        let throttle = throttle(3, None);
+
+        assert_eq!(throttle.retry_at(at(1)), Some(at(4)));
+        assert_eq!(throttle.retry_at(at(4)), None);
+    }
+
+    #[test]
+    fn retry_at_waits_for_the_lockout() {
+        let throttle = throttle(MAX_ACCOUNT_FAILURES, Some(at(900)));
+
+        assert_eq!(throttle.retry_at(at(60)), Some(at(900)));
+        assert_eq!(throttle.retry_at(at(900)), None);
+    }
+
+    #[test]
+    fn retry_at_forgets_stale_failures() {
+        assert_eq!(throttle(0, None).retry_at(at(0)), None);
+        assert_eq!(
+            throttle(20, None).retry_at(at(LOCKOUT_MINUTES * 60 + 1)),
+            None
+        );
+    }
+}
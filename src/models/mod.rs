@@ -1,4 +1,15 @@
+pub mod account;
+pub mod api_key;
 pub mod client;
 pub mod company;
+pub mod contact;
 pub mod email;
+pub mod identity;
+pub mod inbound;
+pub mod login_throttle;
+pub mod outbox;
+pub mod rate_limit;
+pub mod suppression;
+pub mod totp;
 pub mod user;
+pub mod webhook;
@@ -0,0 +1,162 @@
+use std::cmp::min;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::{BigInt, Double};
+use diesel::{self, insert_into, sql_query, update};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::schema::email_outbox;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SENT: &str = "sent";
+// gave up after `email.outbox.max_attempts` failed deliveries
+pub const STATUS_DEAD: &str = "dead";
+
+// first delay before retrying a failed delivery, doubled on every failure
+const RETRY_BASE_SECONDS: i64 = 60;
+const MAX_RETRY_SECONDS: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Queryable, QueryableByName, Identifiable, Serialize, Deserialize)]
+#[table_name = "email_outbox"]
+pub struct OutboxEmail {
+    pub id: i32,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub sent_at: Option<NaiveDateTime>,
+    /// the HTML alternative of `body`
+    pub html_body: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "email_outbox"]
+struct NewOutboxEmail<'a> {
+    recipient: &'a str,
+    subject: &'a str,
+    body: &'a str,
+    html_body: Option<&'a str>,
+}
+
+/// Queue an email, it's delivered by the outbox worker once the current
+/// transaction commits.
+pub fn enqueue_email(
+    conn: &PgConnection,
+    recipient: &str,
+    subject: &str,
+    body: &str,
+    html_body: Option<&str>,
+) -> Result<OutboxEmail, Error> {
+    insert_into(email_outbox::table)
+        .values(&NewOutboxEmail {
+            recipient,
+            subject,
+            body,
+            html_body,
+        })
+        .get_result(conn)
+}
+
+/// Take up to `limit` emails due for delivery. They are leased for
+/// `lease_seconds`, so other workers skip them, and picked up again after
+/// that if the worker died before recording the outcome.
+pub fn claim_due_emails(
+    conn: &PgConnection,
+    limit: i64,
+    lease_seconds: f64,
+) -> Result<Vec<OutboxEmail>, Error> {
+    sql_query(
+        "UPDATE email_outbox \
+         SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2) \
+         WHERE id IN ( \
+             SELECT id FROM email_outbox \
+             WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP \
+             ORDER BY next_attempt_at \
+             LIMIT $1 \
+             FOR UPDATE SKIP LOCKED \
+         ) \
+         RETURNING *",
+    )
+    .bind::<BigInt, _>(limit)
+    .bind::<Double, _>(lease_seconds)
+    .load(conn)
+}
+
+pub fn mark_email_sent(conn: &PgConnection, email_id: i32) -> Result<usize, Error> {
+    use crate::schema::email_outbox::dsl::*;
+
+    update(email_outbox.find(email_id))
+        .set((
+            status.eq(STATUS_SENT),
+            attempts.eq(attempts + 1),
+            last_error.eq(None::<String>),
+            sent_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+}
+
+// 1, 2, 4, 8... minutes, capped at MAX_RETRY_SECONDS
+fn retry_delay(failed_attempts: i32) -> Duration {
+    let exponent = min(failed_attempts.max(1) - 1, 16) as u32;
+    Duration::seconds(min(
+        RETRY_BASE_SECONDS * 2i64.pow(exponent),
+        MAX_RETRY_SECONDS,
+    ))
+}
+
+/// Record a failed delivery and schedule the next attempt, returns `true`
+/// when the email was dead-lettered instead.
+pub fn mark_email_failed(
+    conn: &PgConnection,
+    email: &OutboxEmail,
+    error: &str,
+    max_attempts: i32,
+) -> Result<bool, Error> {
+    use crate::schema::email_outbox::dsl::*;
+
+    let failed_attempts = email.attempts + 1;
+    let dead = failed_attempts >= max_attempts;
+    let next_status = if dead { STATUS_DEAD } else { STATUS_PENDING };
+
+    update(email_outbox.find(email.id))
+        .set((
+            status.eq(next_status),
+            attempts.eq(failed_attempts),
+            last_error.eq(error),
+            next_attempt_at.eq(Utc::now().naive_utc() + retry_delay(failed_attempts)),
+        ))
+        .execute(conn)?;
+
+    Ok(dead)
+}
+
+pub fn find_outbox_email(conn: &PgConnection, email_id: i32) -> Result<Option<OutboxEmail>, Error> {
+    email_outbox::table
+        .find(email_id)
+        .first::<OutboxEmail>(conn)
+        .optional()
+}
+
+/// Queue a dead (or still pending) email again for an immediate delivery,
+/// with a fresh count of attempts. Sent emails are left alone.
+pub fn retry_outbox_email(
+    conn: &PgConnection,
+    email_id: i32,
+) -> Result<Option<OutboxEmail>, Error> {
+    use crate::schema::email_outbox::dsl::*;
+
+    update(email_outbox.find(email_id).filter(status.ne(STATUS_SENT)))
+        .set((
+            status.eq(STATUS_PENDING),
+            attempts.eq(0),
+            next_attempt_at.eq(Utc::now().naive_utc()),
+        ))
+        .get_result(conn)
+        .optional()
+}
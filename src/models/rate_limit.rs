@@ -0,0 +1,67 @@
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_query;
+use diesel::sql_types::{Double, Text};
+
+#[derive(QueryableByName)]
+struct RetryAfter {
+    #[sql_type = "Double"]
+    retry_after: f64,
+}
+
+/// Take a token from the bucket `key`, holding up to `burst` tokens refilled
+/// with `rate` tokens a second. Returns `None` when one was taken, otherwise
+/// the seconds until the next token is available.
+///
+/// The refill is computed and the token taken in a single statement, so
+/// concurrent requests of several instances can't overdraw the bucket.
+pub fn take_token(
+    conn: &PgConnection,
+    key: &str,
+    burst: f64,
+    rate: f64,
+) -> Result<Option<f64>, Error> {
+    let taken = sql_query(
+        "INSERT INTO rate_limit_buckets (bucket_key, tokens) VALUES ($1, $2 - 1) \
+         ON CONFLICT (bucket_key) DO UPDATE SET \
+             tokens = LEAST($2, rate_limit_buckets.tokens \
+                 + EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - rate_limit_buckets.updated_at)::float8 * $3) - 1, \
+             updated_at = CURRENT_TIMESTAMP \
+         WHERE LEAST($2, rate_limit_buckets.tokens \
+             + EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - rate_limit_buckets.updated_at)::float8 * $3) >= 1",
+    )
+    .bind::<Text, _>(key)
+    .bind::<Double, _>(burst)
+    .bind::<Double, _>(rate)
+    .execute(conn)?;
+
+    if taken > 0 {
+        return Ok(None);
+    }
+
+    let retry = sql_query(
+        "SELECT (1 - LEAST($2, tokens \
+             + EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - updated_at)::float8 * $3)) / $3 AS retry_after \
+         FROM rate_limit_buckets WHERE bucket_key = $1",
+    )
+    .bind::<Text, _>(key)
+    .bind::<Double, _>(burst)
+    .bind::<Double, _>(rate)
+    .get_result::<RetryAfter>(conn)
+    .optional()?;
+
+    Ok(Some(retry.map_or(0.0, |r| r.retry_after)))
+}
+
+/// Remove the buckets of `prefix` untouched for `seconds`, they are full
+/// again by then and the same as a missing one.
+pub fn prune_buckets(conn: &PgConnection, prefix: &str, seconds: f64) -> Result<usize, Error> {
+    sql_query(
+        "DELETE FROM rate_limit_buckets \
+         WHERE bucket_key LIKE $1 || '%' \
+         AND updated_at < CURRENT_TIMESTAMP - make_interval(secs => $2)",
+    )
+    .bind::<Text, _>(prefix)
+    .bind::<Double, _>(seconds)
+    .execute(conn)
+}
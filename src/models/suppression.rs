@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use chrono::NaiveDateTime;
+use diesel::dsl::{exists, select};
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, delete, insert_into, update};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::email::bounce::BounceReport;
+use crate::schema::{clients, email_suppressions, emails};
+use crate::sqlx::lower;
+
+/// An address emails are no longer sent to, after a bounce or a complaint.
+/// The `emails` and `clients` using it are flagged as bouncing.
+#[derive(Debug, Queryable, Identifiable, Serialize, Deserialize)]
+pub struct EmailSuppression {
+    pub id: i32,
+    pub address: String,
+    /// `bounce` or `complaint`
+    pub reason: String,
+    pub detail: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "email_suppressions"]
+struct NewEmailSuppression<'a> {
+    address: &'a str,
+    reason: &'a str,
+    detail: &'a str,
+}
+
+// flag the users and clients emails of `address`
+fn set_bouncing(conn: &PgConnection, address: &str, bouncing: bool) -> Result<(), Error> {
+    update(emails::table.filter(lower(emails::email).eq(address)))
+        .set(emails::bouncing.eq(bouncing))
+        .execute(conn)?;
+    update(clients::table.filter(lower(clients::email).eq(address)))
+        .set(clients::email_bouncing.eq(bouncing))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// suppress the address of `report`, a later report replaces the reason
+pub fn suppress_address(
+    conn: &PgConnection,
+    report: &BounceReport,
+) -> Result<EmailSuppression, Error> {
+    use crate::schema::email_suppressions::dsl::*;
+
+    conn.transaction(|| {
+        let suppression = insert_into(email_suppressions)
+            .values(&NewEmailSuppression {
+                address: &report.address,
+                reason: report.kind.as_str(),
+                detail: &report.detail,
+            })
+            .on_conflict(address)
+            .do_update()
+            .set((reason.eq(excluded(reason)), detail.eq(excluded(detail))))
+            .get_result::<EmailSuppression>(conn)?;
+
+        set_bouncing(conn, &suppression.address, true)?;
+        Ok(suppression)
+    })
+}
+
+pub fn is_suppressed(conn: &PgConnection, recipient: &str) -> Result<bool, Error> {
+    use crate::schema::email_suppressions::dsl::*;
+
+    select(exists(
+        email_suppressions.filter(address.eq(recipient.to_ascii_lowercase())),
+    ))
+    .get_result(conn)
+}
+
+/// The addresses among `recipients` emails are no longer sent to, lowercased.
+pub fn suppressed_addresses(
+    conn: &PgConnection,
+    recipients: &[String],
+) -> Result<HashSet<String>, Error> {
+    use crate::schema::email_suppressions::dsl::*;
+
+    let recipients = recipients
+        .iter()
+        .map(|recipient| recipient.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let suppressed = email_suppressions
+        .filter(address.eq_any(&recipients))
+        .select(address)
+        .load::<String>(conn)?;
+    Ok(suppressed.into_iter().collect())
+}
+
+/// Lift a suppression, emails are sent to the address again. Returns `None`
+/// when there is no such suppression.
+pub fn clear_suppression(
+    conn: &PgConnection,
+    suppression_id: i32,
+) -> Result<Option<EmailSuppression>, Error> {
+    conn.transaction(|| {
+        let suppression = delete(email_suppressions::table.find(suppression_id))
+            .get_result::<EmailSuppression>(conn)
+            .optional()?;
+
+        if let Some(suppression) = &suppression {
+            set_bouncing(conn, &suppression.address, false)?;
+        }
+        Ok(suppression)
+    })
+}
@@ -0,0 +1,226 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{self, insert_into};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::models::user::User;
+use crate::schema::{login_challenges, totp_recovery_codes, user_totp};
+use crate::totp::{generate_recovery_codes, generate_secret, hash_recovery_code, verify_code};
+
+// the challenge stands in for the password until the second step
+const CHALLENGE_LEN: usize = 32;
+const CHALLENGE_MINUTES: i64 = 5;
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[belongs_to(User)]
+#[primary_key(user_id)]
+#[table_name = "user_totp"]
+pub struct UserTotp {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: i64,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+pub fn find_user_totp(conn: &PgConnection, owner_id: i32) -> Result<Option<UserTotp>, Error> {
+    user_totp::table
+        .find(owner_id)
+        .first::<UserTotp>(conn)
+        .optional()
+}
+
+pub fn two_factor_enabled(conn: &PgConnection, owner_id: i32) -> Result<bool, Error> {
+    Ok(matches!(find_user_totp(conn, owner_id)?, Some(totp) if totp.enabled))
+}
+
+/// Start (or restart) the enrolment with a new secret. The secret only
+/// becomes active once confirmed with a valid code, returns `None` when two
+/// factor authentication is already enabled.
+pub fn begin_totp_enrolment(conn: &PgConnection, owner_id: i32) -> Result<Option<String>, Error> {
+    use crate::schema::user_totp::dsl::*;
+
+    if two_factor_enabled(conn, owner_id)? {
+        return Ok(None);
+    }
+
+    let new_secret = generate_secret();
+    insert_into(user_totp)
+        .values((user_id.eq(owner_id), secret.eq(&new_secret)))
+        .on_conflict(user_id)
+        .do_update()
+        .set((secret.eq(&new_secret), last_used_step.eq(0)))
+        .execute(conn)?;
+
+    Ok(Some(new_secret))
+}
+
+/// Confirm the pending enrolment with a code from the authenticator app,
+/// on success two factor authentication is enabled and the recovery codes
+/// are returned.
+pub fn confirm_totp_enrolment(
+    conn: &PgConnection,
+    owner_id: i32,
+    code: &str,
+) -> Result<Option<Vec<String>>, Error> {
+    use diesel::update;
+
+    conn.transaction(|| {
+        let totp = match find_user_totp(conn, owner_id)? {
+            Some(totp) if !totp.enabled => totp,
+            _ => return Ok(None),
+        };
+
+        let step = match verify_code(&totp.secret, code, totp.last_used_step) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        let confirmed = update(
+            user_totp::table
+                .find(owner_id)
+                .filter(user_totp::enabled.eq(false))
+                .filter(user_totp::last_used_step.lt(step)),
+        )
+        .set((
+            user_totp::enabled.eq(true),
+            user_totp::last_used_step.eq(step),
+        ))
+        .execute(conn)?;
+        if confirmed == 0 {
+            return Ok(None);
+        }
+
+        replace_recovery_codes(conn, owner_id).map(Some)
+    })
+}
+
+/// Throw away the previous recovery codes and generate new ones.
+pub fn replace_recovery_codes(conn: &PgConnection, owner_id: i32) -> Result<Vec<String>, Error> {
+    use diesel::delete;
+
+    let codes = generate_recovery_codes();
+    let rows = codes
+        .iter()
+        .map(|code| {
+            (
+                totp_recovery_codes::user_id.eq(owner_id),
+                totp_recovery_codes::hashed_code.eq(hash_recovery_code(code)),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(owner_id)))
+        .execute(conn)?;
+    insert_into(totp_recovery_codes::table)
+        .values(&rows)
+        .execute(conn)?;
+
+    Ok(codes)
+}
+
+/// Check a second factor, either a TOTP code or one of the recovery codes.
+/// Both can only be used once.
+pub fn verify_second_factor(
+    conn: &PgConnection,
+    totp: &UserTotp,
+    code: &str,
+) -> Result<bool, Error> {
+    use diesel::update;
+
+    if let Some(step) = verify_code(&totp.secret, code, totp.last_used_step) {
+        // a concurrent request with the same code may have used the step
+        // since `totp` was loaded, only one of them gets to move it forward
+        let used = update(
+            user_totp::table
+                .find(totp.user_id)
+                .filter(user_totp::last_used_step.lt(step)),
+        )
+        .set(user_totp::last_used_step.eq(step))
+        .execute(conn)?;
+
+        return Ok(used > 0);
+    }
+
+    let used = update(
+        totp_recovery_codes::table
+            .filter(totp_recovery_codes::user_id.eq(totp.user_id))
+            .filter(totp_recovery_codes::hashed_code.eq(hash_recovery_code(code)))
+            .filter(totp_recovery_codes::used_at.is_null()),
+    )
+    .set(totp_recovery_codes::used_at.eq(Utc::now().naive_utc()))
+    .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+/// Turn off two factor authentication, the current code (or a recovery code)
+/// is required so a stolen access token is not enough.
+pub fn disable_two_factor(conn: &PgConnection, owner_id: i32, code: &str) -> Result<bool, Error> {
+    use diesel::delete;
+
+    conn.transaction(|| {
+        let totp = match find_user_totp(conn, owner_id)? {
+            Some(totp) if totp.enabled => totp,
+            _ => return Ok(false),
+        };
+
+        if !verify_second_factor(conn, &totp, code)? {
+            return Ok(false);
+        }
+
+        delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(owner_id)))
+            .execute(conn)?;
+        delete(&totp).execute(conn)?;
+
+        Ok(true)
+    })
+}
+
+/// create the short-lived token handed out by the first login step
+pub fn create_login_challenge(conn: &PgConnection, owner_id: i32) -> Result<String, Error> {
+    let token = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CHALLENGE_LEN)
+        .map(char::from)
+        .collect::<String>();
+
+    insert_into(login_challenges::table)
+        .values((
+            login_challenges::token.eq(&token),
+            login_challenges::user_id.eq(owner_id),
+            login_challenges::expires_at
+                .eq(Utc::now().naive_utc() + Duration::minutes(CHALLENGE_MINUTES)),
+        ))
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// returns the user a challenge token belongs to, if it exists and hasn't
+/// expired yet.
+pub fn find_login_challenge(conn: &PgConnection, challenge: &str) -> Result<Option<i32>, Error> {
+    login_challenges::table
+        .filter(login_challenges::token.eq(challenge))
+        .filter(login_challenges::expires_at.gt(Utc::now().naive_utc()))
+        .select(login_challenges::user_id)
+        .first::<i32>(conn)
+        .optional()
+}
+
+/// a challenge is consumed by a successful second step, expired ones are
+/// cleaned up at the same time.
+pub fn delete_login_challenge(conn: &PgConnection, challenge: &str) -> Result<usize, Error> {
+    use diesel::delete;
+
+    delete(
+        login_challenges::table.filter(
+            login_challenges::token
+                .eq(challenge)
+                .or(login_challenges::expires_at.lt(Utc::now().naive_utc())),
+        ),
+    )
+    .execute(conn)
+}
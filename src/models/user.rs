@@ -1,43 +1,69 @@
 use std::error;
 use std::fmt;
 
+use crate::email::EmailError;
 use crate::models::email::{Email, NewEmail};
+use crate::models::login_throttle::{
+    account_key, clear_login_failures, ip_key, login_retry_at, record_login_failure,
+    NewAccountLockout, MAX_ACCOUNT_FAILURES, MAX_IP_FAILURES, SCOPE_ACCOUNT, SCOPE_IP,
+};
+use crate::models::suppression::is_suppressed;
+use crate::models::totp::{
+    create_login_challenge, delete_login_challenge, find_login_challenge, find_user_totp,
+    two_factor_enabled, verify_second_factor,
+};
+use crate::models::webhook::queue_event;
 use crate::schema::{emails, users};
 use crate::sql_types::Role;
 use bcrypt::{hash as bcrypt_hash, verify as bcrypt_verify, BcryptError, DEFAULT_COST};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::{self, insert_into};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
 
 #[derive(Debug)]
 pub enum AuthenticationError {
     IncorrectPassword,
     NoUsernameSet,
     NoPasswordSet,
+    TooManyAttempts(NaiveDateTime),
     BcryptError(BcryptError),
     DatabaseError(diesel::result::Error),
+    EmailError(EmailError),
 }
 
 impl fmt::Display for AuthenticationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "authentication error: {}",
-            match *self {
-                AuthenticationError::IncorrectPassword => "incorrect password",
-                AuthenticationError::NoUsernameSet => "no username set",
-                AuthenticationError::NoPasswordSet => "no password set",
-                _ => "internal error",
+        match *self {
+            AuthenticationError::IncorrectPassword => {
+                write!(f, "authentication error: incorrect password")
             }
-        )
+            AuthenticationError::NoUsernameSet => {
+                write!(f, "authentication error: no username set")
+            }
+            AuthenticationError::NoPasswordSet => {
+                write!(f, "authentication error: no password set")
+            }
+            AuthenticationError::TooManyAttempts(_) => {
+                write!(f, "authentication error: too many failed login attempts")
+            }
+            AuthenticationError::BcryptError(ref e) => write!(f, "authentication error: {}", e),
+            AuthenticationError::DatabaseError(ref e) => write!(f, "authentication error: {}", e),
+            AuthenticationError::EmailError(ref e) => write!(f, "authentication error: {}", e),
+        }
     }
 }
 
 // This is important for other errors to wrap this one.
 impl error::Error for AuthenticationError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        // Generic error, underlying cause isn't tracked.
-        None
+        match *self {
+            AuthenticationError::BcryptError(ref e) => Some(e),
+            AuthenticationError::DatabaseError(ref e) => Some(e),
+            AuthenticationError::EmailError(ref e) => Some(e),
+            _ => None,
+        }
     }
 }
 
@@ -53,7 +79,18 @@ impl From<diesel::result::Error> for AuthenticationError {
     }
 }
 
-pub use self::AuthenticationError::{IncorrectPassword, NoPasswordSet, NoUsernameSet};
+impl From<EmailError> for AuthenticationError {
+    fn from(e: EmailError) -> Self {
+        match e {
+            EmailError::Database(e) => AuthenticationError::DatabaseError(e),
+            e => AuthenticationError::EmailError(e),
+        }
+    }
+}
+
+pub use self::AuthenticationError::{
+    IncorrectPassword, NoPasswordSet, NoUsernameSet, TooManyAttempts,
+};
 
 #[derive(
     Deserialize,
@@ -73,6 +110,15 @@ pub struct User {
     pub username: String,
     pub profile_name: String,
     pub profile_image: String,
+    /// emails are sent in this language, when there are templates for it
+    pub language: String,
+}
+
+impl User {
+    /// staff and superusers can access administrative routes
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Superuser || self.role == Role::Staff
+    }
 }
 
 // user with credential
@@ -91,6 +137,7 @@ pub fn find_user(conn: &PgConnection, id: i32) -> Result<Option<User>, Authentic
             users::username,
             users::profile_name,
             users::profile_image,
+            users::language,
         ))
         .first::<User>(&*conn)
         .optional()
@@ -111,6 +158,7 @@ pub fn try_user_login(
                 users::username,
                 users::profile_name,
                 users::profile_image,
+                users::language,
             ),
             users::hashed_password,
         ))
@@ -132,54 +180,258 @@ pub fn try_user_login(
     }
 }
 
+/// Same as `try_user_login` but guarded by the per-account and per-IP failed
+/// attempts tracking. Every failure delays the next allowed attempt
+/// exponentially, and too many of them lock the account (or the IP address)
+/// for a while. The owner of a locked account is notified by email.
+pub fn try_user_login_throttled(
+    conn: &PgConnection,
+    username: &str,
+    password: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<User>, AuthenticationError> {
+    check_login_allowed(conn, username, ip_address)?;
+
+    let result = try_user_login(conn, username, password);
+    match result {
+        Ok(Some(_)) => {
+            clear_login_failures(conn, &account_key(username))?;
+        }
+        Ok(None) | Err(IncorrectPassword) => {
+            record_failed_login(conn, username, ip_address)?;
+        }
+        Err(_) => {}
+    }
+
+    result
+}
+
+/// outcome of a successful username and password check
+pub enum LoginStep {
+    Authenticated(User),
+    // two factor authentication is enabled, holds the challenge token
+    // to present to `finish_two_factor_login` along with the code.
+    TwoFactorRequired(String),
+}
+
+/// First login step, when the user enabled two factor authentication a
+/// challenge token is returned instead of the authenticated user.
+pub fn begin_user_login(
+    conn: &PgConnection,
+    username: &str,
+    password: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<LoginStep>, AuthenticationError> {
+    let user = match try_user_login_throttled(conn, username, password, ip_address)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    login_step(conn, user).map(Some)
+}
+
+/// What follows the first factor of `user`, a password or an identity
+/// provider: a challenge when two factor authentication is enabled.
+pub fn login_step(conn: &PgConnection, user: User) -> Result<LoginStep, AuthenticationError> {
+    if two_factor_enabled(conn, user.id)? {
+        let challenge = create_login_challenge(conn, user.id)?;
+        Ok(LoginStep::TwoFactorRequired(challenge))
+    } else {
+        Ok(LoginStep::Authenticated(user))
+    }
+}
+
+/// Second login step, exchange the challenge token and a TOTP (or recovery)
+/// code for the user. Wrong codes count as failed login attempts.
+pub fn finish_two_factor_login(
+    conn: &PgConnection,
+    challenge: &str,
+    code: &str,
+    ip_address: Option<&str>,
+) -> Result<Option<User>, AuthenticationError> {
+    let user = match find_login_challenge(conn, challenge)? {
+        Some(user_id) => find_user(conn, user_id)?,
+        None => None,
+    };
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    check_login_allowed(conn, &user.username, ip_address)?;
+
+    let verified = match find_user_totp(conn, user.id)? {
+        Some(totp) if totp.enabled => verify_second_factor(conn, &totp, code)?,
+        // two factor was turned off in the meantime
+        _ => true,
+    };
+
+    if verified {
+        delete_login_challenge(conn, challenge)?;
+        clear_login_failures(conn, &account_key(&user.username))?;
+        Ok(Some(user))
+    } else {
+        record_failed_login(conn, &user.username, ip_address)?;
+        Ok(None)
+    }
+}
+
+/// fails with `TooManyAttempts` while the account or the IP address is
+/// backing off or locked.
+pub fn check_login_allowed(
+    conn: &PgConnection,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<(), AuthenticationError> {
+    let account = account_key(username);
+    let ip = ip_address.map(ip_key);
+
+    for key in std::iter::once(&account).chain(ip.as_ref()) {
+        if let Some(retry_at) = login_retry_at(conn, key)? {
+            return Err(TooManyAttempts(retry_at));
+        }
+    }
+
+    Ok(())
+}
+
+/// count a failed login attempt against the account and the IP address,
+/// locking them when they reached their limit.
+pub fn record_failed_login(
+    conn: &PgConnection,
+    username: &str,
+    ip_address: Option<&str>,
+) -> Result<(), AuthenticationError> {
+    let account = account_key(username);
+    if let Some((failed_count, locked_until)) =
+        record_login_failure(conn, &account, MAX_ACCOUNT_FAILURES)?
+    {
+        lock_account(conn, username, &account, failed_count, locked_until)?;
+    }
+
+    if let Some(ip) = ip_address.map(ip_key) {
+        if let Some((failed_count, locked_until)) =
+            record_login_failure(conn, &ip, MAX_IP_FAILURES)?
+        {
+            NewAccountLockout {
+                user_id: None,
+                scope: SCOPE_IP,
+                throttle_key: &ip,
+                failed_count,
+                locked_until,
+            }
+            .insert_lockout(conn)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn lock_account(
+    conn: &PgConnection,
+    username: &str,
+    key: &str,
+    failed_count: i32,
+    locked_until: NaiveDateTime,
+) -> Result<(), AuthenticationError> {
+    let user = users::table
+        .filter(users::username.eq(username))
+        .select((users::id, users::language))
+        .first::<(i32, String)>(conn)
+        .optional()?;
+    let user_id = user.as_ref().map(|(id, _)| *id);
+
+    NewAccountLockout {
+        user_id,
+        scope: SCOPE_ACCOUNT,
+        throttle_key: key,
+        failed_count,
+        locked_until,
+    }
+    .insert_lockout(conn)?;
+
+    if let Some((user_id, language)) = user {
+        if let Some(email) = user_email(conn, user_id)? {
+            crate::email::send_account_locked_email(
+                conn,
+                &email,
+                username,
+                &language,
+                &locked_until,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn register_user(
     conn: &PgConnection,
     username: &str,
     email: &str,
     password: &str,
     role: &Role,
+    language: &str,
 ) -> Result<User, AuthenticationError> {
     let hashed_password = bcrypt_hash(password, DEFAULT_COST)?;
 
     conn.transaction(|| {
-        let user = insert_into(users::table)
-            .values((
-                users::username.eq(username),
-                users::role.eq(role),
-                users::hashed_password.eq(hashed_password),
-                users::profile_name.eq(""),
-                users::profile_image.eq(""),
-            ))
-            .returning((
-                users::id,
-                users::role,
-                users::username,
-                users::profile_name,
-                users::profile_image,
-            ))
-            .get_result::<User>(&*conn)
-            .map_err(AuthenticationError::DatabaseError)?;
-
-        let new_email = NewEmail {
-            email: email,
-            user_id: user.id,
-        };
-
-        let token = insert_into(emails::table)
-            .values(&new_email)
-            .on_conflict_do_nothing()
-            .returning(emails::token)
-            .get_result::<String>(&*conn)
-            .optional()?;
+        let (user, token) = insert_user(conn, username, email, &hashed_password, role, language)?;
 
         if let Some(token) = token {
-            crate::email::send_user_confirm_email(email, username, &token);
+            crate::email::send_user_confirm_email(conn, email, username, language, &token)?;
         }
 
         Ok(user)
     })
 }
 
+/// Insert the user and its email address, returns the email confirmation
+/// token unless the address is already taken. Must be called in a transaction.
+pub(crate) fn insert_user(
+    conn: &PgConnection,
+    username: &str,
+    email: &str,
+    hashed_password: &str,
+    role: &Role,
+    language: &str,
+) -> Result<(User, Option<String>), AuthenticationError> {
+    let user = insert_into(users::table)
+        .values((
+            users::username.eq(username),
+            users::role.eq(role),
+            users::hashed_password.eq(hashed_password),
+            users::profile_name.eq(""),
+            users::profile_image.eq(""),
+            users::language.eq(language),
+        ))
+        .returning((
+            users::id,
+            users::role,
+            users::username,
+            users::profile_name,
+            users::profile_image,
+            users::language,
+        ))
+        .get_result::<User>(&*conn)
+        .map_err(AuthenticationError::DatabaseError)?;
+
+    let new_email = NewEmail {
+        email: email,
+        user_id: user.id,
+    };
+
+    let bouncing = is_suppressed(conn, email)?;
+    let token = insert_into(emails::table)
+        .values((&new_email, emails::bouncing.eq(bouncing)))
+        .on_conflict_do_nothing()
+        .returning(emails::token)
+        .get_result::<String>(&*conn)
+        .optional()?;
+
+    Ok((user, token))
+}
+
 pub fn regenerate_email_token_and_send(
     conn: &PgConnection,
     user_id: i32,
@@ -196,7 +448,13 @@ pub fn regenerate_email_token_and_send(
                 .get_result::<Email>(&*conn)
                 .map_err(AuthenticationError::DatabaseError)?;
 
-            crate::email::send_user_confirm_email(&email.email, &user.username, &email.token);
+            crate::email::send_user_confirm_email(
+                conn,
+                &email.email,
+                &user.username,
+                &user.language,
+                &email.token,
+            )?;
 
             Ok(true)
         } else {
@@ -212,12 +470,30 @@ pub fn verify_email_with_token(
 ) -> Result<bool, AuthenticationError> {
     use diesel::update;
 
-    let updated_rows = update(emails::table.filter(emails::token.eq(token)))
-        .set(emails::verified.eq(true))
-        .execute(&*conn)
-        .map_err(AuthenticationError::DatabaseError)?;
+    conn.transaction(|| {
+        let email = emails::table
+            .filter(emails::token.eq(token))
+            .first::<Email>(conn)
+            .optional()?;
+        let email = match email {
+            Some(email) => email,
+            None => return Ok(false),
+        };
 
-    Ok(updated_rows > 0)
+        update(&email)
+            .set(emails::verified.eq(true))
+            .execute(&*conn)
+            .map_err(AuthenticationError::DatabaseError)?;
+        if !email.verified {
+            queue_event(
+                conn,
+                email.user_id,
+                "user.email_verified",
+                &json!({ "user_id": email.user_id, "email": email.email }),
+            )?;
+        }
+        Ok(true)
+    })
 }
 
 // update a user
@@ -227,6 +503,7 @@ pub struct UserChanges {
     pub role: Option<Role>,
     pub profile_name: Option<String>,
     pub profile_image: Option<String>,
+    pub language: Option<String>,
 }
 
 // update a user
@@ -240,7 +517,7 @@ pub fn update_user(
 
     let user = update(users.find(user_id))
         .set(user)
-        .returning((id, role, username, profile_name, profile_image))
+        .returning((id, role, username, profile_name, profile_image, language))
         .get_result::<User>(&*conn)
         .map_err(AuthenticationError::DatabaseError)?;
 
@@ -271,3 +548,60 @@ pub fn user_email(
         .optional()
         .map_err(AuthenticationError::DatabaseError)
 }
+
+pub fn find_user_by_username(
+    conn: &PgConnection,
+    name: &str,
+) -> Result<Option<User>, AuthenticationError> {
+    users::table
+        .filter(users::username.eq(name))
+        .select((
+            users::id,
+            users::role,
+            users::username,
+            users::profile_name,
+            users::profile_image,
+            users::language,
+        ))
+        .first::<User>(conn)
+        .optional()
+        .map_err(AuthenticationError::DatabaseError)
+}
+
+/// replace the user password, returns false when there is no such user
+pub fn set_user_password(
+    conn: &PgConnection,
+    user_id: i32,
+    password: &str,
+) -> Result<bool, AuthenticationError> {
+    use diesel::update;
+
+    let hashed_password = bcrypt_hash(password, DEFAULT_COST)?;
+    let updated_rows = update(users::table.find(user_id))
+        .set(users::hashed_password.eq(hashed_password))
+        .execute(conn)?;
+
+    Ok(updated_rows > 0)
+}
+
+/// a user with its email address and whether it's verified
+pub type UserWithEmail = (User, Option<(String, bool)>);
+
+pub fn list_users(conn: &PgConnection) -> Result<Vec<UserWithEmail>, AuthenticationError> {
+    users::table
+        .left_join(emails::table)
+        .select((
+            (
+                users::id,
+                users::role,
+                users::username,
+                users::profile_name,
+                users::profile_image,
+                users::language,
+            ),
+            (emails::email, emails::verified).nullable(),
+        ))
+        .order(users::id)
+        .load::<UserWithEmail>(conn)
+        .map_err(AuthenticationError::DatabaseError)
+}
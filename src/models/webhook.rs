@@ -0,0 +1,362 @@
+use std::cmp::min;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::{BigInt, Double};
+use diesel::{self, delete, insert_into, sql_query, update};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::models::user::User;
+use crate::schema::{webhook_deliveries, webhooks};
+
+/// The events webhooks can subscribe to, `*` subscribes to all of them.
+pub const EVENTS: &[&str] = &[
+    "client.created",
+    "client.updated",
+    "client.deleted",
+    "company.created",
+    "company.updated",
+    "company.deleted",
+    "user.email_verified",
+];
+pub const ALL_EVENTS: &str = "*";
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_DELIVERED: &str = "delivered";
+// gave up after `webhooks.max_attempts` failed deliveries
+pub const STATUS_FAILED: &str = "failed";
+
+const SECRET_LEN: usize = 32;
+
+// first delay before retrying a failed delivery, doubled on every failure
+// rows per INSERT, Postgres takes up to 65535 bind parameters
+const INSERT_BATCH: usize = 1000;
+const RETRY_BASE_SECONDS: i64 = 30;
+const MAX_RETRY_SECONDS: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Queryable, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(User)]
+pub struct Webhook {
+    pub id: i32,
+    pub user_id: i32,
+    pub url: String,
+    /// key of the `X-Lako-Signature` HMAC, only shown once on creation
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Webhook {
+    pub fn subscribes_to(&self, event: &str) -> bool {
+        self.events.iter().any(|e| e == event || e == ALL_EVENTS)
+    }
+}
+
+#[derive(Debug, Queryable, QueryableByName, Identifiable, Associations, Serialize, Deserialize)]
+#[belongs_to(Webhook)]
+#[table_name = "webhook_deliveries"]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    /// the JSON body posted to the webhook
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    /// of the last attempt, when the endpoint answered
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "webhooks"]
+struct NewWebhook<'a> {
+    user_id: i32,
+    url: &'a str,
+    secret: &'a str,
+    events: &'a [String],
+}
+
+#[derive(Debug, AsChangeset, Deserialize)]
+#[table_name = "webhooks"]
+pub struct WebhookChanges {
+    pub url: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub active: Option<bool>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "webhook_deliveries"]
+struct NewWebhookDelivery<'a> {
+    webhook_id: i32,
+    event: &'a str,
+    payload: &'a str,
+}
+
+fn random_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Subscribe `url` to `events` on behalf of `owner_id`, the deliveries are
+/// signed with a new random secret.
+pub fn create_webhook(
+    conn: &PgConnection,
+    owner_id: i32,
+    url: &str,
+    events: &[String],
+) -> Result<Webhook, Error> {
+    insert_into(webhooks::table)
+        .values(&NewWebhook {
+            user_id: owner_id,
+            url,
+            secret: &random_secret(),
+            events,
+        })
+        .get_result(conn)
+}
+
+pub fn list_webhooks(conn: &PgConnection, owner_id: i32) -> Result<Vec<Webhook>, Error> {
+    webhooks::table
+        .filter(webhooks::user_id.eq(owner_id))
+        .order(webhooks::created_at.desc())
+        .load(conn)
+}
+
+pub fn find_webhook(
+    conn: &PgConnection,
+    owner_id: i32,
+    webhook_id: i32,
+) -> Result<Option<Webhook>, Error> {
+    webhooks::table
+        .find(webhook_id)
+        .filter(webhooks::user_id.eq(owner_id))
+        .first(conn)
+        .optional()
+}
+
+pub fn update_webhook(
+    conn: &PgConnection,
+    owner_id: i32,
+    webhook_id: i32,
+    changes: &WebhookChanges,
+) -> Result<Option<Webhook>, Error> {
+    update(webhooks::table.find(webhook_id))
+        .filter(webhooks::user_id.eq(owner_id))
+        .set(changes)
+        .get_result(conn)
+        .optional()
+}
+
+pub fn delete_webhook(conn: &PgConnection, owner_id: i32, webhook_id: i32) -> Result<usize, Error> {
+    delete(webhooks::table.find(webhook_id))
+        .filter(webhooks::user_id.eq(owner_id))
+        .execute(conn)
+}
+
+/// Queue `event` for the active webhooks of `owner_id` subscribed to it, they
+/// are delivered by the webhook worker once the current transaction commits.
+/// The body posted is `{"event": ..., "created_at": ..., "data": data}`.
+pub fn queue_event<T: serde::Serialize>(
+    conn: &PgConnection,
+    owner_id: i32,
+    event: &str,
+    data: &T,
+) -> Result<usize, Error> {
+    queue_events(conn, owner_id, event, std::slice::from_ref(data))
+}
+
+/// `queue_event` for each of `records`, with one query for the webhooks and
+/// as few as possible for the deliveries.
+pub fn queue_events<T: serde::Serialize>(
+    conn: &PgConnection,
+    owner_id: i32,
+    event: &str,
+    records: &[T],
+) -> Result<usize, Error> {
+    let subscribed = webhooks::table
+        .filter(webhooks::user_id.eq(owner_id))
+        .filter(webhooks::active.eq(true))
+        .load::<Webhook>(conn)?
+        .into_iter()
+        .filter(|webhook| webhook.subscribes_to(event))
+        .collect::<Vec<_>>();
+    if subscribed.is_empty() || records.is_empty() {
+        return Ok(0);
+    }
+
+    let created_at = Utc::now().naive_utc();
+    let payloads = records
+        .iter()
+        .map(|data| {
+            json!({
+                "event": event,
+                "created_at": created_at,
+                "data": data,
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    let deliveries = payloads
+        .iter()
+        .flat_map(|payload| {
+            subscribed.iter().map(move |webhook| NewWebhookDelivery {
+                webhook_id: webhook.id,
+                event,
+                payload,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut queued = 0;
+    for batch in deliveries.chunks(INSERT_BATCH) {
+        queued += insert_into(webhook_deliveries::table)
+            .values(batch)
+            .execute(conn)?;
+    }
+    Ok(queued)
+}
+
+/// Take up to `limit` deliveries due, of active webhooks. They are leased for
+/// `lease_seconds` like the emails of the outbox.
+pub fn claim_due_deliveries(
+    conn: &PgConnection,
+    limit: i64,
+    lease_seconds: f64,
+) -> Result<Vec<WebhookDelivery>, Error> {
+    sql_query(
+        "UPDATE webhook_deliveries \
+         SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2) \
+         WHERE id IN ( \
+             SELECT d.id FROM webhook_deliveries d \
+             JOIN webhooks w ON w.id = d.webhook_id \
+             WHERE d.status = 'pending' AND d.next_attempt_at <= CURRENT_TIMESTAMP \
+             AND w.active \
+             ORDER BY d.next_attempt_at \
+             LIMIT $1 \
+             FOR UPDATE OF d SKIP LOCKED \
+         ) \
+         RETURNING *",
+    )
+    .bind::<BigInt, _>(limit)
+    .bind::<Double, _>(lease_seconds)
+    .load(conn)
+}
+
+pub fn mark_delivery_succeeded(
+    conn: &PgConnection,
+    delivery_id: i32,
+    response: i32,
+) -> Result<usize, Error> {
+    use crate::schema::webhook_deliveries::dsl::*;
+
+    update(webhook_deliveries.find(delivery_id))
+        .set((
+            status.eq(STATUS_DELIVERED),
+            attempts.eq(attempts + 1),
+            response_status.eq(response),
+            last_error.eq(None::<String>),
+            delivered_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+}
+
+// 30 seconds, 1, 2, 4... minutes, capped at MAX_RETRY_SECONDS
+fn retry_delay(failed_attempts: i32) -> Duration {
+    let exponent = min(failed_attempts.max(1) - 1, 16) as u32;
+    Duration::seconds(min(
+        RETRY_BASE_SECONDS * 2i64.pow(exponent),
+        MAX_RETRY_SECONDS,
+    ))
+}
+
+/// Record a failed delivery and schedule the next attempt, returns `true`
+/// when giving up on it instead.
+pub fn mark_delivery_failed(
+    conn: &PgConnection,
+    delivery: &WebhookDelivery,
+    response: Option<i32>,
+    error: &str,
+    max_attempts: i32,
+) -> Result<bool, Error> {
+    use crate::schema::webhook_deliveries::dsl::*;
+    use diesel::dsl::now;
+    use diesel::pg::expression::extensions::IntervalDsl;
+
+    let failed_attempts = delivery.attempts + 1;
+    let failed = failed_attempts >= max_attempts;
+    let next_status = if failed {
+        STATUS_FAILED
+    } else {
+        STATUS_PENDING
+    };
+
+    update(webhook_deliveries.find(delivery.id))
+        .set((
+            status.eq(next_status),
+            attempts.eq(failed_attempts),
+            response_status.eq(response),
+            last_error.eq(error),
+            // the database clock, as `claim_due_deliveries` compares with it
+            next_attempt_at.eq(now + retry_delay(failed_attempts).num_seconds().seconds()),
+        ))
+        .execute(conn)?;
+
+    Ok(failed)
+}
+
+/// Deliver a past event of a webhook of `owner_id` again, right away and with
+/// a fresh count of attempts, whether it failed or not.
+pub fn redeliver(
+    conn: &PgConnection,
+    owner_id: i32,
+    delivery_id: i32,
+) -> Result<Option<WebhookDelivery>, Error> {
+    use crate::schema::webhook_deliveries::dsl::*;
+    use diesel::dsl::now;
+
+    let owned = webhooks::table
+        .filter(webhooks::user_id.eq(owner_id))
+        .select(webhooks::id);
+
+    update(
+        webhook_deliveries
+            .find(delivery_id)
+            .filter(webhook_id.eq_any(owned)),
+    )
+    .set((
+        status.eq(STATUS_PENDING),
+        attempts.eq(0),
+        next_attempt_at.eq(now),
+    ))
+    .get_result(conn)
+    .optional()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(0), Duration::seconds(30));
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(4), Duration::seconds(240));
+        assert_eq!(retry_delay(12), Duration::seconds(MAX_RETRY_SECONDS));
+        assert_eq!(retry_delay(i32::MAX), Duration::seconds(MAX_RETRY_SECONDS));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error as ThisError;
+use url::Url;
+
+use crate::config::config;
+
+const VERIFIER_LEN: usize = 64;
+const NONCE_LEN: usize = 32;
+// the sign in callback waits on the provider, don't let it hang
+const HTTP_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(ThisError, Debug)]
+pub enum OidcError {
+    #[error("HTTP error: `{0}`")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Invalid URL: `{0}`")]
+    Url(#[from] url::ParseError),
+
+    #[error("Invalid ID token: `{0}`")]
+    Token(#[from] jsonwebtoken::errors::Error),
+
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(&'static str),
+
+    #[error("Issuer mismatch, expected `{expected}` got `{found}`")]
+    IssuerMismatch { expected: String, found: String },
+}
+
+pub use crate::config::OidcProvider;
+
+/// The provider configured in `oidc.providers` with that name.
+pub fn find_provider(name: &str) -> Option<OidcProvider> {
+    config()
+        .oidc
+        .providers
+        .iter()
+        .find(|provider| provider.name == name)
+        .map(|provider| OidcProvider {
+            issuer: provider.issuer.trim_end_matches('/').to_string(),
+            ..provider.clone()
+        })
+}
+
+/// the subset of the discovery document we need
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+// discovery documents and key sets, keyed by issuer
+static METADATA_CACHE: Lazy<Mutex<HashMap<String, ProviderMetadata>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static JWKS_CACHE: Lazy<Mutex<HashMap<String, JwkSet>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECONDS))
+        .user_agent("Lako")
+        .build()
+        .expect("failed to build the OIDC HTTP client")
+});
+
+/// fetch (and cache) the provider discovery document
+pub async fn discover(provider: &OidcProvider) -> Result<ProviderMetadata, OidcError> {
+    if let Some(metadata) = METADATA_CACHE.lock().unwrap().get(&provider.issuer) {
+        return Ok(metadata.clone());
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+    let metadata = HTTP_CLIENT
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ProviderMetadata>()
+        .await?;
+
+    if metadata.issuer.trim_end_matches('/') != provider.issuer {
+        return Err(OidcError::IssuerMismatch {
+            expected: provider.issuer.clone(),
+            found: metadata.issuer,
+        });
+    }
+
+    METADATA_CACHE
+        .lock()
+        .unwrap()
+        .insert(provider.issuer.clone(), metadata.clone());
+
+    Ok(metadata)
+}
+
+async fn fetch_jwks(metadata: &ProviderMetadata, refresh: bool) -> Result<JwkSet, OidcError> {
+    if !refresh {
+        if let Some(jwks) = JWKS_CACHE.lock().unwrap().get(&metadata.issuer) {
+            return Ok(jwks.clone());
+        }
+    }
+
+    let jwks = HTTP_CLIENT
+        .get(&metadata.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?;
+
+    JWKS_CACHE
+        .lock()
+        .unwrap()
+        .insert(metadata.issuer.clone(), jwks.clone());
+
+    Ok(jwks)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// PKCE verifier and the nonce bound to one authorization request
+pub struct AuthorizationRequest {
+    pub code_verifier: String,
+    pub nonce: String,
+}
+
+impl AuthorizationRequest {
+    pub fn new() -> Self {
+        AuthorizationRequest {
+            code_verifier: random_string(VERIFIER_LEN),
+            nonce: random_string(NONCE_LEN),
+        }
+    }
+
+    // S256 code challenge, RFC 7636
+    fn code_challenge(&self) -> String {
+        base64::encode_config(
+            Sha256::digest(self.code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// the URL to send the user to, `state` is the CSRF token we stored
+    pub fn authorization_url(
+        &self,
+        provider: &OidcProvider,
+        metadata: &ProviderMetadata,
+        state: &str,
+    ) -> Result<String, OidcError> {
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", provider.redirect_url.as_str()),
+                ("scope", provider.scopes.as_str()),
+                ("state", state),
+                ("nonce", self.nonce.as_str()),
+                ("code_challenge", self.code_challenge().as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.into())
+    }
+}
+
+impl Default for AuthorizationRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// claims of a verified ID token
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Exchange the authorization code for tokens and verify the ID token
+/// signature against the provider JWKS, its issuer, audience, expiration
+/// and the nonce we sent with the authorization request.
+pub async fn exchange_code(
+    provider: &OidcProvider,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let metadata = discover(provider).await?;
+
+    let tokens = HTTP_CLIENT
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_url.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+
+    let claims = verify_id_token(provider, &metadata, &tokens.id_token).await?;
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidToken("nonce mismatch"));
+    }
+
+    Ok(claims)
+}
+
+async fn verify_id_token(
+    provider: &OidcProvider,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(id_token)?;
+    match header.alg {
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => {}
+        _ => return Err(OidcError::InvalidToken("unsupported signing algorithm")),
+    }
+
+    // an unknown key id probably means the provider rotated its keys
+    let mut jwks = fetch_jwks(metadata, false).await?;
+    if find_jwk(&jwks, header.kid.as_deref()).is_none() {
+        jwks = fetch_jwks(metadata, true).await?;
+    }
+    let jwk = find_jwk(&jwks, header.kid.as_deref())
+        .ok_or(OidcError::InvalidToken("no matching signing key"))?;
+
+    let (n, e) = match (&jwk.n, &jwk.e) {
+        (Some(n), Some(e)) => (n, e),
+        _ => return Err(OidcError::InvalidToken("malformed signing key")),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+
+    let data = decode::<IdTokenClaims>(
+        id_token,
+        &DecodingKey::from_rsa_components(n, e)?,
+        &validation,
+    )?;
+
+    Ok(data.claims)
+}
+
+fn find_jwk<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    jwks.keys
+        .iter()
+        .filter(|key| key.kty == "RSA")
+        .find(|key| kid.is_none() || key.kid.as_deref() == kid)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,267 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use chrono::Utc;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+
+use super::*;
+
+const ISSUER_KEY: &str = include_str!("testdata/issuer_key.pem");
+const OTHER_KEY: &str = include_str!("testdata/other_key.pem");
+const JWKS: &str = include_str!("testdata/jwks.json");
+const CLIENT_ID: &str = "lako-test";
+const CODE: &str = "the-code";
+
+// A local issuer serving the discovery document, its key set and a token
+// endpoint handing out `id_token` for `CODE`, as long as the PKCE verifier
+// matches `code_challenge`.
+struct MockIssuer {
+    issuer: String,
+    id_token: Arc<Mutex<String>>,
+}
+
+impl MockIssuer {
+    fn start(code_challenge: String) -> MockIssuer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        })
+        .to_string();
+        let id_token = Arc::new(Mutex::new(String::new()));
+        let token = id_token.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let (path, body) = read_request(&mut stream);
+                let (status, response) = match path.as_str() {
+                    "/.well-known/openid-configuration" => ("200 OK", discovery.clone()),
+                    "/jwks" => ("200 OK", JWKS.to_string()),
+                    "/token" => token_response(&body, &code_challenge, &token.lock().unwrap()),
+                    _ => ("404 Not Found", "{}".to_string()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                );
+            }
+        });
+
+        MockIssuer { issuer, id_token }
+    }
+
+    fn provider(&self) -> OidcProvider {
+        OidcProvider {
+            name: "mock".to_string(),
+            issuer: self.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "http://localhost/api/v1/oidc/mock/callback".to_string(),
+            scopes: "openid email".to_string(),
+        }
+    }
+}
+
+// the path and body of a request, enough for the mock endpoints
+fn read_request(stream: &mut TcpStream) -> (String, String) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    (path, String::from_utf8(body).unwrap())
+}
+
+fn token_response(body: &str, code_challenge: &str, id_token: &str) -> (&'static str, String) {
+    let form = url::form_urlencoded::parse(body.as_bytes()).collect::<HashMap<_, _>>();
+    let verifier = form.get("code_verifier").map(|v| v.to_string());
+    let challenge = verifier
+        .map(|v| base64::encode_config(Sha256::digest(v.as_bytes()), base64::URL_SAFE_NO_PAD));
+
+    if form.get("grant_type").map(|v| v.as_ref()) != Some("authorization_code")
+        || form.get("code").map(|v| v.as_ref()) != Some(CODE)
+        || form.get("client_id").map(|v| v.as_ref()) != Some(CLIENT_ID)
+        || challenge.as_deref() != Some(code_challenge)
+    {
+        return (
+            "400 Bad Request",
+            json!({"error": "invalid_grant"}).to_string(),
+        );
+    }
+
+    ("200 OK", json!({ "id_token": id_token }).to_string())
+}
+
+fn claims(issuer: &str, nonce: &str) -> Value {
+    json!({
+        "iss": issuer,
+        "aud": CLIENT_ID,
+        "sub": "user-1",
+        "email": "ann@example.com",
+        "email_verified": true,
+        "nonce": nonce,
+        "iat": Utc::now().timestamp(),
+        "exp": Utc::now().timestamp() + 300,
+    })
+}
+
+fn sign(claims: &Value, key: &str) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("test-key".to_string());
+    encode(
+        &header,
+        claims,
+        &EncodingKey::from_rsa_pem(key.as_bytes()).unwrap(),
+    )
+    .unwrap()
+}
+
+// run the whole exchange against a mock issuer, `customize` edits the ID
+// token claims before they are signed with `key`
+async fn sign_in<F>(
+    key: &str,
+    customize: F,
+    verifier: Option<&str>,
+    nonce: Option<&str>,
+) -> Result<IdTokenClaims, OidcError>
+where
+    F: FnOnce(&mut Value),
+{
+    let request = AuthorizationRequest::new();
+    let mock = MockIssuer::start(request.code_challenge());
+
+    let mut token_claims = claims(&mock.issuer, &request.nonce);
+    customize(&mut token_claims);
+    *mock.id_token.lock().unwrap() = sign(&token_claims, key);
+
+    exchange_code(
+        &mock.provider(),
+        CODE,
+        verifier.unwrap_or(&request.code_verifier),
+        nonce.unwrap_or(&request.nonce),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn signs_in_with_a_valid_id_token() {
+    let claims = sign_in(ISSUER_KEY, |_| {}, None, None).await.unwrap();
+
+    assert_eq!(claims.sub, "user-1");
+    assert_eq!(claims.email.as_deref(), Some("ann@example.com"));
+    assert!(claims.email_verified);
+}
+
+#[tokio::test]
+async fn refuses_a_token_signed_with_another_key() {
+    let result = sign_in(OTHER_KEY, |_| {}, None, None).await;
+
+    assert!(matches!(result, Err(OidcError::Token(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn refuses_a_token_for_another_audience() {
+    let result = sign_in(ISSUER_KEY, |c| c["aud"] = json!("someone-else"), None, None).await;
+
+    assert!(matches!(result, Err(OidcError::Token(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn refuses_a_token_from_another_issuer() {
+    let result = sign_in(
+        ISSUER_KEY,
+        |c| c["iss"] = json!("https://evil.example.com"),
+        None,
+        None,
+    )
+    .await;
+
+    assert!(matches!(result, Err(OidcError::Token(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn refuses_an_expired_token() {
+    let result = sign_in(
+        ISSUER_KEY,
+        |c| c["exp"] = json!(Utc::now().timestamp() - 3600),
+        None,
+        None,
+    )
+    .await;
+
+    assert!(matches!(result, Err(OidcError::Token(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn refuses_a_token_with_another_nonce() {
+    let result = sign_in(ISSUER_KEY, |_| {}, None, Some("another-nonce")).await;
+
+    assert!(
+        matches!(result, Err(OidcError::InvalidToken("nonce mismatch"))),
+        "{:?}",
+        result
+    );
+}
+
+#[tokio::test]
+async fn fails_when_the_pkce_verifier_does_not_match() {
+    let result = sign_in(ISSUER_KEY, |_| {}, Some("another-verifier"), None).await;
+
+    assert!(matches!(result, Err(OidcError::Http(_))), "{:?}", result);
+}
+
+#[test]
+fn authorization_url_carries_the_pkce_challenge_and_nonce() {
+    let mock = MockIssuer::start(String::new());
+    let metadata = ProviderMetadata {
+        issuer: mock.issuer.clone(),
+        authorization_endpoint: format!("{}/authorize", mock.issuer),
+        token_endpoint: format!("{}/token", mock.issuer),
+        jwks_uri: format!("{}/jwks", mock.issuer),
+    };
+    let request = AuthorizationRequest::new();
+
+    let url = request
+        .authorization_url(&mock.provider(), &metadata, "csrf")
+        .unwrap();
+    let url = Url::parse(&url).unwrap();
+    let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+
+    assert_eq!(params["state"], "csrf");
+    assert_eq!(params["nonce"], request.nonce);
+    assert_eq!(params["code_challenge"], request.code_challenge());
+    assert_eq!(params["code_challenge_method"], "S256");
+}
@@ -0,0 +1,88 @@
+use diesel::result::Error as DieselError;
+use log::{error, info, warn};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Outbox;
+use crate::db::Repo;
+use crate::email::send_email;
+use crate::models::outbox::{claim_due_emails, mark_email_failed, mark_email_sent};
+use crate::models::suppression::is_suppressed;
+
+// time a claimed email is hidden from other workers, more than enough for
+// a delivery to go through or time out
+const LEASE_SECONDS: f64 = 300.0;
+
+/// deliver the emails due, returns how many were claimed
+pub fn deliver_due_emails(repo: &Repo, config: &Outbox) -> Result<usize, DieselError> {
+    let conn = repo.connection()?;
+    let emails = claim_due_emails(&conn, config.batch_size, LEASE_SECONDS)?;
+
+    for email in &emails {
+        // queued before the address bounced
+        if is_suppressed(&conn, &email.recipient)? {
+            mark_email_failed(&conn, email, "The address is suppressed", 0)?;
+            warn!(
+                "Not delivering email {} to suppressed `{}`",
+                email.id, email.recipient
+            );
+            continue;
+        }
+
+        match send_email(
+            &email.recipient,
+            &email.subject,
+            &email.body,
+            email.html_body.as_deref(),
+        ) {
+            Ok(()) => {
+                mark_email_sent(&conn, email.id)?;
+            }
+            Err(e) => {
+                let dead = mark_email_failed(&conn, email, &e.to_string(), config.max_attempts)?;
+                if dead {
+                    error!(
+                        "Giving up on email {} to `{}` after {} attempts: {}",
+                        email.id,
+                        email.recipient,
+                        email.attempts + 1,
+                        e
+                    );
+                } else {
+                    warn!(
+                        "Failed to deliver email {} to `{}`, will retry: {}",
+                        email.id, email.recipient, e
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(emails.len())
+}
+
+/// Deliver the emails queued in the `email_outbox` table in the background.
+///
+/// Several instances can run the worker, an email is only claimed by one
+/// of them. Failed deliveries are retried with an exponential backoff until
+/// `max_attempts`, then the email is dead-lettered, see the admin outbox
+/// routes to retry those.
+pub fn spawn_outbox_worker(repo: Repo, config: Outbox) -> thread::JoinHandle<()> {
+    info!("Starting the email outbox worker");
+
+    thread::Builder::new()
+        .name("outbox-worker".into())
+        .spawn(move || loop {
+            let wait = match deliver_due_emails(&repo, &config) {
+                // there may be more due right away
+                Ok(claimed) if claimed as i64 >= config.batch_size => continue,
+                Ok(_) => config.poll_interval,
+                Err(e) => {
+                    error!("Failed to process the email outbox: {}", e);
+                    config.poll_interval
+                }
+            };
+            thread::sleep(Duration::from_secs(wait));
+        })
+        .expect("failed to spawn the outbox worker")
+}
@@ -0,0 +1,231 @@
+use chrono::Duration;
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::{create_empty_response, create_response};
+use gotham::hyper::header::CONTENT_DISPOSITION;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::{error, info};
+use std::pin::Pin;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::config::config;
+use crate::db::Repo;
+use crate::models::account::{
+    cancel_deletion, find_deletion, find_export_archive, list_exports, request_export,
+    schedule_deletion,
+};
+use crate::routes::paths::TokenPath;
+use crate::routes::utils::{
+    json_response, json_response_bad_message, json_response_not_found, json_response_ok,
+};
+
+/// serve POST /api/v1/me/export
+/// The export is built in the background, its download link is emailed to
+/// the user once it's ready.
+pub fn request_export_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| request_export(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(export) => {
+                let res = json_response(&state, &export, StatusCode::ACCEPTED);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to request export: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to export your data.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/me/exports
+pub fn list_exports_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| list_exports(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(exports) => {
+                let res = json_response_ok(&state, &exports);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to list exports: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get exports".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/account/exports/:token
+/// the ZIP of an export, the token of the emailed link is the credential
+pub fn download_export_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = TokenPath::borrow_from(&state).token.clone();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| find_export_archive(&conn, &token))
+            .await;
+
+        match result {
+            Ok(Some((archive, completed_at))) => {
+                let content_type = "application/zip".parse().unwrap();
+                let mut res = create_response(&state, StatusCode::OK, content_type, archive);
+                let disposition = format!(
+                    "attachment; filename=\"lako-export-{}.zip\"",
+                    completed_at.format("%Y-%m-%d")
+                );
+                if let Ok(value) = disposition.parse() {
+                    res.headers_mut().insert(CONTENT_DISPOSITION, value);
+                }
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get export: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the export.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/me/deletion
+pub fn get_deletion_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| find_deletion(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(Some(deletion)) => {
+                let res = json_response_ok(&state, &deletion);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get deletion: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the deletion.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/me/deletion
+/// The account is deleted with all its records after
+/// `accounts.deletion_grace_days`, until then the deletion can be cancelled.
+pub fn schedule_deletion_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+    let grace = Duration::days(config().accounts.deletion_grace_days);
+
+    async move {
+        let result = repo
+            .run(move |conn| schedule_deletion(&conn, current_user_id, grace))
+            .await;
+
+        match result {
+            Ok(deletion) => {
+                info!(
+                    "[{}] User {} asked to delete their account, on {}",
+                    request_id(&state),
+                    current_user_id,
+                    deletion.delete_after
+                );
+                let res = json_response(&state, &deletion, StatusCode::ACCEPTED);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to schedule deletion: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to delete your account.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/me/deletion
+/// keep the account after all
+pub fn cancel_deletion_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| cancel_deletion(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(cancelled) if cancelled > 0 => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(_) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to cancel deletion: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to cancel the deletion.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
@@ -0,0 +1,440 @@
+use diesel::PgConnection;
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::login_throttle::{clear_login_failures, find_lockout, AccountLockout};
+use crate::models::outbox::{find_outbox_email, retry_outbox_email, OutboxEmail, STATUS_SENT};
+use crate::models::suppression::{clear_suppression, EmailSuppression};
+use crate::models::user::{find_user, AuthenticationError};
+use crate::routes::paths::{OutboxQueryExtractor, PaginationExtractor, ResourceIDPath};
+use crate::routes::utils::{
+    json_response_bad_message, json_response_forbidden, json_response_not_found, json_response_ok,
+};
+use crate::sqlx::pagination::Paginate;
+
+/// returns `true` when the user behind the token is allowed to use admin routes
+fn is_admin(conn: &PgConnection, user_id: i32) -> Result<bool, AuthenticationError> {
+    Ok(matches!(find_user(conn, user_id)?, Some(user) if user.is_admin()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockoutPagination {
+    pub total_pages: i64,
+    pub results: Vec<AccountLockout>,
+}
+
+/// serve GET /api/v1/admin/lockouts
+pub fn list_lockouts_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (per_page, page, search) = {
+        let res = PaginationExtractor::take_from(&mut state);
+        (res.per_page, res.page.unwrap_or(1), res.q)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::account_lockouts;
+                use crate::schema::account_lockouts::dsl::*;
+                use diesel::prelude::*;
+
+                if !is_admin(&conn, current_user_id)? {
+                    return Ok(None);
+                }
+
+                let mut query = account_lockouts::table
+                    .order(created_at.desc())
+                    .into_boxed();
+
+                if let Some(search) = search {
+                    query = query.filter(throttle_key.ilike(format!("%{}%", search)));
+                }
+
+                let mut queryx = query.paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                queryx
+                    .load_and_count_pages::<AccountLockout>(&mut conn)
+                    .map(Some)
+                    .map_err(AuthenticationError::DatabaseError)
+            })
+            .await;
+
+        match result {
+            Ok(Some((lockouts, total_pages))) => {
+                let res = json_response_ok(
+                    &state,
+                    &LockoutPagination {
+                        total_pages,
+                        results: lockouts,
+                    },
+                );
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get lockouts: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get lockouts".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/admin/lockouts/:id
+/// lift the lockout, the failed attempts counter start from zero again
+pub fn unlock_lockout_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    let lockout_id = {
+        let res = ResourceIDPath::borrow_from(&state);
+        res.id
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| -> Result<Option<bool>, AuthenticationError> {
+                if !is_admin(&conn, current_user_id)? {
+                    return Ok(None);
+                }
+
+                match find_lockout(&conn, lockout_id)? {
+                    Some(lockout) => {
+                        clear_login_failures(&conn, &lockout.throttle_key)?;
+                        Ok(Some(true))
+                    }
+                    None => Ok(Some(false)),
+                }
+            })
+            .await;
+
+        match result {
+            Ok(Some(true)) => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(Some(false)) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to lift lockout: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to lift the lockout.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OutboxPagination {
+    pub total_pages: i64,
+    pub results: Vec<OutboxEmail>,
+}
+
+/// serve GET /api/v1/admin/outbox
+/// the queued emails, `status` filters on pending, sent or dead
+pub fn list_outbox_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (status_filter, per_page, page, search) = {
+        let res = OutboxQueryExtractor::take_from(&mut state);
+        (res.status, res.per_page, res.page.unwrap_or(1), res.q)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::email_outbox;
+                use crate::schema::email_outbox::dsl::*;
+                use diesel::prelude::*;
+
+                if !is_admin(&conn, current_user_id)? {
+                    return Ok(None);
+                }
+
+                let mut query = email_outbox::table.order(created_at.desc()).into_boxed();
+
+                if let Some(status_filter) = status_filter {
+                    query = query.filter(status.eq(status_filter));
+                }
+
+                if let Some(search) = search {
+                    query = query.filter(recipient.ilike(format!("%{}%", search)));
+                }
+
+                let mut queryx = query.paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                queryx
+                    .load_and_count_pages::<OutboxEmail>(&mut conn)
+                    .map(Some)
+                    .map_err(AuthenticationError::DatabaseError)
+            })
+            .await;
+
+        match result {
+            Ok(Some((emails, total_pages))) => {
+                let res = json_response_ok(
+                    &state,
+                    &OutboxPagination {
+                        total_pages,
+                        results: emails,
+                    },
+                );
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get outbox: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the outbox".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+enum RetryOutcome {
+    Retried(OutboxEmail),
+    AlreadySent,
+    NotFound,
+}
+
+/// serve POST /api/v1/admin/outbox/:id/retry
+/// queue a dead email again, the delivery attempts start from zero
+pub fn retry_outbox_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    let email_id = {
+        let res = ResourceIDPath::borrow_from(&state);
+        res.id
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(
+                move |conn| -> Result<Option<RetryOutcome>, AuthenticationError> {
+                    if !is_admin(&conn, current_user_id)? {
+                        return Ok(None);
+                    }
+
+                    let outcome = match find_outbox_email(&conn, email_id)? {
+                        Some(email) if email.status == STATUS_SENT => RetryOutcome::AlreadySent,
+                        Some(_) => match retry_outbox_email(&conn, email_id)? {
+                            Some(email) => RetryOutcome::Retried(email),
+                            // sent in the meantime
+                            None => RetryOutcome::AlreadySent,
+                        },
+                        None => RetryOutcome::NotFound,
+                    };
+                    Ok(Some(outcome))
+                },
+            )
+            .await;
+
+        match result {
+            Ok(Some(RetryOutcome::Retried(email))) => {
+                let res = json_response_ok(&state, &email);
+                Ok((state, res))
+            }
+            Ok(Some(RetryOutcome::AlreadySent)) => {
+                let res = json_response_bad_message(&state, "That email was already sent.".into());
+                Ok((state, res))
+            }
+            Ok(Some(RetryOutcome::NotFound)) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to retry email: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to retry the email.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SuppressionPagination {
+    pub total_pages: i64,
+    pub results: Vec<EmailSuppression>,
+}
+
+/// serve GET /api/v1/admin/suppressions
+/// the addresses emails are not sent to after a bounce or complaint
+pub fn list_suppressions_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (per_page, page, search) = {
+        let res = PaginationExtractor::take_from(&mut state);
+        (res.per_page, res.page.unwrap_or(1), res.q)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::email_suppressions;
+                use crate::schema::email_suppressions::dsl::*;
+                use diesel::prelude::*;
+
+                if !is_admin(&conn, current_user_id)? {
+                    return Ok(None);
+                }
+
+                let mut query = email_suppressions::table
+                    .order(created_at.desc())
+                    .into_boxed();
+
+                if let Some(search) = search {
+                    query = query.filter(address.ilike(format!("%{}%", search)));
+                }
+
+                let mut queryx = query.paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                queryx
+                    .load_and_count_pages::<EmailSuppression>(&mut conn)
+                    .map(Some)
+                    .map_err(AuthenticationError::DatabaseError)
+            })
+            .await;
+
+        match result {
+            Ok(Some((suppressions, total_pages))) => {
+                let res = json_response_ok(
+                    &state,
+                    &SuppressionPagination {
+                        total_pages,
+                        results: suppressions,
+                    },
+                );
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get suppressions: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get suppressions".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/admin/suppressions/:id
+/// send emails to the address again, eg. once the mailbox is fixed
+pub fn clear_suppression_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    let suppression_id = {
+        let res = ResourceIDPath::borrow_from(&state);
+        res.id
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| -> Result<Option<bool>, AuthenticationError> {
+                if !is_admin(&conn, current_user_id)? {
+                    return Ok(None);
+                }
+
+                let cleared = clear_suppression(&conn, suppression_id)?;
+                Ok(Some(cleared.is_some()))
+            })
+            .await;
+
+        match result {
+            Ok(Some(true)) => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(Some(false)) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_forbidden(&state, "Admin access required.".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to clear suppression: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to clear the suppression.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
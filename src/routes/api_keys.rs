@@ -0,0 +1,156 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+use validator::Validate;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::api_key::{create_api_key, list_api_keys, revoke_api_key, ApiKey, SCOPES};
+use crate::routes::paths::ResourceIDPath;
+use crate::routes::utils::{
+    extract_json, json_response_bad_message, json_response_created, json_response_not_found,
+    json_response_ok,
+};
+
+#[derive(Debug, Deserialize, Validate)]
+struct NewApiKeyRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+/// serve POST /api/v1/api-keys
+/// the key itself is only part of this response, it's stored hashed.
+pub fn create_api_key_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let repo = Repo::borrow_from(&state).clone();
+
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    #[derive(Serialize)]
+    struct R {
+        #[serde(flatten)]
+        api_key: ApiKey,
+        key: String,
+    }
+
+    async move {
+        let request = match extract_json::<NewApiKeyRequest>(&mut state).await {
+            Ok(request) => match request.validate() {
+                Ok(_) => request,
+                Err(e) => return Err((state, e.into())),
+            },
+            Err(e) => return Err((state, e)),
+        };
+
+        if let Some(scope) = request
+            .scopes
+            .iter()
+            .find(|scope| !SCOPES.contains(&scope.as_str()))
+        {
+            let res = json_response_bad_message(&state, format!("Unknown scope `{}`.", scope));
+            return Ok((state, res));
+        }
+
+        let result = repo
+            .run(move |conn| {
+                create_api_key(
+                    &conn,
+                    current_user_id,
+                    request.name.as_str(),
+                    &request.scopes,
+                )
+            })
+            .await;
+
+        match result {
+            Ok((api_key, key)) => {
+                let res = json_response_created(&state, &R { api_key, key });
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to create API key: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to create an API key.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/api-keys
+pub fn list_api_keys_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| list_api_keys(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(api_keys) => {
+                let res = json_response_ok(&state, &api_keys);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to list API keys: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get API keys".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/api-keys/:id
+/// revoked keys are kept so their last use can still be looked at.
+pub fn revoke_api_key_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    let key_id = {
+        let res = ResourceIDPath::borrow_from(&state);
+        res.id
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| revoke_api_key(&conn, key_id, current_user_id))
+            .await;
+
+        match result {
+            Ok(revoked_count) => {
+                if revoked_count > 0 {
+                    let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                    Ok((state, res))
+                } else {
+                    let res = json_response_not_found(&state, "That resource is not found".into());
+                    Ok((state, res))
+                }
+            }
+            Err(e) => {
+                error!("[{}] Failed to revoke API key: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to revoke the API key.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
@@ -1,20 +1,27 @@
+use chrono::{NaiveDateTime, Utc};
 use futures::future;
 use futures::prelude::*;
 use gotham::handler::HandlerFuture;
-use gotham::state::{FromState, State};
-use gotham_middleware_jwt::AuthorizationToken;
+use gotham::hyper::{Body, Response};
+use gotham::state::{client_addr, request_id, FromState, State};
+use log::error;
 use serde_derive::{Deserialize, Serialize};
 use std::pin::Pin;
 use validator::Validate;
 
-use crate::auth::{encode_token, Claims};
+use crate::auth::{encode_token, keys, AuthorizationToken, Claims};
+use crate::config::config;
 use crate::db::Repo;
+use crate::email::templates::templates;
 use crate::models::user::{
-    find_user, regenerate_email_token_and_send, register_user, try_user_login, update_user,
-    verify_email_with_token, AuthenticationError, UserChanges,
+    begin_user_login, find_user, finish_two_factor_login, regenerate_email_token_and_send,
+    register_user, update_user, verify_email_with_token, AuthenticationError, LoginStep,
+    UserChanges,
 };
 use crate::routes::paths::{ResourceIDPath, TokenPath};
-use crate::routes::utils::{extract_json, json_response_bad_message, json_response_ok};
+use crate::routes::utils::{
+    extract_json, json_response_bad_message, json_response_ok, json_response_too_many_requests,
+};
 use crate::sql_types::Role;
 
 #[derive(Debug, Deserialize, Validate)]
@@ -27,6 +34,16 @@ struct NewUser {
     password1: String,
     #[validate(length(min = 8))]
     password2: String,
+    /// language of the emails, `email.default_language` when not given
+    language: Option<String>,
+}
+
+// a language emails can be sent in
+fn unsupported_language(language: &Option<String>) -> Option<&str> {
+    match language {
+        Some(language) if !templates().languages().contains(language) => Some(language),
+        _ => None,
+    }
 }
 
 /// serve POST /api/v1/register
@@ -51,14 +68,24 @@ pub fn register_user_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
             Err(e) => return Err((state, e)),
         };
 
+        if let Some(language) = unsupported_language(&user.language) {
+            let res =
+                json_response_bad_message(&state, format!("Unsupported language `{}`.", language));
+            return Ok((state, res));
+        }
+
         let result = repo
             .run(move |conn| {
+                let language = user
+                    .language
+                    .unwrap_or_else(|| config().email.default_language.clone());
                 register_user(
                     &conn,
                     user.username.to_ascii_lowercase().as_str(),
                     user.email.to_ascii_lowercase().as_str(),
                     user.password1.as_str(),
                     &Role::Customer,
+                    &language,
                 )
             })
             .await;
@@ -83,13 +110,28 @@ struct LoginForm {
     password: String,
 }
 
+fn too_many_attempts_response(state: &State, retry_at: NaiveDateTime) -> Response<Body> {
+    let retry_after = (retry_at - Utc::now().naive_utc()).num_seconds().max(1);
+
+    json_response_too_many_requests(
+        state,
+        "too many failed login attempts, try again later".into(),
+        retry_after as u64,
+    )
+}
+
 /// serve POST /api/v1/login
+/// when the user enabled two factor authentication, a short-lived `challenge`
+/// is returned instead of the `access` token, see `login_two_factor_handler`.
 pub fn login_user_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
     let repo = Repo::borrow_from(&state).clone();
+    let ip_address = client_addr(&state).map(|addr| addr.ip().to_string());
 
     #[derive(Serialize)]
-    struct R {
-        access: String,
+    #[serde(untagged)]
+    enum R {
+        Access { access: String },
+        Challenge { challenge: String },
     }
 
     async move {
@@ -100,22 +142,111 @@ pub fn login_user_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
 
         let result = repo
             .run(move |conn| {
-                try_user_login(
+                begin_user_login(
                     &conn,
                     creds.username.to_ascii_lowercase().as_str(),
                     creds.password.as_str(),
+                    ip_address.as_deref(),
                 )
             })
             .await;
 
-        if let Ok(Some(user)) = result {
-            let token = encode_token(user.id);
-            let res = json_response_ok(&state, &R { access: token });
+        match result {
+            Ok(Some(LoginStep::Authenticated(user))) => {
+                let token = encode_token(user.id);
+                let res = json_response_ok(&state, &R::Access { access: token });
+                Ok((state, res))
+            }
+            Ok(Some(LoginStep::TwoFactorRequired(challenge))) => {
+                let res = json_response_ok(&state, &R::Challenge { challenge });
+                Ok((state, res))
+            }
+            Err(AuthenticationError::TooManyAttempts(retry_at)) => {
+                let res = too_many_attempts_response(&state, retry_at);
+                Ok((state, res))
+            }
+            Err(e @ AuthenticationError::DatabaseError(_))
+            | Err(e @ AuthenticationError::BcryptError(_)) => {
+                error!("[{}] Failed to log in: {}", request_id(&state), e);
+                let res = json_response_bad_message(&state, "invalid username or password".into());
+                Ok((state, res))
+            }
+            _ => {
+                let res = json_response_bad_message(&state, "invalid username or password".into());
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
 
-            Ok((state, res))
-        } else {
-            let res = json_response_bad_message(&state, "invalid username or password".into());
-            Ok((state, res))
+#[derive(Debug, Deserialize, Validate)]
+struct TwoFactorLoginForm {
+    #[validate(length(min = 1))]
+    challenge: String,
+    #[validate(length(min = 1))]
+    code: String,
+}
+
+/// serve POST /api/v1/login/2fa
+/// second login step, exchange the challenge and a TOTP or recovery code
+/// for the access token.
+pub fn login_two_factor_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let repo = Repo::borrow_from(&state).clone();
+    let ip_address = client_addr(&state).map(|addr| addr.ip().to_string());
+
+    #[derive(Serialize)]
+    struct R {
+        access: String,
+    }
+
+    async move {
+        let form = match extract_json::<TwoFactorLoginForm>(&mut state).await {
+            Ok(form) => form,
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| {
+                finish_two_factor_login(
+                    &conn,
+                    form.challenge.as_str(),
+                    form.code.as_str(),
+                    ip_address.as_deref(),
+                )
+            })
+            .await;
+
+        match result {
+            Ok(Some(user)) => {
+                let token = encode_token(user.id);
+                let res = json_response_ok(&state, &R { access: token });
+                Ok((state, res))
+            }
+            Err(AuthenticationError::TooManyAttempts(retry_at)) => {
+                let res = too_many_attempts_response(&state, retry_at);
+                Ok((state, res))
+            }
+            Err(e @ AuthenticationError::DatabaseError(_))
+            | Err(e @ AuthenticationError::BcryptError(_)) => {
+                error!(
+                    "[{}] Failed to log in with two factor: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "invalid or expired challenge or code".into(),
+                );
+                Ok((state, res))
+            }
+            _ => {
+                let res = json_response_bad_message(
+                    &state,
+                    "invalid or expired challenge or code".into(),
+                );
+                Ok((state, res))
+            }
         }
     }
     .boxed()
@@ -164,7 +295,8 @@ pub fn confirm_user_email(state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_ok(&state, &OkBool { ok: b });
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to confirm email: {}", request_id(&state), e);
                 let res =
                     json_response_bad_message(&state, "Email belonging to token not found.".into());
                 Ok((state, res))
@@ -200,7 +332,12 @@ pub fn regenerate_token_and_send(state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_ok(&state, &OkBool { ok: b });
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!(
+                    "[{}] Failed to resend confirmation email: {}",
+                    request_id(&state),
+                    e
+                );
                 let res =
                     json_response_bad_message(&state, "Email belonging to token not found.".into());
                 Ok((state, res))
@@ -214,6 +351,7 @@ pub fn regenerate_token_and_send(state: State) -> Pin<Box<HandlerFuture>> {
 struct UserChangeRequest {
     pub profile_name: Option<String>,
     pub profile_image: Option<String>,
+    pub language: Option<String>,
 }
 
 /// Handles `Patch /me` router
@@ -230,6 +368,12 @@ pub fn user_update_detail_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
             Err(e) => return Err((state, e)),
         };
 
+        if let Some(language) = unsupported_language(&changes.language) {
+            let res =
+                json_response_bad_message(&state, format!("Unsupported language `{}`.", language));
+            return Ok((state, res));
+        }
+
         let result = repo
             .run(move |conn| {
                 update_user(
@@ -239,6 +383,7 @@ pub fn user_update_detail_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                         role: None,
                         profile_name: changes.profile_name,
                         profile_image: changes.profile_image,
+                        language: changes.language,
                     },
                 )
             })
@@ -249,7 +394,8 @@ pub fn user_update_detail_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_ok(&state, &user);
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to update user: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to update user.".into(),
@@ -260,3 +406,10 @@ pub fn user_update_detail_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
     }
     .boxed()
 }
+
+/// serve GET /.well-known/jwks.json
+/// the public keys access tokens can be verified with
+pub fn jwks_handler(state: State) -> (State, Response<Body>) {
+    let res = json_response_ok(&state, &keys().jwks());
+    (state, res)
+}
@@ -0,0 +1,128 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::header::{HeaderMap, CONTENT_TYPE};
+use gotham::state::{request_id, FromState, State};
+use log::{error, info};
+use serde_derive::Serialize;
+use std::pin::Pin;
+use tokio::task;
+
+use crate::config::config;
+use crate::db::Repo;
+use crate::email::bounce::{parse_report, parse_webhook};
+use crate::models::suppression::suppress_address;
+use crate::routes::paths::BounceTokenExtractor;
+use crate::routes::utils::{
+    extract_bytes, json_response_bad_message, json_response_forbidden, json_response_not_found,
+    json_response_ok,
+};
+
+// bounces quote the original message, usually not all of it
+const MAX_REPORT_BYTES: usize = 10 * 1024 * 1024;
+
+// compare the tokens without leaking how much of them matched
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// serve POST /api/v1/email/bounces?token=<email.bounce_secret>
+/// A JSON body is a webhook payload of the email provider, anything else a
+/// delivery status notification or abuse report piped by the MTA. The
+/// addresses that bounced or complained are suppressed.
+pub fn receive_bounces_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = BounceTokenExtractor::take_from(&mut state).token;
+    let content_type = HeaderMap::borrow_from(&state)
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let is_json = matches!(content_type, Some(value) if value.starts_with("application/json"));
+    let repo = Repo::borrow_from(&state).clone();
+
+    #[derive(Serialize)]
+    struct R {
+        suppressed: Vec<String>,
+    }
+
+    async move {
+        let secret = match &config().email.bounce_secret {
+            Some(secret) => secret,
+            None => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                return Ok((state, res));
+            }
+        };
+        if !matches!(&token, Some(token) if same_token(token, secret)) {
+            let res = json_response_forbidden(&state, "Invalid token.".into());
+            return Ok((state, res));
+        }
+
+        let raw = match extract_bytes(&mut state, MAX_REPORT_BYTES).await {
+            Ok(raw) => raw,
+            Err(e) => return Err((state, e)),
+        };
+        // a large report takes a while to parse, off the executor
+        let reports = task::spawn_blocking(move || {
+            if is_json {
+                serde_json::from_slice(&raw)
+                    .map(|payload| parse_webhook(&payload))
+                    .map_err(|e| e.to_string())
+            } else {
+                parse_report(&raw).map_err(|e| e.to_string())
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        let reports = match reports {
+            Ok(reports) => reports,
+            Err(e) => {
+                let res = json_response_bad_message(
+                    &state,
+                    format!("That report could not be parsed: {}", e),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        let result = repo
+            .run(move |conn| {
+                reports
+                    .iter()
+                    .map(|report| suppress_address(&conn, report))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await;
+
+        match result {
+            Ok(suppressions) => {
+                for suppression in &suppressions {
+                    info!(
+                        "[{}] Suppressed `{}` after a {}: {}",
+                        request_id(&state),
+                        suppression.address,
+                        suppression.reason,
+                        suppression.detail
+                    );
+                }
+                let suppressed = suppressions.into_iter().map(|s| s.address).collect();
+                let res = json_response_ok(&state, &R { suppressed });
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to suppress addresses: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to record the bounces.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
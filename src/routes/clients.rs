@@ -2,15 +2,17 @@ use futures::prelude::*;
 use gotham::handler::HandlerFuture;
 use gotham::helpers::http::response::create_empty_response;
 use gotham::hyper::StatusCode;
-use gotham::state::{FromState, State};
-use gotham_middleware_jwt::AuthorizationToken;
+use gotham::state::{request_id, FromState, State};
+use log::error;
 use serde_derive::{Deserialize, Serialize};
 use std::pin::Pin;
 use validator::Validate;
 
-use crate::auth::Claims;
+use crate::auth::{AuthorizationToken, Claims};
 use crate::db::Repo;
-use crate::models::client::{delete_client, ChangeClient, CompactClient, NewClient};
+use crate::models::client::{client_search, delete_client, ChangeClient, CompactClient, NewClient};
+use crate::models::inbound::{with_attachments, InboundEmail};
+use crate::routes::inbound::InboundEmailPagination;
 use crate::routes::paths::{PaginationExtractor, ResourceIDPath};
 use crate::routes::utils::{
     extract_json, json_response_bad_message, json_response_created, json_response_not_found,
@@ -19,7 +21,7 @@ use crate::routes::utils::{
 use crate::sqlx::pagination::Paginate;
 
 #[derive(Debug, Deserialize, Validate)]
-struct NewClientRequest {
+pub(crate) struct NewClientRequest {
     pub name: String,
     #[validate(email)]
     pub email: String,
@@ -34,6 +36,25 @@ struct NewClientRequest {
     pub notes: String,
 }
 
+impl NewClientRequest {
+    pub(crate) fn into_new_client(self, user_id: i32) -> NewClient {
+        NewClient {
+            user_id,
+            name: self.name,
+            email: self.email,
+            company_name: self.company_name,
+            address_1: self.address_1,
+            address_2: self.address_2,
+            city: self.city,
+            state: self.state,
+            zip_code: self.zip_code,
+            country: self.country,
+            notes: self.notes,
+            website: self.website,
+        }
+    }
+}
+
 /// serve POST /api/v1/clients
 /// this route create a client for logged in user
 pub fn create_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
@@ -53,21 +74,9 @@ pub fn create_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
 
         let result = repo
             .run(move |conn| {
-                let new_client = NewClient {
-                    user_id: current_user_id,
-                    name: new_client.name,
-                    email: new_client.email,
-                    company_name: new_client.company_name,
-                    address_1: new_client.address_1,
-                    address_2: new_client.address_2,
-                    city: new_client.city,
-                    state: new_client.state,
-                    zip_code: new_client.zip_code,
-                    country: new_client.country,
-                    notes: new_client.notes,
-                    website: new_client.website,
-                };
-                new_client.insert_client(&conn)
+                new_client
+                    .into_new_client(current_user_id)
+                    .insert_client(&conn)
             })
             .await;
 
@@ -76,7 +85,8 @@ pub fn create_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_created(&state, &client);
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to insert client: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to insert client.".into(),
@@ -114,7 +124,8 @@ pub fn update_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_ok(&state, &client);
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to update client: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to update client.".into(),
@@ -152,7 +163,8 @@ pub fn delete_client_handler(state: State) -> Pin<Box<HandlerFuture>> {
                     Ok((state, res))
                 }
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to delete client: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to update client.".into(),
@@ -194,7 +206,7 @@ pub fn list_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                     .into_boxed();
 
                 if let Some(search) = search {
-                    query = query.filter(name.ilike(format!("{}%", search)));
+                    query = query.filter(client_search(&search));
                 }
 
                 let mut queryx = query.paginate(page);
@@ -219,7 +231,8 @@ pub fn list_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 );
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to list clients: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to get clients".into(),
@@ -230,3 +243,85 @@ pub fn list_client_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
     }
     .boxed()
 }
+
+/// serve GET /api/v1/clients/:id/timeline
+/// the emails received from the client, newest first
+pub fn client_timeline_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let owner_client_id = ResourceIDPath::borrow_from(&state).id;
+    let (per_page, page) = {
+        let res = PaginationExtractor::take_from(&mut state);
+        (res.per_page, res.page.unwrap_or(1))
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::clients;
+                use crate::schema::inbound_emails;
+                use crate::schema::inbound_emails::dsl::*;
+                use diesel::prelude::*;
+
+                let client = clients::table
+                    .find(owner_client_id)
+                    .filter(clients::user_id.eq(current_user_id))
+                    .select(clients::id)
+                    .first::<i32>(&conn)
+                    .optional()?;
+                if client.is_none() {
+                    return Ok(None);
+                }
+
+                let mut queryx = inbound_emails::table
+                    .order(received_at.desc())
+                    .filter(client_id.eq(owner_client_id))
+                    .into_boxed()
+                    .paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                let (emails, total_pages) =
+                    queryx.load_and_count_pages::<InboundEmail>(&mut conn)?;
+                Ok::<_, diesel::result::Error>(Some((
+                    with_attachments(&conn, emails)?,
+                    total_pages,
+                )))
+            })
+            .await;
+
+        match result {
+            Ok(Some((emails, total_pages))) => {
+                let res = json_response_ok(
+                    &state,
+                    &InboundEmailPagination {
+                        total_pages,
+                        results: emails,
+                    },
+                );
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to get client timeline: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the timeline".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
@@ -2,16 +2,18 @@ use futures::prelude::*;
 use gotham::handler::HandlerFuture;
 use gotham::helpers::http::response::create_empty_response;
 use gotham::hyper::StatusCode;
-use gotham::state::{FromState, State};
-use gotham_middleware_jwt::AuthorizationToken;
+use gotham::state::{request_id, FromState, State};
+use log::error;
 use serde_derive::{Deserialize, Serialize};
 use std::pin::Pin;
 use validator::Validate;
 
-use crate::auth::Claims;
+use crate::auth::{AuthorizationToken, Claims};
 use crate::db::Repo;
 
-use crate::models::company::{delete_company, ChangeCompany, CompactCompany, NewCompany};
+use crate::models::company::{
+    company_search, delete_company, ChangeCompany, CompactCompany, NewCompany,
+};
 use crate::routes::paths::{PaginationExtractor, ResourceIDPath};
 use crate::routes::utils::{
     extract_json, json_response_bad_message, json_response_created, json_response_not_found,
@@ -68,7 +70,8 @@ pub fn create_company_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_created(&state, &company);
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to insert company: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to insert a company.".into(),
@@ -106,7 +109,8 @@ pub fn update_company_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 let res = json_response_ok(&state, &client);
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to update company: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to update company.".into(),
@@ -144,7 +148,8 @@ pub fn delete_company_handler(state: State) -> Pin<Box<HandlerFuture>> {
                     Ok((state, res))
                 }
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to delete company: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to update company.".into(),
@@ -186,7 +191,7 @@ pub fn list_company_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                     .into_boxed();
 
                 if let Some(search) = search {
-                    query = query.filter(name.ilike(format!("{}%", search)));
+                    query = query.filter(company_search(&search));
                 }
 
                 let mut queryx = query.paginate(page);
@@ -211,7 +216,8 @@ pub fn list_company_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
                 );
                 Ok((state, res))
             }
-            Err(_) => {
+            Err(e) => {
+                error!("[{}] Failed to list companies: {}", request_id(&state), e);
                 let res = json_response_bad_message(
                     &state,
                     "Unexpected error detected when trying to get companies".into(),
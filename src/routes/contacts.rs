@@ -0,0 +1,205 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::Deserialize;
+use std::pin::Pin;
+use validator::Validate;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::contact::{
+    create_contact, delete_contact, list_contacts, ChangeContact, NewContact,
+};
+use crate::routes::paths::{ClientContactPath, ResourceIDPath};
+use crate::routes::utils::{
+    extract_json, json_response_bad_message, json_response_created, json_response_not_found,
+    json_response_ok,
+};
+
+#[derive(Debug, Deserialize, Validate)]
+struct NewContactRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    #[serde(default)]
+    #[validate(length(max = 255))]
+    pub title: String,
+    #[serde(default)]
+    #[validate(email)]
+    pub email: Option<String>,
+    #[serde(default)]
+    #[validate(length(max = 64))]
+    pub phone: String,
+    #[serde(default)]
+    pub is_primary: bool,
+}
+
+/// serve GET /api/v1/clients/:id/contacts
+/// the primary contact comes first
+pub fn list_contacts_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let client_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| list_contacts(&conn, current_user_id, client_id))
+            .await;
+
+        match result {
+            Ok(Some(contacts)) => {
+                let res = json_response_ok(&state, &contacts);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to list contacts: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get contacts".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/clients/:id/contacts
+/// the first contact of a client becomes its primary one
+pub fn create_contact_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let client_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let contact = match extract_json::<NewContactRequest>(&mut state).await {
+            Ok(contact) => match contact.validate() {
+                Ok(_) => contact,
+                Err(e) => return Err((state, e.into())),
+            },
+            Err(e) => return Err((state, e)),
+        };
+
+        let new_contact = NewContact {
+            client_id,
+            name: contact.name,
+            title: contact.title,
+            email: contact.email.unwrap_or_default(),
+            phone: contact.phone,
+            is_primary: contact.is_primary,
+        };
+        let result = repo
+            .run(move |conn| create_contact(&conn, current_user_id, new_contact))
+            .await;
+
+        match result {
+            Ok(Some(contact)) => {
+                let res = json_response_created(&state, &contact);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to insert contact: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to insert contact.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve PATCH /api/v1/clients/:id/contacts/:contact_id
+pub fn update_contact_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (client_id, contact_id) = {
+        let res = ClientContactPath::borrow_from(&state);
+        (res.id, res.contact_id)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let changes = match extract_json::<ChangeContact>(&mut state).await {
+            Ok(changes) => match changes.validate() {
+                Ok(_) => changes,
+                Err(e) => return Err((state, e.into())),
+            },
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| changes.update(&conn, current_user_id, client_id, contact_id))
+            .await;
+
+        match result {
+            Ok(Some(contact)) => {
+                let res = json_response_ok(&state, &contact);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to update contact: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to update contact.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/clients/:id/contacts/:contact_id
+pub fn delete_contact_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (client_id, contact_id) = {
+        let res = ClientContactPath::borrow_from(&state);
+        (res.id, res.contact_id)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| delete_contact(&conn, current_user_id, client_id, contact_id))
+            .await;
+
+        match result {
+            Ok(deleted_count) if deleted_count > 0 => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(_) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to delete contact: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to delete contact.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
@@ -0,0 +1,432 @@
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use futures::prelude::*;
+use futures::stream;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::CONTENT_DISPOSITION;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::state::{FromState, State};
+use log::error;
+use std::pin::Pin;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::client::{client_export_batch, Client};
+use crate::models::company::{company_export_batch, Company};
+use crate::routes::paths::ExportQueryExtractor;
+use crate::routes::utils::json_response_bad_message;
+
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+const CLIENT_COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "email",
+    "company_name",
+    "address_1",
+    "address_2",
+    "city",
+    "state",
+    "zip_code",
+    "country",
+    "website",
+    "notes",
+    "created_at",
+    "updated_at",
+];
+
+const COMPANY_COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "address_1",
+    "address_2",
+    "city",
+    "state",
+    "zip_code",
+    "country",
+    "created_at",
+    "updated_at",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Ndjson,
+    Vcard,
+}
+
+impl Format {
+    fn parse(format: Option<&str>) -> Option<Format> {
+        match format.unwrap_or("csv") {
+            "csv" => Some(Format::Csv),
+            "ndjson" => Some(Format::Ndjson),
+            "vcard" => Some(Format::Vcard),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> mime::Mime {
+        let content_type = match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Vcard => "text/vcard; charset=utf-8",
+        };
+        content_type.parse().unwrap()
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Vcard => "vcf",
+        }
+    }
+}
+
+// the position of the last record exported, the next batch starts after it
+type Cursor = (NaiveDateTime, i32);
+
+// A body writing `head` then the records loaded batch by batch, so a large
+// export never sits in memory. A database error midway aborts the response.
+fn export_body<T, L, K, E>(repo: Repo, head: Vec<u8>, load: L, key: K, encode: E) -> Body
+where
+    T: Send + 'static,
+    L: Fn(&PgConnection, Option<Cursor>) -> Result<Vec<T>, DieselError> + Clone + Send + 'static,
+    K: Fn(&T) -> Cursor + Clone + Send + 'static,
+    E: Fn(&[T]) -> Vec<u8> + Clone + Send + 'static,
+{
+    let batches = stream::unfold(Some(None), move |cursor: Option<Option<Cursor>>| {
+        let repo = repo.clone();
+        let load = load.clone();
+        let key = key.clone();
+        let encode = encode.clone();
+        async move {
+            let cursor = cursor?;
+            let batch = match repo.run(move |conn| load(&conn, cursor)).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Failed to export records: {}", e);
+                    return Some((Err(e), None));
+                }
+            };
+            if batch.is_empty() {
+                return None;
+            }
+            let next = if (batch.len() as i64) < EXPORT_BATCH_SIZE {
+                None
+            } else {
+                batch.last().map(|last| Some(key(last)))
+            };
+            Some((Ok(encode(&batch)), next))
+        }
+    });
+
+    Body::wrap_stream(stream::once(future::ready(Ok(head))).chain(batches))
+}
+
+fn csv_rows<I, R>(rows: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = R>,
+    R: IntoIterator,
+    R::Item: AsRef<[u8]>,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        // only fails on I/O, there is none writing to a Vec
+        writer.write_record(row).unwrap();
+    }
+    writer.into_inner().unwrap_or_default()
+}
+
+fn ndjson_rows<T: serde::Serialize>(records: &[T]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut out, record).unwrap();
+        out.push(b'\n');
+    }
+    out
+}
+
+// a spreadsheet evaluates a cell starting with one of these as a formula
+const FORMULA_PREFIXES: &[char] = &['=', '+', '-', '@', '\t', '\r'];
+
+// user input, kept from being run as a formula when the CSV is opened
+fn csv_text(value: &str) -> String {
+    if value.starts_with(FORMULA_PREFIXES) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn client_csv_row(client: &Client) -> Vec<String> {
+    vec![
+        client.id.to_string(),
+        csv_text(&client.name),
+        csv_text(&client.email),
+        csv_text(&client.company_name),
+        csv_text(&client.address_1),
+        csv_text(&client.address_2),
+        csv_text(&client.city),
+        csv_text(&client.state),
+        csv_text(&client.zip_code),
+        csv_text(&client.country),
+        csv_text(&client.website),
+        csv_text(&client.notes),
+        client.created_at.to_string(),
+        client.updated_at.to_string(),
+    ]
+}
+
+fn company_csv_row(company: &Company) -> Vec<String> {
+    vec![
+        company.id.to_string(),
+        csv_text(&company.name),
+        csv_text(&company.address_1),
+        csv_text(&company.address_2),
+        csv_text(&company.city),
+        csv_text(&company.state),
+        csv_text(&company.zip_code),
+        csv_text(&company.country),
+        company.created_at.to_string(),
+        company.updated_at.to_string(),
+    ]
+}
+
+// escape a vCard property value, RFC 6350 section 3.4
+fn vcard_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// fold a content line at 75 octets, without splitting a character
+fn vcard_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn client_vcard(client: &Client) -> String {
+    let mut card = String::new();
+    vcard_line(&mut card, "BEGIN:VCARD");
+    vcard_line(&mut card, "VERSION:4.0");
+    // FN is the one property a vCard must have
+    let full_name = if client.name.is_empty() {
+        &client.email
+    } else {
+        &client.name
+    };
+    vcard_line(&mut card, &format!("FN:{}", vcard_escape(full_name)));
+    if !client.email.is_empty() {
+        vcard_line(&mut card, &format!("EMAIL:{}", vcard_escape(&client.email)));
+    }
+    if !client.company_name.is_empty() {
+        vcard_line(
+            &mut card,
+            &format!("ORG:{}", vcard_escape(&client.company_name)),
+        );
+    }
+    let address = [
+        &client.address_1,
+        &client.address_2,
+        &client.city,
+        &client.state,
+        &client.zip_code,
+        &client.country,
+    ];
+    if address.iter().any(|part| !part.is_empty()) {
+        // post office box, extended address, street, locality, region,
+        // postal code, country
+        vcard_line(
+            &mut card,
+            &format!(
+                "ADR:;{};{};{};{};{};{}",
+                vcard_escape(&client.address_2),
+                vcard_escape(&client.address_1),
+                vcard_escape(&client.city),
+                vcard_escape(&client.state),
+                vcard_escape(&client.zip_code),
+                vcard_escape(&client.country),
+            ),
+        );
+    }
+    if !client.website.is_empty() {
+        vcard_line(&mut card, &format!("URL:{}", vcard_escape(&client.website)));
+    }
+    vcard_line(&mut card, "END:VCARD");
+    card
+}
+
+fn attachment_response(state: &State, format: Format, name: &str, body: Body) -> Response<Body> {
+    let mut res = create_response(state, StatusCode::OK, format.content_type(), body);
+    let disposition = format!("attachment; filename=\"{}.{}\"", name, format.extension());
+    if let Ok(value) = disposition.parse() {
+        res.headers_mut().insert(CONTENT_DISPOSITION, value);
+    }
+    res
+}
+
+/// serve GET /api/v1/clients/export?format=csv|ndjson|vcard&q=
+/// all the clients, newest first, `q` filters them like the list of clients
+pub fn export_clients_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let query = ExportQueryExtractor::take_from(&mut state);
+    let repo = Repo::borrow_from(&state).clone();
+
+    let format = match Format::parse(query.format.as_deref()) {
+        Some(format) => format,
+        None => {
+            let message = format!("Unknown format `{}`.", query.format.unwrap_or_default());
+            let res = json_response_bad_message(&state, message);
+            return future::ok((state, res)).boxed();
+        }
+    };
+    let search = query.q;
+
+    let load = move |conn: &PgConnection, after: Option<Cursor>| {
+        client_export_batch(
+            conn,
+            current_user_id,
+            search.as_deref(),
+            after,
+            EXPORT_BATCH_SIZE,
+        )
+    };
+    let key = |client: &Client| (client.created_at, client.id);
+    let body = match format {
+        Format::Csv => export_body(
+            repo,
+            csv_rows(vec![CLIENT_COLUMNS]),
+            load,
+            key,
+            |clients: &[Client]| csv_rows(clients.iter().map(client_csv_row)),
+        ),
+        Format::Ndjson => export_body(repo, Vec::new(), load, key, |clients: &[Client]| {
+            ndjson_rows(clients)
+        }),
+        Format::Vcard => export_body(repo, Vec::new(), load, key, |clients: &[Client]| {
+            clients
+                .iter()
+                .map(client_vcard)
+                .collect::<String>()
+                .into_bytes()
+        }),
+    };
+
+    let res = attachment_response(&state, format, "clients", body);
+    future::ok((state, res)).boxed()
+}
+
+/// serve GET /api/v1/companies/export?format=csv|ndjson&q=
+/// all the companies, newest first, `q` filters them like the list of companies
+pub fn export_companies_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let query = ExportQueryExtractor::take_from(&mut state);
+    let repo = Repo::borrow_from(&state).clone();
+
+    let format = match Format::parse(query.format.as_deref()) {
+        Some(format) if format != Format::Vcard => format,
+        _ => {
+            let message = format!("Unknown format `{}`.", query.format.unwrap_or_default());
+            let res = json_response_bad_message(&state, message);
+            return future::ok((state, res)).boxed();
+        }
+    };
+    let search = query.q;
+
+    let load = move |conn: &PgConnection, after: Option<Cursor>| {
+        company_export_batch(
+            conn,
+            current_user_id,
+            search.as_deref(),
+            after,
+            EXPORT_BATCH_SIZE,
+        )
+    };
+    let key = |company: &Company| (company.created_at, company.id);
+    let body = if format == Format::Csv {
+        export_body(
+            repo,
+            csv_rows(vec![COMPANY_COLUMNS]),
+            load,
+            key,
+            |companies: &[Company]| csv_rows(companies.iter().map(company_csv_row)),
+        )
+    } else {
+        export_body(repo, Vec::new(), load, key, |companies: &[Company]| {
+            ndjson_rows(companies)
+        })
+    };
+
+    let res = attachment_response(&state, format, "companies", body);
+    future::ok((state, res)).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_text_defuses_formulas() {
+        assert_eq!(csv_text("=HYPERLINK(\"x\")"), "'=HYPERLINK(\"x\")");
+        assert_eq!(csv_text("+1 555 0100"), "'+1 555 0100");
+        assert_eq!(csv_text("-2"), "'-2");
+        assert_eq!(csv_text("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_text("Ann = Bob"), "Ann = Bob");
+        assert_eq!(csv_text(""), "");
+    }
+
+    #[test]
+    fn vcard_escape_escapes_the_special_characters() {
+        assert_eq!(
+            vcard_escape("Smith, Ann; Jr\\\r\nnext"),
+            r"Smith\, Ann\; Jr\\\nnext"
+        );
+    }
+
+    #[test]
+    fn vcard_line_folds_at_75_octets() {
+        let mut out = String::new();
+        vcard_line(&mut out, &"a".repeat(80));
+
+        assert_eq!(out, format!("{}\r\n {}\r\n", "a".repeat(75), "a".repeat(5)));
+    }
+
+    #[test]
+    fn vcard_line_keeps_characters_whole() {
+        let mut out = String::new();
+        vcard_line(&mut out, &format!("{}é", "a".repeat(74)));
+
+        assert_eq!(out, format!("{}\r\n é\r\n", "a".repeat(74)));
+        assert!(out.split("\r\n").all(|line| line.len() <= 75));
+    }
+
+    #[test]
+    fn a_short_line_is_not_folded() {
+        let mut out = String::new();
+        vcard_line(&mut out, "FN:Ann");
+
+        assert_eq!(out, "FN:Ann\r\n");
+    }
+}
@@ -0,0 +1,120 @@
+use diesel::{sql_query, RunQueryDsl};
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::Serialize;
+use std::pin::Pin;
+
+use crate::db::{pending_migrations, PoolStats, Repo};
+use crate::email::check_mail_transport;
+use crate::metrics::{observe_pool, render};
+use crate::routes::utils::{json_response, json_response_ok};
+
+// the reason a check failed is only logged, the route is public
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+}
+
+impl Check {
+    fn from_result(state: &State, name: &str, result: Result<(), String>) -> Self {
+        if let Err(e) = &result {
+            error!(
+                "[{}] Readiness check `{}` failed: {}",
+                request_id(state),
+                name,
+                e
+            );
+        }
+        Check { ok: result.is_ok() }
+    }
+}
+
+#[derive(Serialize)]
+struct Checks {
+    database: Check,
+    migrations: Check,
+    email: Check,
+}
+
+/// serve GET /healthz
+/// the process is up and serving requests
+pub fn healthz_handler(state: State) -> (State, Response<Body>) {
+    #[derive(Serialize)]
+    struct R {
+        status: &'static str,
+    }
+
+    let res = json_response_ok(&state, &R { status: "ok" });
+    (state, res)
+}
+
+/// serve GET /readyz
+/// ready to take traffic when the database is reachable, its migrations are
+/// current and the mail settings can work. Answers 503 otherwise.
+pub fn readyz_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let repo = Repo::borrow_from(&state).clone();
+
+    #[derive(Serialize)]
+    struct R {
+        status: &'static str,
+        checks: Checks,
+        pool: PoolStats,
+    }
+
+    async move {
+        let database = repo
+            .run(|conn| sql_query("SELECT 1").execute(&conn))
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+
+        let migrations = if database.is_ok() {
+            match repo.run(|conn| pending_migrations(&conn)).await {
+                Ok(pending) if pending.is_empty() => Ok(()),
+                Ok(pending) => Err(format!("pending migrations: {}", pending.join(", "))),
+                Err(e) => Err(e.to_string()),
+            }
+        } else {
+            Err("database is not reachable".to_string())
+        };
+
+        let checks = Checks {
+            database: Check::from_result(&state, "database", database),
+            migrations: Check::from_result(&state, "migrations", migrations),
+            email: Check::from_result(&state, "email", check_mail_transport()),
+        };
+        let ready = checks.database.ok && checks.migrations.ok && checks.email.ok;
+
+        let body = R {
+            status: if ready { "ok" } else { "unavailable" },
+            checks,
+            pool: repo.stats(),
+        };
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let res = json_response(&state, &body, status);
+        Ok((state, res))
+    }
+    .boxed()
+}
+
+/// serve GET /metrics
+/// in the Prometheus text format
+pub fn metrics_handler(state: State) -> (State, Response<Body>) {
+    observe_pool(Repo::borrow_from(&state).stats());
+
+    let res = create_response(
+        &state,
+        StatusCode::OK,
+        "text/plain; version=0.0.4".parse::<mime::Mime>().unwrap(),
+        render(),
+    );
+    (state, res)
+}
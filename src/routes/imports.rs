@@ -0,0 +1,327 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::{error, info};
+use serde_derive::Serialize;
+use std::pin::Pin;
+use tokio::task;
+use validator::Validate;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::client::{existing_client_emails, import_clients};
+use crate::routes::clients::NewClientRequest;
+use crate::routes::paths::ImportQueryExtractor;
+use crate::routes::utils::{extract_bytes, json_response, json_response_bad_message};
+
+const MAX_IMPORT_BYTES: usize = 5 * 1024 * 1024;
+const MAX_IMPORT_ROWS: usize = 5000;
+
+const STATUS_NEW: &str = "new";
+// a client with that email exists already, or an earlier row has it
+const STATUS_DUPLICATE: &str = "duplicate";
+const STATUS_INVALID: &str = "invalid";
+
+// the `NewClient` field a column is imported into, the header is compared
+// lowercased with spaces and dashes as underscores
+fn client_field(header: &str) -> Option<&'static str> {
+    let header = header.trim().to_lowercase().replace([' ', '-'], "_");
+    let field = match header.as_str() {
+        "name" | "full_name" | "contact" | "contact_name" => "name",
+        "email" | "e_mail" | "email_address" => "email",
+        "company" | "company_name" | "organization" | "organisation" => "company_name",
+        "address" | "address_1" | "address1" | "street" => "address_1",
+        "address_2" | "address2" => "address_2",
+        "city" | "town" => "city",
+        "state" | "province" | "region" => "state",
+        "zip" | "zip_code" | "zipcode" | "postal_code" | "postcode" => "zip_code",
+        "country" => "country",
+        "website" | "url" | "web" => "website",
+        "notes" | "note" | "comments" => "notes",
+        _ => return None,
+    };
+    Some(field)
+}
+
+#[derive(Debug, Serialize)]
+struct ColumnMapping {
+    column: String,
+    /// `null` when the column is ignored
+    field: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportRow {
+    /// the number of the row under the header, blank rows aside
+    row: usize,
+    name: String,
+    email: String,
+    status: &'static str,
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportReport {
+    dry_run: bool,
+    columns: Vec<ColumnMapping>,
+    rows: Vec<ImportRow>,
+    /// the clients inserted, or that would be without `dry_run`
+    imported: usize,
+}
+
+struct ParsedCsv {
+    columns: Vec<ColumnMapping>,
+    rows: Vec<NewClientRequest>,
+}
+
+fn parse_csv(raw: &[u8]) -> Result<ParsedCsv, String> {
+    // spreadsheets like to start their UTF-8 exports with a BOM
+    let raw = raw.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(raw);
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(raw);
+
+    let columns = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|column| ColumnMapping {
+            column: column.to_string(),
+            field: client_field(column),
+        })
+        .collect::<Vec<_>>();
+    if !columns.iter().any(|c| c.field == Some("email")) {
+        return Err("there is no `email` column".into());
+    }
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        if record.iter().all(|value| value.is_empty()) {
+            continue;
+        }
+        if rows.len() == MAX_IMPORT_ROWS {
+            return Err(format!("more than {} rows", MAX_IMPORT_ROWS));
+        }
+
+        let mut request = NewClientRequest {
+            name: String::new(),
+            email: String::new(),
+            company_name: String::new(),
+            address_1: String::new(),
+            address_2: String::new(),
+            city: String::new(),
+            state: String::new(),
+            zip_code: String::new(),
+            country: String::new(),
+            website: String::new(),
+            notes: String::new(),
+        };
+        for (mapping, value) in columns.iter().zip(record.iter()) {
+            let target = match mapping.field {
+                Some("name") => &mut request.name,
+                Some("email") => &mut request.email,
+                Some("company_name") => &mut request.company_name,
+                Some("address_1") => &mut request.address_1,
+                Some("address_2") => &mut request.address_2,
+                Some("city") => &mut request.city,
+                Some("state") => &mut request.state,
+                Some("zip_code") => &mut request.zip_code,
+                Some("country") => &mut request.country,
+                Some("website") => &mut request.website,
+                Some("notes") => &mut request.notes,
+                _ => continue,
+            };
+            // a later column mapped to the same field doesn't erase it
+            if target.is_empty() {
+                *target = value.to_string();
+            }
+        }
+        rows.push(request);
+    }
+
+    Ok(ParsedCsv { columns, rows })
+}
+
+/// serve POST /api/v1/clients/import?dry_run=true
+/// The body is a CSV with a header line, its columns are mapped to the client
+/// fields by name and the rows validated like `POST /api/v1/clients`. Rows
+/// with the email of an existing client, or of an earlier row, are skipped.
+/// Without `dry_run` the clients are inserted all at once, and none of them
+/// when a row is invalid.
+pub fn import_clients_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let dry_run = ImportQueryExtractor::take_from(&mut state)
+        .dry_run
+        .unwrap_or(false);
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let raw = match extract_bytes(&mut state, MAX_IMPORT_BYTES).await {
+            Ok(raw) => raw,
+            Err(e) => return Err((state, e)),
+        };
+        // up to MAX_IMPORT_ROWS rows to parse, off the executor
+        let parsed = task::spawn_blocking(move || parse_csv(&raw))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+        let ParsedCsv { columns, rows } = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let res = json_response_bad_message(
+                    &state,
+                    format!("That CSV could not be imported: {}", e),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        let result = repo
+            .run(move |conn| {
+                use diesel::Connection;
+
+                conn.transaction(|| {
+                    let emails = rows.iter().map(|row| row.email.clone()).collect::<Vec<_>>();
+                    let mut seen = existing_client_emails(&conn, current_user_id, &emails)?;
+
+                    let mut report = Vec::new();
+                    let mut new_clients = Vec::new();
+                    for (i, row) in rows.into_iter().enumerate() {
+                        let errors = match row.validate() {
+                            Ok(_) => Vec::new(),
+                            Err(e) => {
+                                let mut fields =
+                                    e.field_errors().keys().copied().collect::<Vec<_>>();
+                                fields.sort_unstable();
+                                fields
+                                    .into_iter()
+                                    .map(|field| format!("Invalid `{}`.", field))
+                                    .collect()
+                            }
+                        };
+                        let status = if !errors.is_empty() {
+                            STATUS_INVALID
+                        } else if !seen.insert(row.email.to_lowercase()) {
+                            STATUS_DUPLICATE
+                        } else {
+                            STATUS_NEW
+                        };
+
+                        report.push(ImportRow {
+                            row: i + 1,
+                            name: row.name.clone(),
+                            email: row.email.clone(),
+                            status,
+                            errors,
+                        });
+                        if status == STATUS_NEW {
+                            new_clients.push(row.into_new_client(current_user_id));
+                        }
+                    }
+
+                    let valid = report.iter().all(|row| row.status != STATUS_INVALID);
+                    let imported = if dry_run {
+                        new_clients.len()
+                    } else if valid {
+                        import_clients(&conn, current_user_id, new_clients)?.len()
+                    } else {
+                        0
+                    };
+
+                    Ok::<_, diesel::result::Error>(ImportReport {
+                        dry_run,
+                        columns,
+                        rows: report,
+                        imported,
+                    })
+                })
+            })
+            .await;
+
+        match result {
+            Ok(report) => {
+                let valid = report.rows.iter().all(|row| row.status != STATUS_INVALID);
+                let status = if report.dry_run {
+                    StatusCode::OK
+                } else if valid {
+                    info!(
+                        "[{}] Imported {} clients",
+                        request_id(&state),
+                        report.imported
+                    );
+                    StatusCode::CREATED
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                let res = json_response(&state, &report, status);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to import clients: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to import clients.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_field_matches_the_usual_headers() {
+        assert_eq!(client_field("E-mail"), Some("email"));
+        assert_eq!(client_field(" Full Name "), Some("name"));
+        assert_eq!(client_field("Postal code"), Some("zip_code"));
+        assert_eq!(client_field("Organisation"), Some("company_name"));
+        assert_eq!(client_field("Phone"), None);
+    }
+
+    #[test]
+    fn parse_csv_maps_the_columns() {
+        let raw = b"\xEF\xBB\xBFName,E-mail,Phone,Town\n\
+            Ann,ann@example.com,555,Oslo\n\
+            ,,,\n\
+            Bob,bob@example.com\n";
+        let parsed = parse_csv(raw).unwrap();
+
+        let fields = parsed.columns.iter().map(|c| c.field).collect::<Vec<_>>();
+        assert_eq!(fields, [Some("name"), Some("email"), None, Some("city")]);
+        assert_eq!(parsed.rows.len(), 2);
+        assert_eq!(parsed.rows[0].name, "Ann");
+        assert_eq!(parsed.rows[0].email, "ann@example.com");
+        assert_eq!(parsed.rows[0].city, "Oslo");
+        assert_eq!(parsed.rows[1].email, "bob@example.com");
+        assert_eq!(parsed.rows[1].city, "");
+    }
+
+    #[test]
+    fn the_first_column_of_a_field_wins() {
+        let parsed = parse_csv(b"email,e-mail\nann@example.com,other@example.com\n").unwrap();
+
+        assert_eq!(parsed.rows[0].email, "ann@example.com");
+    }
+
+    #[test]
+    fn parse_csv_needs_an_email_column() {
+        assert!(parse_csv(b"name,city\nAnn,Oslo\n").is_err());
+    }
+
+    #[test]
+    fn parse_csv_refuses_too_many_rows() {
+        let mut raw = String::from("email\n");
+        for i in 0..=MAX_IMPORT_ROWS {
+            raw.push_str(&format!("{}@example.com\n", i));
+        }
+
+        assert!(parse_csv(raw.as_bytes()).is_err());
+    }
+}
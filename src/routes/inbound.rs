@@ -0,0 +1,299 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::{create_empty_response, create_response};
+use gotham::hyper::header::CONTENT_DISPOSITION;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+use tokio::task;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::email::inbound::parse_message;
+use crate::models::inbound::{
+    assign_inbound_email, delete_inbound_email, find_attachment, store_inbound_email,
+    with_attachments, InboundEmail, InboundEmailDetail,
+};
+use crate::routes::paths::{PaginationExtractor, ResourceIDPath};
+use crate::routes::utils::{
+    extract_bytes, extract_json, json_response_bad_message, json_response_created,
+    json_response_not_found, json_response_ok,
+};
+use crate::sqlx::pagination::Paginate;
+
+// the usual limit of mail servers
+const MAX_MESSAGE_BYTES: usize = 25 * 1024 * 1024;
+
+/// serve POST /api/v1/inbound/emails
+/// the body is a raw RFC 5322 message, eg. piped by the MTA. It lands on the
+/// timeline of the client who sent it, or in the review queue.
+pub fn receive_inbound_email_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let raw = match extract_bytes(&mut state, MAX_MESSAGE_BYTES).await {
+            Ok(raw) => raw,
+            Err(e) => return Err((state, e)),
+        };
+        // a large MIME tree takes a while to parse, off the executor
+        let parsed = task::spawn_blocking(move || parse_message(&raw).map_err(|e| e.to_string()))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+        let message = match parsed {
+            Ok(message) => message,
+            Err(e) => {
+                let res = json_response_bad_message(
+                    &state,
+                    format!("That message could not be parsed: {}", e),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        let result = repo
+            .run(move |conn| {
+                let (email, created) = store_inbound_email(&conn, current_user_id, &message)?;
+                let email = with_attachments(&conn, vec![email])?.pop();
+                Ok::<_, diesel::result::Error>((email, created))
+            })
+            .await;
+
+        match result {
+            Ok((Some(email), true)) => {
+                let res = json_response_created(&state, &email);
+                Ok((state, res))
+            }
+            // already received
+            Ok((email, _)) => {
+                let res = json_response_ok(&state, &email);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to store inbound email: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to store the email.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct InboundEmailPagination {
+    pub total_pages: i64,
+    pub results: Vec<InboundEmailDetail>,
+}
+
+/// serve GET /api/v1/inbound/review
+/// the emails no client matched, newest first
+pub fn list_review_queue_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let (per_page, page, search) = {
+        let res = PaginationExtractor::take_from(&mut state);
+        (res.per_page, res.page.unwrap_or(1), res.q)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::inbound_emails;
+                use crate::schema::inbound_emails::dsl::*;
+                use diesel::prelude::*;
+
+                let mut query = inbound_emails::table
+                    .order(received_at.desc())
+                    .filter(user_id.eq(current_user_id))
+                    .filter(client_id.is_null())
+                    .into_boxed();
+
+                if let Some(search) = search {
+                    let pattern = format!("%{}%", search);
+                    query = query.filter(sender.ilike(pattern.clone()).or(subject.ilike(pattern)));
+                }
+
+                let mut queryx = query.paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                let (emails, total_pages) =
+                    queryx.load_and_count_pages::<InboundEmail>(&mut conn)?;
+                Ok::<_, diesel::result::Error>((with_attachments(&conn, emails)?, total_pages))
+            })
+            .await;
+
+        match result {
+            Ok((emails, total_pages)) => {
+                let res = json_response_ok(
+                    &state,
+                    &InboundEmailPagination {
+                        total_pages,
+                        results: emails,
+                    },
+                );
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to list the review queue: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the emails.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Deserialize)]
+struct AssignRequest {
+    pub client_id: i32,
+}
+
+/// serve POST /api/v1/inbound/emails/:id/assign
+/// move an email to the timeline of another client, typically out of the
+/// review queue
+pub fn assign_inbound_email_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let email_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let request = match extract_json::<AssignRequest>(&mut state).await {
+            Ok(request) => request,
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| {
+                assign_inbound_email(&conn, current_user_id, email_id, request.client_id)
+            })
+            .await;
+
+        match result {
+            Ok(Some(email)) => {
+                let res = json_response_ok(&state, &email);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to assign inbound email: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to assign the email.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/inbound/emails/:id
+pub fn delete_inbound_email_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let email_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| delete_inbound_email(&conn, current_user_id, email_id))
+            .await;
+
+        match result {
+            Ok(deleted_count) if deleted_count > 0 => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(_) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to delete inbound email: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to delete the email.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/inbound/attachments/:id
+pub fn download_attachment_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let attachment_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| find_attachment(&conn, current_user_id, attachment_id))
+            .await;
+
+        match result {
+            Ok(Some(attachment)) => {
+                let content_type = attachment
+                    .content_type
+                    .parse()
+                    .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+                let mut res =
+                    create_response(&state, StatusCode::OK, content_type, attachment.content);
+                let filename = attachment.filename.replace(['"', '\\'], "_");
+                if let Ok(value) = format!("attachment; filename=\"{}\"", filename).parse() {
+                    res.headers_mut().insert(CONTENT_DISPOSITION, value);
+                }
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to get attachment: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the attachment.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
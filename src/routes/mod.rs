@@ -1,5 +1,17 @@
+pub mod account;
+pub mod admin;
+pub mod api_keys;
 pub mod auth;
+pub mod bounces;
 pub mod clients;
 pub mod companies;
+pub mod contacts;
+pub mod exports;
+pub mod health;
+pub mod imports;
+pub mod inbound;
+pub mod oidc;
 pub mod paths;
-mod utils;
+pub mod two_factor;
+pub(crate) mod utils;
+pub mod webhooks;
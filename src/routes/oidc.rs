@@ -0,0 +1,308 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::header::{COOKIE, SET_COOKIE};
+use gotham::hyper::{Body, HeaderMap, Response};
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+
+use crate::auth::encode_token;
+use crate::db::Repo;
+use crate::models::identity::{create_login_state, link_or_create_user, take_login_state};
+use crate::models::user::{login_step, LoginStep};
+use crate::oidc::{discover, exchange_code, find_provider, AuthorizationRequest};
+use crate::routes::paths::{OidcCallbackExtractor, ProviderPath};
+use crate::routes::utils::{json_response_bad_message, json_response_not_found, json_response_ok};
+
+// ties the `state` of a sign in to the browser that started it, without it
+// anyone could send a callback URL with their own code and have a victim
+// signed in to their account
+const STATE_COOKIE: &str = "lako_oidc_state";
+
+fn state_hash(csrf: &str) -> String {
+    Sha256::digest(csrf.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// `value` is empty to remove the cookie
+fn set_state_cookie(res: &mut Response<Body>, value: &str, secure: bool) {
+    let cookie = format!(
+        "{}={}; Path=/api/v1/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE,
+        value,
+        if value.is_empty() { 0 } else { 600 },
+        if secure { "; Secure" } else { "" }
+    );
+    if let Ok(cookie) = cookie.parse() {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+}
+
+fn state_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == STATE_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+/// serve GET /api/v1/oidc/:provider/authorize
+/// returns the provider URL the client should redirect the user to, and a
+/// cookie the callback requires from the same browser
+pub fn oidc_authorize_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let provider = find_provider(&ProviderPath::borrow_from(&state).provider);
+    let repo = Repo::borrow_from(&state).clone();
+
+    #[derive(Serialize)]
+    struct R {
+        authorization_url: String,
+    }
+
+    async move {
+        let provider = match provider {
+            Some(provider) => provider,
+            None => {
+                let res = json_response_not_found(&state, "Unknown identity provider".into());
+                return Ok((state, res));
+            }
+        };
+
+        let metadata = match discover(&provider).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!(
+                    "[{}] OIDC discovery for `{}` failed: {}",
+                    request_id(&state),
+                    provider.name,
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "The identity provider is not available.".into(),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        let request = AuthorizationRequest::new();
+        let (name, verifier, nonce) = (
+            provider.name.clone(),
+            request.code_verifier.clone(),
+            request.nonce.clone(),
+        );
+        let result = repo
+            .run(move |conn| create_login_state(&conn, &name, &verifier, &nonce))
+            .await;
+
+        let authorization_url = result
+            .map_err(|e| format!("failed to store the sign in state: {}", e))
+            .and_then(|csrf| {
+                request
+                    .authorization_url(&provider, &metadata, &csrf)
+                    .map(|url| (url, state_hash(&csrf)))
+                    .map_err(|e| e.to_string())
+            });
+
+        match authorization_url {
+            Ok((authorization_url, hash)) => {
+                let mut res = json_response_ok(&state, &R { authorization_url });
+                let secure = provider.redirect_url.starts_with("https://");
+                set_state_cookie(&mut res, &hash, secure);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] OIDC sign in with `{}` failed: {}",
+                    request_id(&state),
+                    provider.name,
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to sign in.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/oidc/:provider/callback
+/// the provider redirects here with the authorization code, in exchange
+/// the user gets an access token, or the two factor `challenge`, as with
+/// `POST /api/v1/login`.
+pub fn oidc_callback_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let provider = find_provider(&ProviderPath::borrow_from(&state).provider);
+    let query = OidcCallbackExtractor::take_from(&mut state);
+    let cookie = state_cookie(HeaderMap::borrow_from(&state));
+    let repo = Repo::borrow_from(&state).clone();
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum R {
+        Access { access: String },
+        Challenge { challenge: String },
+    }
+
+    async move {
+        let provider = match provider {
+            Some(provider) => provider,
+            None => {
+                let res = json_response_not_found(&state, "Unknown identity provider".into());
+                return Ok((state, res));
+            }
+        };
+
+        let (code, csrf) = match (query.code, query.state, query.error) {
+            (Some(code), Some(csrf), None) => (code, csrf),
+            (_, _, error) => {
+                let res = json_response_bad_message(
+                    &state,
+                    format!(
+                        "Sign in failed: {}",
+                        error.unwrap_or_else(|| "missing code".into())
+                    ),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        if cookie != Some(state_hash(&csrf)) {
+            let res = json_response_bad_message(
+                &state,
+                "The sign in was not started from this browser.".into(),
+            );
+            return Ok((state, res));
+        }
+
+        let name = provider.name.clone();
+        let login_state = match repo
+            .run(move |conn| take_login_state(&conn, &name, &csrf))
+            .await
+        {
+            Ok(Some(login_state)) => login_state,
+            Ok(None) => {
+                let res =
+                    json_response_bad_message(&state, "Invalid or expired sign in state.".into());
+                return Ok((state, res));
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to read the OIDC sign in state: {}",
+                    request_id(&state),
+                    e
+                );
+                let res =
+                    json_response_bad_message(&state, "Invalid or expired sign in state.".into());
+                return Ok((state, res));
+            }
+        };
+
+        let claims = match exchange_code(
+            &provider,
+            &code,
+            &login_state.code_verifier,
+            &login_state.nonce,
+        )
+        .await
+        {
+            Ok(claims) => claims,
+            Err(e) => {
+                error!(
+                    "[{}] OIDC sign in with `{}` failed: {}",
+                    request_id(&state),
+                    provider.name,
+                    e
+                );
+                let res = json_response_bad_message(&state, "Sign in failed.".into());
+                return Ok((state, res));
+            }
+        };
+
+        let email = match claims.email.as_deref() {
+            Some(email) => email.to_ascii_lowercase(),
+            None => {
+                let res = json_response_bad_message(
+                    &state,
+                    "The identity provider did not share an email address.".into(),
+                );
+                return Ok((state, res));
+            }
+        };
+
+        let secure = provider.redirect_url.starts_with("https://");
+        let result = repo
+            .run(move |conn| {
+                let user = link_or_create_user(
+                    &conn,
+                    &provider.name,
+                    &claims.sub,
+                    &email,
+                    claims.email_verified,
+                    claims.preferred_username.as_deref(),
+                )?;
+                login_step(&conn, user)
+            })
+            .await;
+
+        match result {
+            Ok(LoginStep::Authenticated(user)) => {
+                let token = encode_token(user.id);
+                let mut res = json_response_ok(&state, &R::Access { access: token });
+                set_state_cookie(&mut res, "", secure);
+                Ok((state, res))
+            }
+            Ok(LoginStep::TwoFactorRequired(challenge)) => {
+                let mut res = json_response_ok(&state, &R::Challenge { challenge });
+                set_state_cookie(&mut res, "", secure);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to link OIDC identity: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to sign in.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_state_cookie_among_others() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, "theme=dark; lako_oidc_state=abc".parse().unwrap());
+        headers.append(COOKIE, "other=1".parse().unwrap());
+
+        assert_eq!(state_cookie(&headers).as_deref(), Some("abc"));
+        assert_eq!(state_cookie(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn the_state_cookie_is_http_only() {
+        let mut res = Response::new(Body::empty());
+        set_state_cookie(&mut res, &state_hash("csrf"), true);
+
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with(&format!("lako_oidc_state={};", state_hash("csrf"))));
+        assert!(cookie.contains("HttpOnly; SameSite=Lax; Secure"));
+    }
+}
@@ -10,9 +10,51 @@ pub struct ResourceIDPath {
     pub id: i32,
 }
 
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ClientContactPath {
+    pub id: i32,
+    pub contact_id: i32,
+}
+
 #[derive(Deserialize, StateData, StaticResponseExtender)]
 pub struct PaginationExtractor {
     pub per_page: Option<i64>,
     pub page: Option<i64>,
     pub q: Option<String>,
 }
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct OutboxQueryExtractor {
+    pub status: Option<String>,
+    pub per_page: Option<i64>,
+    pub page: Option<i64>,
+    pub q: Option<String>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct BounceTokenExtractor {
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ProviderPath {
+    pub provider: String,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct OidcCallbackExtractor {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ImportQueryExtractor {
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ExportQueryExtractor {
+    pub format: Option<String>,
+    pub q: Option<String>,
+}
@@ -0,0 +1,240 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+use validator::Validate;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::db::Repo;
+use crate::models::totp::{
+    begin_totp_enrolment, confirm_totp_enrolment, disable_two_factor, find_user_totp,
+    replace_recovery_codes, verify_second_factor,
+};
+use crate::models::user::{find_user, AuthenticationError};
+use crate::routes::utils::{
+    extract_json, json_response_bad_message, json_response_created, json_response_ok,
+};
+use crate::totp::otpauth_uri;
+
+#[derive(Debug, Deserialize, Validate)]
+struct CodeForm {
+    #[validate(length(min = 1))]
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OkBool {
+    ok: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// serve POST /api/v1/me/2fa
+/// generate a new TOTP secret for the current user, it has to be confirmed
+/// with `POST /api/v1/me/2fa/confirm` before it's used at login.
+pub fn enrol_two_factor_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    #[derive(Serialize)]
+    struct R {
+        secret: String,
+        otpauth_uri: String,
+    }
+
+    async move {
+        let result = repo
+            .run(move |conn| -> Result<Option<R>, AuthenticationError> {
+                let user = match find_user(&conn, current_user_id)? {
+                    Some(user) => user,
+                    None => return Ok(None),
+                };
+
+                Ok(begin_totp_enrolment(&conn, user.id)?.map(|secret| R {
+                    otpauth_uri: otpauth_uri(&secret, &user.username),
+                    secret,
+                }))
+            })
+            .await;
+
+        match result {
+            Ok(Some(enrolment)) => {
+                let res = json_response_created(&state, &enrolment);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_bad_message(
+                    &state,
+                    "Two factor authentication is already enabled.".into(),
+                );
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to enrol two factor: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to enable two factor authentication."
+                        .into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/me/2fa/confirm
+/// enable two factor authentication, the recovery codes are only returned here
+pub fn confirm_two_factor_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let form = match extract_json::<CodeForm>(&mut state).await {
+            Ok(form) => form,
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| confirm_totp_enrolment(&conn, current_user_id, form.code.as_str()))
+            .await;
+
+        match result {
+            Ok(Some(recovery_codes)) => {
+                let res = json_response_ok(&state, &RecoveryCodes { recovery_codes });
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_bad_message(
+                    &state,
+                    "Invalid code or no pending two factor enrolment.".into(),
+                );
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to confirm two factor: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to enable two factor authentication."
+                        .into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/me/2fa/recovery-codes
+/// replace the recovery codes, requires a valid code
+pub fn regenerate_recovery_codes_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let form = match extract_json::<CodeForm>(&mut state).await {
+            Ok(form) => form,
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| {
+                let totp = match find_user_totp(&conn, current_user_id)? {
+                    Some(totp) if totp.enabled => totp,
+                    _ => return Ok(None),
+                };
+
+                if verify_second_factor(&conn, &totp, form.code.as_str())? {
+                    replace_recovery_codes(&conn, current_user_id).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .await;
+
+        match result {
+            Ok(Some(recovery_codes)) => {
+                let res = json_response_ok(&state, &RecoveryCodes { recovery_codes });
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_bad_message(
+                    &state,
+                    "Invalid code or two factor authentication is not enabled.".into(),
+                );
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to regenerate recovery codes: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to generate recovery codes.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/me/2fa/disable
+pub fn disable_two_factor_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let form = match extract_json::<CodeForm>(&mut state).await {
+            Ok(form) => form,
+            Err(e) => return Err((state, e)),
+        };
+
+        let result = repo
+            .run(move |conn| disable_two_factor(&conn, current_user_id, form.code.as_str()))
+            .await;
+
+        match result {
+            Ok(true) => {
+                let res = json_response_ok(&state, &OkBool { ok: true });
+                Ok((state, res))
+            }
+            Ok(false) => {
+                let res = json_response_bad_message(
+                    &state,
+                    "Invalid code or two factor authentication is not enabled.".into(),
+                );
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to disable two factor: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to disable two factor authentication."
+                        .into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
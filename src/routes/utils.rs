@@ -1,7 +1,10 @@
 use std::str::from_utf8;
 
+use futures::prelude::*;
+use gotham::anyhow::anyhow;
 use gotham::handler::{HandlerError, MapHandlerError, MapHandlerErrorFuture};
 use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::RETRY_AFTER;
 use gotham::hyper::{body, Body, Response, StatusCode};
 use gotham::state::{FromState, State};
 use serde_derive::Serialize;
@@ -19,6 +22,21 @@ where
         .and_then(|s| serde_json::from_str::<T>(s).map_err_with_status(StatusCode::BAD_REQUEST))
 }
 
+/// the raw request body, a body over `limit` bytes is rejected with a 413
+pub async fn extract_bytes(state: &mut State, limit: usize) -> Result<Vec<u8>, HandlerError> {
+    let mut body = Body::take_from(state);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err_with_status(StatusCode::BAD_REQUEST)?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(anyhow!("request body over {} bytes", limit))
+                .map_err_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
 pub fn json_response<T: serde::Serialize>(
     state: &State,
     t: &T,
@@ -49,3 +67,22 @@ pub fn json_response_bad_message(state: &State, msg: String) -> Response<Body> {
 pub fn json_response_not_found(state: &State, msg: String) -> Response<Body> {
     json_response(state, &ErrMessage { message: msg }, StatusCode::NOT_FOUND)
 }
+
+pub fn json_response_forbidden(state: &State, msg: String) -> Response<Body> {
+    json_response(state, &ErrMessage { message: msg }, StatusCode::FORBIDDEN)
+}
+
+pub fn json_response_too_many_requests(
+    state: &State,
+    msg: String,
+    retry_after: u64,
+) -> Response<Body> {
+    let mut res = json_response(
+        state,
+        &ErrMessage { message: msg },
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+    res.headers_mut()
+        .insert(RETRY_AFTER, retry_after.to_string().parse().unwrap());
+    res
+}
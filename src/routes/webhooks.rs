@@ -0,0 +1,333 @@
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::hyper::StatusCode;
+use gotham::state::{request_id, FromState, State};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+
+use crate::auth::{AuthorizationToken, Claims};
+use crate::config::config;
+use crate::db::Repo;
+use crate::models::webhook::{
+    create_webhook, delete_webhook, find_webhook, list_webhooks, redeliver, update_webhook,
+    Webhook, WebhookChanges, WebhookDelivery, ALL_EVENTS, EVENTS,
+};
+use crate::routes::paths::{PaginationExtractor, ResourceIDPath};
+use crate::routes::utils::{
+    extract_json, json_response_bad_message, json_response_created, json_response_not_found,
+    json_response_ok,
+};
+use crate::sqlx::pagination::Paginate;
+use crate::webhooks::check_webhook_url;
+
+#[derive(Debug, Deserialize)]
+struct NewWebhookRequest {
+    pub url: String,
+    pub events: Vec<String>,
+}
+
+// why the URL or events of a webhook are not acceptable
+async fn invalid_webhook(url: Option<&str>, events: Option<&[String]>) -> Option<String> {
+    if let Some(url) = url {
+        match url::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            _ => return Some(format!("`{}` is not an http or https URL.", url)),
+        }
+        let allow_private_networks = config().webhooks.allow_private_networks;
+        if let Err(message) = check_webhook_url(url, allow_private_networks).await {
+            return Some(message);
+        }
+    }
+    if let Some(events) = events {
+        if events.is_empty() {
+            return Some("Subscribe to at least one event.".into());
+        }
+        if let Some(event) = events
+            .iter()
+            .find(|event| *event != ALL_EVENTS && !EVENTS.contains(&event.as_str()))
+        {
+            return Some(format!("Unknown event `{}`.", event));
+        }
+    }
+    None
+}
+
+/// serve POST /api/v1/webhooks
+/// the response has the secret the deliveries are signed with
+pub fn create_webhook_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let repo = Repo::borrow_from(&state).clone();
+
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+
+    #[derive(Serialize)]
+    struct R {
+        #[serde(flatten)]
+        webhook: Webhook,
+        secret: String,
+    }
+
+    async move {
+        let request = match extract_json::<NewWebhookRequest>(&mut state).await {
+            Ok(request) => request,
+            Err(e) => return Err((state, e)),
+        };
+
+        if let Some(message) = invalid_webhook(Some(&request.url), Some(&request.events)).await {
+            let res = json_response_bad_message(&state, message);
+            return Ok((state, res));
+        }
+
+        let result = repo
+            .run(move |conn| create_webhook(&conn, current_user_id, &request.url, &request.events))
+            .await;
+
+        match result {
+            Ok(webhook) => {
+                let secret = webhook.secret.clone();
+                let res = json_response_created(&state, &R { webhook, secret });
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to create webhook: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to create a webhook.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve GET /api/v1/webhooks
+pub fn list_webhooks_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| list_webhooks(&conn, current_user_id))
+            .await;
+
+        match result {
+            Ok(webhooks) => {
+                let res = json_response_ok(&state, &webhooks);
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to list webhooks: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get webhooks".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve PATCH /api/v1/webhooks/:id
+/// change the URL or events, or pause the deliveries with `active`
+pub fn update_webhook_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let webhook_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let changes = match extract_json::<WebhookChanges>(&mut state).await {
+            Ok(changes) => changes,
+            Err(e) => return Err((state, e)),
+        };
+
+        if let Some(message) =
+            invalid_webhook(changes.url.as_deref(), changes.events.as_deref()).await
+        {
+            let res = json_response_bad_message(&state, message);
+            return Ok((state, res));
+        }
+
+        let result = repo
+            .run(move |conn| update_webhook(&conn, current_user_id, webhook_id, &changes))
+            .await;
+
+        match result {
+            Ok(Some(webhook)) => {
+                let res = json_response_ok(&state, &webhook);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to update webhook: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to update the webhook.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve DELETE /api/v1/webhooks/:id
+/// its delivery log goes with it
+pub fn delete_webhook_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let webhook_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| delete_webhook(&conn, current_user_id, webhook_id))
+            .await;
+
+        match result {
+            Ok(deleted_count) if deleted_count > 0 => {
+                let res = create_empty_response(&state, StatusCode::NO_CONTENT);
+                Ok((state, res))
+            }
+            Ok(_) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to delete webhook: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to delete the webhook.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeliveryPagination {
+    pub total_pages: i64,
+    pub results: Vec<WebhookDelivery>,
+}
+
+/// serve GET /api/v1/webhooks/:id/deliveries
+/// the delivery log, newest first, `q` filters on the event name
+pub fn list_deliveries_handler(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let owner_webhook_id = ResourceIDPath::borrow_from(&state).id;
+    let (per_page, page, search) = {
+        let res = PaginationExtractor::take_from(&mut state);
+        (res.per_page, res.page.unwrap_or(1), res.q)
+    };
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |mut conn| {
+                use crate::schema::webhook_deliveries;
+                use crate::schema::webhook_deliveries::dsl::*;
+                use diesel::prelude::*;
+
+                if find_webhook(&conn, current_user_id, owner_webhook_id)?.is_none() {
+                    return Ok(None);
+                }
+
+                let mut query = webhook_deliveries::table
+                    .order(created_at.desc())
+                    .filter(webhook_id.eq(owner_webhook_id))
+                    .into_boxed();
+
+                if let Some(search) = search {
+                    query = query.filter(event.ilike(format!("{}%", search)));
+                }
+
+                let mut queryx = query.paginate(page);
+
+                if let Some(per_page) = per_page {
+                    use std::cmp::min;
+                    queryx = queryx.per_page(min(per_page, 100));
+                }
+
+                queryx
+                    .load_and_count_pages::<WebhookDelivery>(&mut conn)
+                    .map(Some)
+            })
+            .await;
+
+        match result {
+            Ok(Some((deliveries, total_pages))) => {
+                let res = json_response_ok(
+                    &state,
+                    &DeliveryPagination {
+                        total_pages,
+                        results: deliveries,
+                    },
+                );
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!(
+                    "[{}] Failed to get webhook deliveries: {}",
+                    request_id(&state),
+                    e
+                );
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to get the deliveries".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
+
+/// serve POST /api/v1/webhooks/deliveries/:id/redeliver
+/// post the same payload again, right away
+pub fn redeliver_handler(state: State) -> Pin<Box<HandlerFuture>> {
+    let token = AuthorizationToken::<Claims>::borrow_from(&state);
+    let current_user_id = token.0.claims.user_id();
+    let delivery_id = ResourceIDPath::borrow_from(&state).id;
+    let repo = Repo::borrow_from(&state).clone();
+
+    async move {
+        let result = repo
+            .run(move |conn| redeliver(&conn, current_user_id, delivery_id))
+            .await;
+
+        match result {
+            Ok(Some(delivery)) => {
+                let res = json_response_ok(&state, &delivery);
+                Ok((state, res))
+            }
+            Ok(None) => {
+                let res = json_response_not_found(&state, "That resource is not found".into());
+                Ok((state, res))
+            }
+            Err(e) => {
+                error!("[{}] Failed to redeliver: {}", request_id(&state), e);
+                let res = json_response_bad_message(
+                    &state,
+                    "Unexpected error detected when trying to redeliver.".into(),
+                );
+                Ok((state, res))
+            }
+        }
+    }
+    .boxed()
+}
@@ -1,3 +1,51 @@
+table! {
+    account_deletions (user_id) {
+        user_id -> Int4,
+        requested_at -> Timestamp,
+        delete_after -> Timestamp,
+    }
+}
+
+table! {
+    account_exports (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token -> Varchar,
+        status -> Varchar,
+        archive -> Nullable<Bytea>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    account_lockouts (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        scope -> Varchar,
+        throttle_key -> Varchar,
+        failed_count -> Int4,
+        locked_until -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        prefix -> Varchar,
+        hashed_key -> Varchar,
+        scopes -> Array<Text>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     clients (id) {
         id -> Int4,
@@ -15,6 +63,7 @@ table! {
         notes -> Text,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        email_bouncing -> Bool,
     }
 }
 
@@ -34,6 +83,46 @@ table! {
     }
 }
 
+table! {
+    contacts (id) {
+        id -> Int4,
+        client_id -> Int4,
+        name -> Varchar,
+        title -> Varchar,
+        email -> Varchar,
+        phone -> Varchar,
+        is_primary -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    email_outbox (id) {
+        id -> Int4,
+        recipient -> Varchar,
+        subject -> Varchar,
+        body -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        html_body -> Nullable<Text>,
+    }
+}
+
+table! {
+    email_suppressions (id) {
+        id -> Int4,
+        address -> Varchar,
+        reason -> Varchar,
+        detail -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     emails (id) {
         id -> Int4,
@@ -42,6 +131,101 @@ table! {
         token -> Text,
         verified -> Bool,
         token_generated_at -> Timestamp,
+        bouncing -> Bool,
+    }
+}
+
+table! {
+    inbound_email_attachments (id) {
+        id -> Int4,
+        inbound_email_id -> Int4,
+        filename -> Varchar,
+        content_type -> Varchar,
+        size -> Int4,
+        content -> Bytea,
+    }
+}
+
+table! {
+    inbound_emails (id) {
+        id -> Int4,
+        user_id -> Int4,
+        client_id -> Nullable<Int4>,
+        message_id -> Nullable<Varchar>,
+        sender -> Varchar,
+        recipients -> Array<Text>,
+        subject -> Varchar,
+        body_text -> Text,
+        body_html -> Nullable<Text>,
+        sent_at -> Nullable<Timestamp>,
+        received_at -> Timestamp,
+    }
+}
+
+table! {
+    login_challenges (token) {
+        token -> Text,
+        user_id -> Int4,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    login_throttles (throttle_key) {
+        throttle_key -> Varchar,
+        failed_count -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    oidc_login_states (state_token) {
+        state_token -> Text,
+        provider -> Varchar,
+        code_verifier -> Varchar,
+        nonce -> Varchar,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    rate_limit_buckets (bucket_key) {
+        bucket_key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    totp_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        hashed_code -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_totp (user_id) {
+        user_id -> Int4,
+        secret -> Varchar,
+        enabled -> Bool,
+        last_used_step -> Int8,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -55,11 +239,78 @@ table! {
         profile_image -> Varchar,
         joined_at -> Timestamp,
         updated_at -> Timestamp,
+        language -> Varchar,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Int4,
+        user_id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Array<Text>,
+        active -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
+joinable!(account_deletions -> users (user_id));
+joinable!(account_exports -> users (user_id));
+joinable!(account_lockouts -> users (user_id));
+joinable!(api_keys -> users (user_id));
 joinable!(clients -> users (user_id));
 joinable!(companies -> users (user_id));
+joinable!(contacts -> clients (client_id));
 joinable!(emails -> users (user_id));
+joinable!(inbound_email_attachments -> inbound_emails (inbound_email_id));
+joinable!(inbound_emails -> clients (client_id));
+joinable!(inbound_emails -> users (user_id));
+joinable!(login_challenges -> users (user_id));
+joinable!(totp_recovery_codes -> users (user_id));
+joinable!(user_identities -> users (user_id));
+joinable!(user_totp -> users (user_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
+joinable!(webhooks -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(clients, companies, emails, users,);
+allow_tables_to_appear_in_same_query!(
+    account_deletions,
+    account_exports,
+    account_lockouts,
+    api_keys,
+    clients,
+    companies,
+    contacts,
+    email_outbox,
+    email_suppressions,
+    emails,
+    inbound_email_attachments,
+    inbound_emails,
+    login_challenges,
+    login_throttles,
+    oidc_login_states,
+    rate_limit_buckets,
+    totp_recovery_codes,
+    user_identities,
+    user_totp,
+    users,
+    webhook_deliveries,
+    webhooks,
+);
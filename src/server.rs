@@ -0,0 +1,256 @@
+use futures::prelude::*;
+use gotham::handler::{Handler, HandlerFuture, NewHandler};
+use gotham::hyper::header::{HeaderValue, CONNECTION};
+use gotham::state::State;
+use log::{debug, error, info};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::ToSocketAddrs;
+use std::panic::RefUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error as ThisError;
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config;
+
+#[derive(ThisError, Debug)]
+pub enum ServerError {
+    #[error("Failed to read `{path}`: {source}")]
+    Io { path: String, source: io::Error },
+
+    #[error("Invalid TLS configuration: {0}")]
+    Tls(String),
+
+    #[error("Failed to listen on `{address}`: {source}")]
+    Bind { address: String, source: io::Error },
+
+    #[error("Failed to install the signal handlers: {0}")]
+    Signal(io::Error),
+
+    #[error("Failed to start the runtime: {0}")]
+    Runtime(io::Error),
+}
+
+// the requests being served, and whether the server is shutting down
+#[derive(Default)]
+struct Drain {
+    in_flight: AtomicUsize,
+    shutting_down: AtomicBool,
+}
+
+// counts a request in until it's dropped
+struct InFlight(Arc<Drain>);
+
+impl InFlight {
+    fn new(drain: Arc<Drain>) -> Self {
+        drain.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(drain)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Keep count of the requests in flight, so a shutdown can wait for them.
+///
+/// Once the server is shutting down, responses ask the client to close the
+/// connection so kept-alive connections don't bring in new requests.
+#[derive(Clone)]
+struct DrainHandler<H> {
+    handler: H,
+    drain: Arc<Drain>,
+}
+
+impl<H> Handler for DrainHandler<H>
+where
+    H: Handler + Send + 'static,
+{
+    fn handle(self, state: State) -> Pin<Box<HandlerFuture>> {
+        let in_flight = InFlight::new(self.drain);
+
+        self.handler
+            .handle(state)
+            .map(move |mut result| {
+                if in_flight.0.shutting_down.load(Ordering::SeqCst) {
+                    if let Ok((_, res)) = &mut result {
+                        res.headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
+                }
+                drop(in_flight);
+                result
+            })
+            .boxed()
+    }
+}
+
+impl<H> NewHandler for DrainHandler<H>
+where
+    H: Handler + Clone + Send + Sync + RefUnwindSafe + 'static,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> gotham::anyhow::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, ServerError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| ServerError::Io {
+            path: path.to_string(),
+            source,
+        })
+}
+
+fn tls_config(tls: &config::Tls) -> Result<ServerConfig, ServerError> {
+    let chain = certs(&mut open(&tls.cert_file)?).unwrap_or_default();
+    if chain.is_empty() {
+        return Err(ServerError::Tls(format!(
+            "no certificate found in `{}`",
+            tls.cert_file
+        )));
+    }
+
+    let mut keys = pkcs8_private_keys(&mut open(&tls.key_file)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&tls.key_file)?).unwrap_or_default();
+    }
+    let key = keys.into_iter().next().ok_or_else(|| {
+        ServerError::Tls(format!(
+            "no PKCS#8 or RSA private key found in `{}`",
+            tls.key_file
+        ))
+    })?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .map_err(|e| ServerError::Tls(e.to_string()))?;
+    Ok(config)
+}
+
+#[cfg(unix)]
+fn shutdown_signal() -> io::Result<impl Future<Output = &'static str>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> io::Result<impl Future<Output = &'static str>> {
+    Ok(tokio::signal::ctrl_c().map(|_| "Ctrl-C"))
+}
+
+// wait for the requests in flight, `false` when some are still running
+// after `timeout`
+async fn drained(drain: &Drain, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while drain.in_flight.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    true
+}
+
+/// Serve `handler` until SIGTERM or SIGINT.
+///
+/// On either signal the server stops accepting connections and gives the
+/// requests in flight `shutdown_timeout` seconds to finish. With `server.tls`
+/// set, HTTPS is served with the configured certificate.
+pub fn serve<H>(config: &config::Server, handler: H) -> Result<(), ServerError>
+where
+    H: Handler + Clone + Send + Sync + RefUnwindSafe + 'static,
+{
+    let tls = match &config.tls {
+        Some(tls) => Some(TlsAcceptor::from(Arc::new(tls_config(tls)?))),
+        None => None,
+    };
+    let drain = Arc::new(Drain::default());
+    let handler = DrainHandler {
+        handler,
+        drain: drain.clone(),
+    };
+    let address = config.address.clone();
+    let timeout = Duration::from_secs(config.shutdown_timeout);
+
+    let mut runtime = runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .thread_name("lako-worker")
+        .build()
+        .map_err(ServerError::Runtime)?;
+
+    let result = runtime.block_on(async move {
+        let bind_error = |source| ServerError::Bind {
+            address: address.clone(),
+            source,
+        };
+        let addr = address
+            .to_socket_addrs()
+            .map_err(bind_error)?
+            .next()
+            .ok_or_else(|| bind_error(io::ErrorKind::AddrNotAvailable.into()))?;
+        let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+        let signal = shutdown_signal().map_err(ServerError::Signal)?;
+
+        let server = match tls {
+            Some(tls) => {
+                info!("Listening on https://{}", addr);
+                gotham::bind_server(listener, handler, move |socket| {
+                    tls.accept(socket).map_err(|e| {
+                        debug!("TLS handshake failed: {}", e);
+                    })
+                })
+                .boxed_local()
+            }
+            None => {
+                info!("Listening on http://{}", addr);
+                gotham::bind_server(listener, handler, future::ok).boxed_local()
+            }
+        };
+
+        // the listener is dropped along with the server future, requests
+        // of the connections already accepted go on
+        let name = tokio::select! {
+            _ = server => return Ok(()),
+            name = signal => name,
+        };
+        info!("Received {}, waiting for the requests in flight", name);
+        drain.shutting_down.store(true, Ordering::SeqCst);
+
+        if drained(&drain, timeout).await {
+            info!("Shut down gracefully");
+        } else {
+            error!(
+                "Shutting down, requests still in flight: {}",
+                drain.in_flight.load(Ordering::SeqCst)
+            );
+        }
+        Ok(())
+    });
+
+    // connections still open are dropped with the runtime
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    result
+}
@@ -1 +1,5 @@
+use diesel::sql_types::Text;
+
 pub(crate) mod pagination;
+
+sql_function!(fn lower(x: Text) -> Text);
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect;
+use sha2::Sha256;
+use url::{Host, Url};
+
+use crate::config::Webhooks;
+use crate::db::Repo;
+use crate::models::webhook::{
+    claim_due_deliveries, mark_delivery_failed, mark_delivery_succeeded, Webhook, WebhookDelivery,
+};
+use crate::schema::webhooks;
+
+// time a claimed delivery is hidden from other workers, more than the
+// endpoint timeout
+const LEASE_SECONDS: f64 = 300.0;
+
+/// The `X-Lako-Signature` of a delivery: `sha256=` then the hex HMAC-SHA256
+/// of `<X-Lako-Timestamp>.<body>`, keyed with the webhook secret. Receivers
+/// compute the same to check the request comes from Lako, and reject old
+/// timestamps against replays.
+pub fn sign_payload(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("sha256={}", signature)
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this network", shared address space (carrier-grade NAT), IETF
+        // protocol assignments, benchmarking and reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // IPv4-mapped and NAT64 addresses reach the IPv4 they embed
+    if let Some(ipv4) = ip.to_ipv4() {
+        if segments[..5].iter().all(|s| *s == 0) {
+            return is_public_ipv4(ipv4);
+        }
+    }
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_public_ipv4(Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local, link-local and the deprecated site-local
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+/// Whether webhooks may be posted to `ip`: not a loopback, private or
+/// link-local address, the latter being where cloud metadata services are.
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+// the domain to resolve and port of a webhook URL, or its IP address
+fn webhook_target(url: &str) -> Result<(Result<String, IpAddr>, u16), String> {
+    let parsed = Url::parse(url).map_err(|_| format!("`{}` is not a valid URL.", url))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| format!("`{}` has no port.", url))?;
+    match parsed.host() {
+        Some(Host::Domain(domain)) => Ok((Ok(domain.to_string()), port)),
+        Some(Host::Ipv4(ip)) => Ok((Err(IpAddr::V4(ip)), port)),
+        Some(Host::Ipv6(ip)) => Ok((Err(IpAddr::V6(ip)), port)),
+        None => Err(format!("`{}` has no host.", url)),
+    }
+}
+
+// every address must be public, a domain may resolve to several
+fn check_addresses<I>(url: &str, addresses: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = SocketAddr>,
+{
+    let mut resolved = false;
+    for address in addresses {
+        if !is_public_address(address.ip()) {
+            return Err(format!(
+                "`{}` is not a public address, webhooks can't be posted to it.",
+                url
+            ));
+        }
+        resolved = true;
+    }
+    if resolved {
+        Ok(())
+    } else {
+        Err(format!("`{}` does not resolve.", url))
+    }
+}
+
+/// Resolve the host of a webhook URL and check it's public, unless
+/// `webhooks.allow_private_networks` is set.
+pub async fn check_webhook_url(url: &str, allow_private_networks: bool) -> Result<(), String> {
+    let (host, port) = webhook_target(url)?;
+    if allow_private_networks {
+        return Ok(());
+    }
+    match host {
+        Ok(domain) => {
+            let addresses = tokio::net::lookup_host((domain.as_str(), port))
+                .await
+                .map_err(|_| format!("`{}` does not resolve.", url))?;
+            check_addresses(url, addresses)
+        }
+        Err(ip) => check_addresses(url, Some(SocketAddr::new(ip, port))),
+    }
+}
+
+// same as `check_webhook_url` for the worker thread
+fn check_webhook_url_blocking(url: &str, allow_private_networks: bool) -> Result<(), String> {
+    let (host, port) = webhook_target(url)?;
+    if allow_private_networks {
+        return Ok(());
+    }
+    match host {
+        Ok(domain) => {
+            let addresses = (domain.as_str(), port)
+                .to_socket_addrs()
+                .map_err(|_| format!("`{}` does not resolve.", url))?;
+            check_addresses(url, addresses)
+        }
+        Err(ip) => check_addresses(url, Some(SocketAddr::new(ip, port))),
+    }
+}
+
+// post the payload, the response status when the endpoint answered
+fn post_delivery(
+    client: &Client,
+    config: &Webhooks,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<i32, (Option<i32>, String)> {
+    // checked again as the domain may point elsewhere since the webhook
+    // was created
+    check_webhook_url_blocking(&webhook.url, config.allow_private_networks)
+        .map_err(|e| (None, e))?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default();
+
+    let response = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Lako-Event", &delivery.event)
+        .header("X-Lako-Delivery", delivery.id.to_string())
+        .header("X-Lako-Timestamp", timestamp.to_string())
+        .header(
+            "X-Lako-Signature",
+            sign_payload(&webhook.secret, timestamp, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .map_err(|e| (None, e.to_string()))?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16() as i32)
+    } else {
+        Err((
+            Some(status.as_u16() as i32),
+            format!("The endpoint answered {}", status),
+        ))
+    }
+}
+
+/// deliver the events due, returns how many were claimed
+pub fn deliver_due_webhooks(
+    repo: &Repo,
+    config: &Webhooks,
+    client: &Client,
+) -> Result<usize, DieselError> {
+    let conn = repo.connection()?;
+    let deliveries = claim_due_deliveries(&conn, config.batch_size, LEASE_SECONDS)?;
+    if deliveries.is_empty() {
+        return Ok(0);
+    }
+
+    let ids = deliveries.iter().map(|d| d.webhook_id).collect::<Vec<_>>();
+    let hooks = webhooks::table
+        .filter(webhooks::id.eq_any(ids))
+        .load::<Webhook>(&conn)?
+        .into_iter()
+        .map(|webhook| (webhook.id, webhook))
+        .collect::<HashMap<_, _>>();
+
+    for delivery in &deliveries {
+        // deleted since, the delivery went with it
+        let webhook = match hooks.get(&delivery.webhook_id) {
+            Some(webhook) => webhook,
+            None => continue,
+        };
+
+        match post_delivery(client, config, webhook, delivery) {
+            Ok(response) => {
+                mark_delivery_succeeded(&conn, delivery.id, response)?;
+            }
+            Err((response, e)) => {
+                let failed =
+                    mark_delivery_failed(&conn, delivery, response, &e, config.max_attempts)?;
+                if failed {
+                    error!(
+                        "Giving up on delivery {} of `{}` to `{}` after {} attempts: {}",
+                        delivery.id,
+                        delivery.event,
+                        webhook.url,
+                        delivery.attempts + 1,
+                        e
+                    );
+                } else {
+                    warn!(
+                        "Failed to deliver {} of `{}` to `{}`, will retry: {}",
+                        delivery.id, delivery.event, webhook.url, e
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(deliveries.len())
+}
+
+/// Post the queued webhook events in the background, see
+/// `outbox::spawn_outbox_worker`, this one works the same.
+pub fn spawn_webhook_worker(repo: Repo, config: Webhooks) -> thread::JoinHandle<()> {
+    info!("Starting the webhook worker");
+
+    thread::Builder::new()
+        .name("webhook-worker".into())
+        .spawn(move || {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(config.timeout))
+                .user_agent("Lako-Webhooks")
+                // a redirect could lead anywhere, past the address check
+                .redirect(redirect::Policy::none())
+                .build()
+                .expect("failed to build the webhook HTTP client");
+
+            loop {
+                let wait = match deliver_due_webhooks(&repo, &config, &client) {
+                    // there may be more due right away
+                    Ok(claimed) if claimed as i64 >= config.batch_size => continue,
+                    Ok(_) => config.poll_interval,
+                    Err(e) => {
+                        error!("Failed to process the webhook deliveries: {}", e);
+                        config.poll_interval
+                    }
+                };
+                thread::sleep(Duration::from_secs(wait));
+            }
+        })
+        .expect("failed to spawn the webhook worker")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_payload() {
+        let signature = sign_payload("whsec", 1700000000, r#"{"event":"client.created"}"#);
+
+        assert_eq!(
+            signature,
+            "sha256=124092274f3c9324eddac60203cc71e9b2e3c6435b28e4e5714adc13f8414815"
+        );
+    }
+
+    #[test]
+    fn refuses_non_public_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_the_host_of_a_webhook_url() {
+        assert!(check_webhook_url("http://127.0.0.1:8080/hook", false)
+            .await
+            .is_err());
+        assert!(check_webhook_url("http://[::1]/hook", false).await.is_err());
+        assert!(check_webhook_url("http://localhost/hook", false)
+            .await
+            .is_err());
+        assert!(check_webhook_url("http://localhost/hook", true)
+            .await
+            .is_ok());
+        assert!(check_webhook_url_blocking("http://169.254.169.254/latest", false).is_err());
+        assert!(check_webhook_url_blocking("http://93.184.216.34/hook", false).is_ok());
+    }
+}